@@ -0,0 +1,103 @@
+use actix_web::Error as ActixError;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::http::header::LINK;
+
+use actix_service::Service;
+
+use chrono::NaiveDate;
+
+use futures::future::{Future, FutureExt};
+
+use crate::config::Config;
+
+// the routes of users are served below this prefix, so they can not
+// collide with the top-level paths of the service:
+//
+//   /api/v1/{user}/...   the routes of users
+//   /{user}/...          deprecated aliases of them, see LegacyRoutes
+//   /healthz, /readyz, /metrics, /openapi.json, /docs, /dav
+//                        operational routes and CalDAV, not versioned
+//
+// The aliases share the root with the other top-level paths, so
+// keycloak_proxy does not register users named like them, see
+// RESERVED_USERNAMES in its validation module
+pub static API_PREFIX: &str = "/api/v1";
+
+static DEPRECATION: &str = "deprecation";
+static SUNSET: &str = "sunset";
+
+static DATE: &str = "%Y-%m-%d";
+static HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+  NaiveDate::parse_from_str(date, DATE).ok()
+}
+
+// the routes at the root are aliases of the ones below API_PREFIX,
+// kept for the clients from before the prefix. Their responses carry
+// the Deprecation (RFC 9745) and Sunset (RFC 8594) headers, dated by
+// the legacy_routes_deprecated and legacy_routes_sunset settings, and
+// link the route replacing them as successor-version
+#[derive(Clone)]
+pub struct LegacyRoutes {
+  deprecation: HeaderValue,
+  sunset: HeaderValue,
+}
+
+impl LegacyRoutes {
+  pub fn new(config: &Config) -> LegacyRoutes {
+    let deprecated = parse_date(&config.legacy_routes_deprecated)
+      .unwrap().and_hms(0, 0, 0);
+    let sunset = parse_date(&config.legacy_routes_sunset)
+      .unwrap().and_hms(0, 0, 0);
+
+    LegacyRoutes {
+      deprecation: HeaderValue::from_str(
+        &format!("@{}", deprecated.timestamp())
+      ).unwrap(),
+      sunset: HeaderValue::from_str(
+        &sunset.format(HTTP_DATE).to_string()
+      ).unwrap(),
+    }
+  }
+
+  pub fn successor(req: &ServiceRequest) -> String {
+    match req.query_string() {
+      "" => format!("{}{}", API_PREFIX, req.path()),
+      query => format!("{}{}?{}", API_PREFIX, req.path(), query),
+    }
+  }
+
+  pub fn mark<S, B>(&self, req: ServiceRequest, srv: &mut S)
+    -> impl Future<Output = Result<ServiceResponse<B>, ActixError>>
+    where S: Service<
+      Request = ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = ActixError,
+    >
+  {
+    let deprecation = self.deprecation.clone();
+    let sunset = self.sunset.clone();
+    let link = HeaderValue::from_str(&format!(
+      "<{}>; rel=\"successor-version\"", LegacyRoutes::successor(&req),
+    )).ok();
+
+    srv.call(req).map(move |res| {
+      res.map(|mut res| {
+        let headers =
+          vec![(DEPRECATION, deprecation), (SUNSET, sunset)];
+
+        for (name, value) in headers {
+          let name = HeaderName::from_static(name);
+          res.headers_mut().insert(name, value);
+        }
+
+        if let Some(link) = link {
+          res.headers_mut().insert(LINK, link);
+        }
+        res
+      })
+    })
+  }
+}
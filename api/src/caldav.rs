@@ -0,0 +1,722 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::http::header::IF_NONE_MATCH;
+
+use mongodb::bson::{Document, doc, to_bson};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+
+use futures::stream::StreamExt;
+
+use std::convert::TryFrom;
+
+use crate::to_mongodb_import_entry;
+use crate::elements::Element;
+use crate::errors::StoreError;
+use crate::events::{EventBus, EventKind};
+use crate::formats::{Columns, Entry, ImportFormat};
+use crate::formats::{ics_calendar, ics_uid};
+use crate::history::Change;
+use crate::middlewares::AuthenticatedUser;
+use crate::routes::{delete_matching, if_match_header, vanished};
+use crate::store::Store;
+use crate::versions::{etag, matches, version_clause, version_of};
+
+// a subset of CalDAV (RFC 4791) serving the elements of a user as a
+// single calendar of VTODOs:
+//
+//   /dav/                       discovery of the principal
+//   /dav/{user}/                principal and calendar home
+//   /dav/{user}/todos/          the calendar
+//   /dav/{user}/todos/{name}    an element
+//
+// Elements are named by their id, elements created by a client keep
+// the name and UID the client chose. Shared elements are not part of
+// the calendar
+pub static DAV_ROOT: &str = "/dav";
+static CALENDAR: &str = "todos";
+static CALENDAR_NAME: &str = "yata";
+static OBJECT_SUFFIX: &str = ".ics";
+static OBJECT_CONTENT_TYPE: &str =
+  "text/calendar; charset=utf-8; component=VTODO";
+
+static NS_DAV: &str = "DAV:";
+static NS_CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+static NS_CALSERVER: &str = "http://calendarserver.org/ns/";
+
+static PREFIXES: [(&str, &str); 3] = [
+  ("d", "DAV:"),
+  ("c", "urn:ietf:params:xml:ns:caldav"),
+  ("cs", "http://calendarserver.org/ns/"),
+];
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+  let method = |name: &[u8]| Method::from_bytes(name).unwrap();
+  let propfind = || web::method(method(b"PROPFIND"));
+  let report = || web::method(method(b"REPORT"));
+
+  cfg
+    .service(web::resource(vec!["", "/"])
+      .route(propfind().to(propfind_root))
+      .route(web::method(Method::OPTIONS).to(options)))
+    .service(web::resource(vec!["/{user}", "/{user}/"])
+      .route(propfind().to(propfind_principal))
+      .route(web::method(Method::OPTIONS).to(options)))
+    .service(web::resource(vec!["/{user}/todos", "/{user}/todos/"])
+      .route(propfind().to(propfind_calendar))
+      .route(report().to(report_calendar))
+      .route(web::method(Method::OPTIONS).to(options)))
+    .service(web::resource("/{user}/todos/{name}")
+      .route(propfind().to(propfind_object))
+      .route(web::get().to(get_object))
+      .route(web::put().to(put_object))
+      .route(web::delete().to(delete_object))
+      .route(web::method(Method::OPTIONS).to(options)));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropName {
+  pub namespace: String,
+  pub name: String,
+}
+
+impl PropName {
+  fn new(namespace: &str, name: &str) -> PropName {
+    PropName {
+      namespace: String::from(namespace),
+      name: String::from(name),
+    }
+  }
+
+  fn is(&self, namespace: &str, name: &str) -> bool {
+    self.namespace == namespace && self.name == name
+  }
+
+  // properties of unknown namespaces declare their own prefix
+  fn element(&self) -> (String, String) {
+    match PREFIXES.iter().find(|(_, ns)| *ns == self.namespace) {
+      Some((prefix, _)) => {
+        let name = format!("{}:{}", prefix, self.name);
+        (name.clone(), name)
+      },
+      None => (
+        format!(
+          "x:{} xmlns:x=\"{}\"", self.name, escape(&self.namespace),
+        ),
+        format!("x:{}", self.name),
+      ),
+    }
+  }
+}
+
+// the properties a PROPFIND asks for. An empty body, allprop and
+// propname are all answered like allprop
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropFind {
+  All,
+  Props(Vec<PropName>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+  // a calendar-query, which only matches elements if it asks for
+  // VTODOs. Time ranges and other filters are not evaluated
+  Query { props: Vec<PropName>, todos: bool },
+  Multiget { props: Vec<PropName>, names: Vec<String> },
+}
+
+fn is_element(node: &roxmltree::Node, namespace: &str, name: &str)
+  -> bool
+{
+  node.is_element()
+    && node.tag_name().namespace() == Some(namespace)
+    && node.tag_name().name() == name
+}
+
+fn prop_names(node: Option<roxmltree::Node>) -> Vec<PropName> {
+  node.iter()
+    .flat_map(|prop| prop.children())
+    .filter(|n| n.is_element())
+    .map(|n| PropName::new(
+      n.tag_name().namespace().unwrap_or(""), n.tag_name().name(),
+    ))
+    .collect()
+}
+
+pub fn parse_propfind(body: &str)
+  -> Result<PropFind, roxmltree::Error>
+{
+  if body.trim().is_empty() {
+    return Ok(PropFind::All);
+  }
+
+  let doc = roxmltree::Document::parse(body)?;
+
+  let prop = doc.root_element().children()
+    .find(|n| is_element(n, NS_DAV, "prop"));
+
+  Ok(match prop {
+    Some(prop) => PropFind::Props(prop_names(Some(prop))),
+    None => PropFind::All,
+  })
+}
+
+// None for reports other than calendar-query and calendar-multiget
+pub fn parse_report(body: &str)
+  -> Result<Option<Report>, roxmltree::Error>
+{
+  let doc = roxmltree::Document::parse(body)?;
+  let root = doc.root_element();
+
+  let props = prop_names(
+    root.children().find(|n| is_element(n, NS_DAV, "prop"))
+  );
+
+  if is_element(&root, NS_CALDAV, "calendar-query") {
+    let todos = root.descendants()
+      .filter(|n| is_element(n, NS_CALDAV, "comp-filter"))
+      .filter_map(|n| n.attribute("name"))
+      .all(|name| name == "VCALENDAR" || name == "VTODO");
+
+    return Ok(Some(Report::Query { props, todos }));
+  }
+
+  if is_element(&root, NS_CALDAV, "calendar-multiget") {
+    // hrefs may be absolute URLs, only the name of the element counts
+    let names = root.descendants()
+      .filter(|n| is_element(n, NS_DAV, "href"))
+      .filter_map(|n| n.text())
+      .filter_map(|href| href.trim().trim_end_matches('/')
+        .rsplit('/').next())
+      .map(String::from)
+      .collect();
+
+    return Ok(Some(Report::Multiget { props, names }));
+  }
+
+  Ok(None)
+}
+
+// an element as a calendar object
+#[derive(Debug, Clone)]
+pub struct Object {
+  name: String,
+  version: i64,
+  calendar: String,
+}
+
+impl TryFrom<&Document> for Object {
+  type Error = crate::errors::ParseDocumentError;
+
+  fn try_from(doc: &Document) -> Result<Self, Self::Error> {
+    let entry = Entry::from(Element::try_from(doc.clone())?);
+
+    let name = match doc.get_str("dav_name") {
+      Ok(name) => String::from(name),
+      Err(_) =>
+        format!("{}{}", doc.get_object_id("_id")?, OBJECT_SUFFIX),
+    };
+
+    let uid = match doc.get_str("dav_uid") {
+      Ok(uid) => String::from(uid),
+      Err(_) => ics_uid(&entry),
+    };
+
+    Ok(Object {
+      name,
+      version: version_of(doc),
+      calendar: ics_calendar(&entry, &uid),
+    })
+  }
+}
+
+pub enum Resource {
+  Root,
+  Principal,
+  Calendar { ctag: i64 },
+  Object(Object),
+}
+
+impl Resource {
+  fn href(&self, user: &str) -> String {
+    match self {
+      Resource::Root => format!("{}/", DAV_ROOT),
+      Resource::Principal => principal(user),
+      Resource::Calendar { .. } =>
+        format!("{}/{}/{}/", DAV_ROOT, user, CALENDAR),
+      Resource::Object(object) =>
+        format!("{}/{}/{}/{}", DAV_ROOT, user, CALENDAR, object.name),
+    }
+  }
+
+  // what allprop returns, calendar-data has to be asked for
+  fn all_props(&self) -> Vec<PropName> {
+    let names: &[(&str, &str)] = match self {
+      Resource::Root => &[
+        (NS_DAV, "resourcetype"), (NS_DAV, "current-user-principal"),
+      ],
+      Resource::Principal => &[
+        (NS_DAV, "resourcetype"), (NS_DAV, "displayname"),
+        (NS_DAV, "current-user-principal"), (NS_DAV, "principal-URL"),
+        (NS_CALDAV, "calendar-home-set"),
+      ],
+      Resource::Calendar { .. } => &[
+        (NS_DAV, "resourcetype"), (NS_DAV, "displayname"),
+        (NS_CALDAV, "supported-calendar-component-set"),
+        (NS_CALSERVER, "getctag"),
+      ],
+      Resource::Object(_) => &[
+        (NS_DAV, "resourcetype"), (NS_DAV, "getetag"),
+        (NS_DAV, "getcontenttype"),
+      ],
+    };
+
+    names.iter().map(|(ns, name)| PropName::new(ns, name)).collect()
+  }
+
+  // the value of prop as XML, None if the resource does not have it
+  fn prop(&self, user: &str, prop: &PropName) -> Option<String> {
+    let href = |href: String|
+      format!("<d:href>{}</d:href>", escape(&href));
+
+    if prop.is(NS_DAV, "current-user-principal") {
+      return Some(href(principal(user)));
+    }
+
+    match self {
+      _ if prop.is(NS_DAV, "resourcetype") => Some(String::from(
+        match self {
+          Resource::Root => "<d:collection/>",
+          Resource::Principal => "<d:collection/><d:principal/>",
+          Resource::Calendar { .. } => "<d:collection/><c:calendar/>",
+          Resource::Object(_) => "",
+        }
+      )),
+      Resource::Principal if prop.is(NS_DAV, "displayname") =>
+        Some(escape(user)),
+      Resource::Principal if prop.is(NS_DAV, "principal-URL")
+        || prop.is(NS_CALDAV, "calendar-home-set") =>
+        Some(href(principal(user))),
+      Resource::Calendar { .. } if prop.is(NS_DAV, "displayname") =>
+        Some(String::from(CALENDAR_NAME)),
+      Resource::Calendar { .. }
+        if prop.is(NS_CALDAV, "supported-calendar-component-set") =>
+        Some(String::from("<c:comp name=\"VTODO\"/>")),
+      Resource::Calendar { ctag } if prop.is(NS_CALSERVER, "getctag") =>
+        Some(ctag.to_string()),
+      Resource::Object(object) if prop.is(NS_DAV, "getetag") =>
+        Some(escape(&format!("\"{}\"", object.version))),
+      Resource::Object(_) if prop.is(NS_DAV, "getcontenttype") =>
+        Some(String::from(OBJECT_CONTENT_TYPE)),
+      Resource::Object(object) if prop.is(NS_CALDAV, "calendar-data") =>
+        Some(escape(&object.calendar)),
+      _ => None,
+    }
+  }
+
+  // the response element of a multistatus. Properties the resource
+  // does not have are listed as not found
+  pub fn response(&self, user: &str, props: &PropFind) -> String {
+    let props = match props {
+      PropFind::All => self.all_props(),
+      PropFind::Props(props) => props.clone(),
+    };
+
+    let mut found = String::new();
+    let mut missing = String::new();
+
+    for prop in &props {
+      let (open, close) = prop.element();
+
+      match self.prop(user, prop) {
+        Some(value) =>
+          found.push_str(&format!("<{}>{}</{}>", open, value, close)),
+        None => missing.push_str(&format!("<{}/>", open)),
+      }
+    }
+
+    let mut res = format!(
+      "<d:response><d:href>{}</d:href>", escape(&self.href(user))
+    );
+
+    let propstats = [(found, "200 OK"), (missing, "404 Not Found")];
+
+    for (props, status) in &propstats {
+      if !props.is_empty() {
+        res.push_str(&format!(
+          "<d:propstat><d:prop>{}</d:prop>\
+          <d:status>HTTP/1.1 {}</d:status></d:propstat>",
+          props, status,
+        ));
+      }
+    }
+
+    res.push_str("</d:response>");
+    res
+  }
+}
+
+fn principal(user: &str) -> String {
+  format!("{}/{}/", DAV_ROOT, user)
+}
+
+pub fn escape(text: &str) -> String {
+  text.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+pub fn multistatus(responses: &[String]) -> HttpResponse {
+  let namespaces: String = PREFIXES.iter()
+    .map(|(prefix, ns)| format!(" xmlns:{}=\"{}\"", prefix, ns))
+    .collect();
+
+  HttpResponse::build(StatusCode::MULTI_STATUS)
+    .content_type("application/xml; charset=utf-8")
+    .body(format!(
+      "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+      <d:multistatus{}>{}</d:multistatus>",
+      namespaces, responses.concat(),
+    ))
+}
+
+fn not_found(user: &str, name: &str) -> String {
+  format!(
+    "<d:response><d:href>{}/{}/{}/{}</d:href>\
+    <d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+    DAV_ROOT, escape(user), CALENDAR, escape(name),
+  )
+}
+
+// Depth: 0 only describes the resource itself, 1 its members as well.
+// Infinity is treated like 1
+fn with_members(req: &HttpRequest) -> bool {
+  req.headers().get("Depth")
+    .and_then(|depth| depth.to_str().ok())
+    .is_none_or(|depth| depth.trim() != "0")
+}
+
+// elements are found by the name a client gave them or by their id
+fn object_filter(user: &str, name: &str) -> Document {
+  let mut clauses = vec![doc!{"dav_name": name}];
+
+  if let Some(id) = name.strip_suffix(OBJECT_SUFFIX) {
+    if let Ok(id) = ObjectId::with_string(id) {
+      clauses.push(doc!{"_id": id, "dav_name": {"$exists": false}});
+    }
+  }
+
+  doc!{"user": user, "$or": clauses}
+}
+
+async fn objects(store: &Store, filter: Document)
+  -> Result<Vec<Object>, StoreError>
+{
+  let mut cursor = store.elements.find(filter, None).await?;
+
+  let mut res: Vec<Object> = Vec::new();
+
+  while let Some(result) = cursor.next().await {
+    res.push(Object::try_from(&result?)?);
+  }
+
+  Ok(res)
+}
+
+async fn options() -> HttpResponse {
+  HttpResponse::Ok()
+    .header("DAV", "1, calendar-access")
+    .header("Allow", "OPTIONS, GET, PUT, DELETE, PROPFIND, REPORT")
+    .finish()
+}
+
+async fn propfind_root(
+  user: web::ReqData<AuthenticatedUser>,
+  body: String) -> HttpResponse
+{
+  let props = match parse_propfind(&body) {
+    Ok(props) => props,
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  multistatus(&[Resource::Root.response(&user.username, &props)])
+}
+
+async fn propfind_principal(
+  req: HttpRequest,
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  body: String) -> HttpResponse
+{
+  let props = match parse_propfind(&body) {
+    Ok(props) => props,
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  let mut responses = vec![Resource::Principal.response(&user, &props)];
+
+  if with_members(&req) {
+    let ctag = store.revision(&user).await.unwrap();
+    responses.push(Resource::Calendar { ctag }
+      .response(&user, &props));
+  }
+
+  multistatus(&responses)
+}
+
+async fn propfind_calendar(
+  req: HttpRequest,
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  body: String) -> HttpResponse
+{
+  let props = match parse_propfind(&body) {
+    Ok(props) => props,
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  let ctag = store.revision(&user).await.unwrap();
+  let mut responses = vec![
+    Resource::Calendar { ctag }.response(&user, &props),
+  ];
+
+  if with_members(&req) {
+    for object in objects(&store, doc!{"user": &user}).await.unwrap() {
+      responses.push(Resource::Object(object).response(&user, &props));
+    }
+  }
+
+  multistatus(&responses)
+}
+
+async fn propfind_object(
+  web::Path((user, name)): web::Path<(String, String)>,
+  store: web::Data<Store>,
+  body: String) -> HttpResponse
+{
+  let props = match parse_propfind(&body) {
+    Ok(props) => props,
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  let objects = objects(&store, object_filter(&user, &name))
+    .await
+    .unwrap();
+
+  match objects.into_iter().next() {
+    Some(object) =>
+      multistatus(&[Resource::Object(object).response(&user, &props)]),
+    None => HttpResponse::NotFound().finish(),
+  }
+}
+
+async fn report_calendar(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  body: String) -> HttpResponse
+{
+  let report = match parse_report(&body) {
+    Ok(Some(report)) => report,
+    // RFC 3253 wants a supported-report precondition for this, which
+    // clients do not look at
+    Ok(None) => return HttpResponse::Forbidden().finish(),
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  match report {
+    Report::Query { props, todos } => {
+      let props = PropFind::Props(props);
+
+      let objects = match todos {
+        true => objects(&store, doc!{"user": &user}).await.unwrap(),
+        false => Vec::new(),
+      };
+
+      let responses: Vec<String> = objects.into_iter()
+        .map(|object| Resource::Object(object).response(&user, &props))
+        .collect();
+
+      multistatus(&responses)
+    },
+    Report::Multiget { props, names } => {
+      let props = PropFind::Props(props);
+      let mut responses: Vec<String> = Vec::new();
+
+      for name in &names {
+        let object = objects(&store, object_filter(&user, name))
+          .await
+          .unwrap()
+          .into_iter()
+          .next();
+
+        responses.push(match object {
+          Some(object) =>
+            Resource::Object(object).response(&user, &props),
+          None => not_found(&user, name),
+        });
+      }
+
+      multistatus(&responses)
+    },
+  }
+}
+
+async fn get_object(
+  web::Path((user, name)): web::Path<(String, String)>,
+  store: web::Data<Store>) -> HttpResponse
+{
+  let objects = objects(&store, object_filter(&user, &name))
+    .await
+    .unwrap();
+
+  match objects.into_iter().next() {
+    Some(object) => HttpResponse::Ok()
+      .content_type("text/calendar; charset=utf-8")
+      .set(etag(object.version))
+      .body(object.calendar),
+    None => HttpResponse::NotFound().finish(),
+  }
+}
+
+// creates or replaces the element with the single VTODO of the body.
+// If-None-Match: * and If-Match are honored, so clients do not
+// overwrite changes they have not seen
+async fn put_object(
+  req: HttpRequest,
+  web::Path((user, name)): web::Path<(String, String)>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>,
+  body: String) -> HttpResponse
+{
+  let decoded = ImportFormat::Ics.decode(&body, &Columns::default());
+
+  let entry = match decoded {
+    Ok(mut decoded) if decoded.len() == 1 =>
+      match decoded.pop().unwrap().entry {
+        Ok(entry) => entry,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+      },
+    _ => return HttpResponse::BadRequest().finish(),
+  };
+
+  let if_match = match if_match_header(&req) {
+    Ok(if_match) => if_match,
+    Err(res) => return res,
+  };
+
+  let if_none_match = req.headers().get(IF_NONE_MATCH)
+    .is_some_and(|v| v.as_bytes() == b"*");
+
+  let mut filter = object_filter(&user, &name);
+
+  let current = store.elements.find_one(filter.clone(), None)
+    .await
+    .unwrap();
+
+  let current = match current {
+    Some(current) => current,
+    None if if_match.is_some() =>
+      return HttpResponse::PreconditionFailed().finish(),
+    None => return create_object(&store, &events, &user, &name, entry)
+      .await,
+  };
+
+  let version = version_of(&current);
+
+  if if_none_match || !if_match.is_none_or(|c| matches(&c, version)) {
+    return HttpResponse::PreconditionFailed().finish();
+  }
+
+  filter.insert("version", version_clause(version));
+
+  let revision = store.next_revision(&user).await.unwrap();
+
+  let update = doc!{
+    "$set": {
+      "content": entry.content,
+      "status": to_bson(&entry.status).unwrap(),
+      "revision": revision,
+    },
+    "$inc": {"version": 1i64},
+  };
+
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
+
+  let updated = store.elements
+    .find_one_and_update(filter.clone(), update, options)
+    .await
+    .unwrap();
+
+  let updated = match updated {
+    Some(updated) => updated,
+    None => return vanished(&filter),
+  };
+
+  store.record(
+    &Change::new(&user),
+    EventKind::Updated,
+    Some(&current),
+    Some(&updated),
+  ).await.unwrap();
+
+  events.publish(&store, EventKind::Updated, &updated).await;
+
+  HttpResponse::NoContent().set(etag(version_of(&updated))).finish()
+}
+
+async fn create_object(
+  store: &Store,
+  events: &EventBus,
+  user: &str,
+  name: &str,
+  entry: Entry) -> HttpResponse
+{
+  let uid = entry.id.clone();
+
+  let mut doc = to_mongodb_import_entry(entry, String::from(user))
+    .unwrap();
+
+  doc.insert("_id", ObjectId::new());
+  doc.insert("dav_name", name);
+  doc.insert("revision", store.next_revision(user).await.unwrap());
+
+  if let Some(uid) = uid {
+    doc.insert("dav_uid", uid);
+  }
+
+  store.elements.insert_one(doc.clone(), None).await.unwrap();
+
+  store.record(&Change::new(user), EventKind::Created, None, Some(&doc))
+    .await
+    .unwrap();
+
+  events.publish(store, EventKind::Created, &doc).await;
+
+  HttpResponse::Created().set(etag(version_of(&doc))).finish()
+}
+
+async fn delete_object(
+  req: HttpRequest,
+  web::Path((user, name)): web::Path<(String, String)>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>) -> HttpResponse
+{
+  let filter = object_filter(&user, &name);
+
+  let if_match = match if_match_header(&req) {
+    Ok(if_match) => if_match,
+    Err(res) => return res,
+  };
+
+  let deleted = delete_matching(
+    if_match.as_ref(), &store, &events, &user, filter,
+  ).await;
+
+  match deleted {
+    Ok(_) => HttpResponse::NoContent().finish(),
+    Err(res) => res,
+  }
+}
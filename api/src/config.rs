@@ -0,0 +1,387 @@
+use actix_web::http::{HeaderName, Method};
+use actix_web::http::header::{ETAG, LINK};
+
+use actix_cors::Cors;
+
+use serde_derive::{Serialize, Deserialize};
+
+use toml::value::{Table, Value};
+
+use yata_common::cors::{self, CorsConfig};
+use yata_common::logging::LogFormat;
+use yata_common::rate_limit::{Network, Rule};
+
+pub use yata_common::list::List;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::api_version::parse_date;
+
+static ENV_PREFIX: &str = "YATA_API_";
+
+static REDACTED: &str = "********";
+
+// origin of the frontend when served by flutter's development server
+static FRONTEND_ORIGIN: &str = "http://localhost:8000";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+  pub port: u16,
+  // full connection string, so credentials, replica sets and other
+  // options can be given
+  pub mongodb_uri: String,
+  pub database: String,
+  pub keycloak_proxy_url: String,
+  // how often connecting to mongodb and fetching the key set is tried
+  // on startup before giving up
+  pub startup_attempts: u32,
+  pub startup_backoff_max_secs: u64,
+  // in-flight requests get this long to finish on SIGTERM
+  pub shutdown_timeout_secs: u64,
+  pub log_level: String,
+  pub log_format: LogFormat,
+  pub cors_allowed_origins: List,
+  pub cors_allowed_methods: List,
+  pub cors_allowed_headers: List,
+  pub cors_max_age_secs: usize,
+  // addresses or networks of reverse proxies whose X-Forwarded-For
+  // header is trusted
+  pub trusted_proxies: List,
+  // limits per user, in the format of Rule
+  pub rate_limits: List,
+  // limits per client address, checked before authenticating
+  pub client_rate_limits: List,
+  // serves Swagger UI for /openapi.json at /docs
+  pub swagger_ui: bool,
+  // dates of the deprecation and the removal of the routes at the
+  // root, which moved below /api/v1
+  pub legacy_routes_deprecated: String,
+  pub legacy_routes_sunset: String,
+  // bearer token scrapes of /metrics need, nobody can scrape without
+  pub metrics_token: Option<String>,
+  // how often the element counts of /metrics are refreshed
+  pub metrics_refresh_secs: u64,
+  // shared secret keycloak_proxy sends when it deletes the account of
+  // a user. Users can not be deleted without it
+  pub service_token: Option<String>,
+}
+
+impl Config {
+  // reads the optional toml file given by --config or YATA_API_CONFIG,
+  // environment variables take precedence over its values
+  pub fn load(args: &[String]) -> Result<Config, ConfigError> {
+    let path = args.iter()
+      .position(|a| a == "--config")
+      .and_then(|i| args.get(i + 1).cloned())
+      .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+
+    let file = match path {
+      Some(path) => read_file(&path)?,
+      None => Table::new(),
+    };
+
+    Config::from_sources(file, |key| env::var(key).ok())
+  }
+
+  pub fn from_sources<F>(file: Table, env: F)
+    -> Result<Config, ConfigError>
+    where F: Fn(&str) -> Option<String>
+  {
+    let mut s = Source::new(file, env);
+
+    let mongodb_uri = match s.optional::<String>("mongodb_uri") {
+      Some(uri) => uri,
+      None => format!(
+        "mongodb://{}:{}",
+        s.get::<String>("mongodb_server", None),
+        s.get("mongodb_port", Some(27017u16)),
+      ),
+    };
+
+    let keycloak_proxy_url =
+      match s.optional::<String>("keycloak_proxy_url") {
+        Some(url) => url,
+        None => format!(
+          "{}://{}:{}",
+          s.get("keycloak_proxy_scheme", Some(String::from("http"))),
+          s.get::<String>("keycloak_proxy_server", None),
+          s.get("keycloak_proxy_port", Some(9998u16)),
+        ),
+      };
+
+    let config = Config {
+      port: s.get("port", Some(9999)),
+      mongodb_uri,
+      database: s.get("database", Some(String::from("yata_db"))),
+      keycloak_proxy_url:
+        keycloak_proxy_url.trim_end_matches("/").to_string(),
+      startup_attempts: s.get("startup_attempts", Some(10)),
+      startup_backoff_max_secs:
+        s.get("startup_backoff_max_secs", Some(30)),
+      shutdown_timeout_secs: s.get("shutdown_timeout_secs", Some(30)),
+      log_level: s.get("log_level", Some(String::from("info"))),
+      log_format: s.get("log_format", Some(LogFormat::Text)),
+      cors_allowed_origins: s.get(
+        "cors_allowed_origins", Some(List::of(&[FRONTEND_ORIGIN])),
+      ),
+      cors_allowed_methods: s.get(
+        "cors_allowed_methods",
+        Some(List::of(&["GET", "POST", "PUT", "DELETE"])),
+      ),
+      cors_allowed_headers: s.get(
+        "cors_allowed_headers",
+        Some(List::of(&[
+          "Authorization", "Content-Type", "X-Request-Id", "If-Match",
+        ])),
+      ),
+      cors_max_age_secs: s.get("cors_max_age_secs", Some(3600)),
+      trusted_proxies: s.get("trusted_proxies", Some(List::default())),
+      rate_limits: s.get("rate_limits", Some(List::of(&[
+        "POST /{user}/add_todo 60/60",
+        "POST * 30/60",
+        "PUT * 120/60",
+        "DELETE * 60/60",
+      ]))),
+      client_rate_limits: s.get(
+        "client_rate_limits", Some(List::of(&["* * 600/60"])),
+      ),
+      swagger_ui: s.get("swagger_ui", Some(false)),
+      legacy_routes_deprecated: s.get(
+        "legacy_routes_deprecated", Some(String::from("2026-10-19")),
+      ),
+      legacy_routes_sunset: s.get(
+        "legacy_routes_sunset", Some(String::from("2027-04-19")),
+      ),
+      metrics_token: s.optional("metrics_token"),
+      metrics_refresh_secs: s.get("metrics_refresh_secs", Some(60)),
+      service_token: s.optional("service_token"),
+    };
+
+    s.check(
+      "cors_allowed_methods",
+      config.cors_allowed_methods.iter()
+        .all(|m| Method::from_bytes(m.as_bytes()).is_ok()),
+    );
+    s.check(
+      "cors_allowed_headers",
+      config.cors_allowed_headers.iter().all(|h| {
+        h == "*" || HeaderName::from_bytes(h.as_bytes()).is_ok()
+      }),
+    );
+
+    s.check(
+      "trusted_proxies",
+      config.trusted_proxies.iter()
+        .all(|n| n.parse::<Network>().is_ok()),
+    );
+    s.check(
+      "rate_limits",
+      config.rate_limits.iter().all(|r| r.parse::<Rule>().is_ok()),
+    );
+    s.check(
+      "client_rate_limits",
+      config.client_rate_limits.iter()
+        .all(|r| r.parse::<Rule>().is_ok()),
+    );
+
+    s.check(
+      "legacy_routes_deprecated",
+      parse_date(&config.legacy_routes_deprecated).is_some(),
+    );
+    s.check(
+      "legacy_routes_sunset",
+      parse_date(&config.legacy_routes_sunset).is_some(),
+    );
+
+    s.check("metrics_refresh_secs", config.metrics_refresh_secs > 0);
+
+    s.finish()?;
+    Ok(config)
+  }
+
+  pub fn certs_url(&self) -> String {
+    format!("{}/certs", self.keycloak_proxy_url)
+  }
+
+  pub fn startup_backoff_max(&self) -> Duration {
+    Duration::from_secs(self.startup_backoff_max_secs)
+  }
+
+  pub fn metrics_refresh(&self) -> Duration {
+    Duration::from_secs(self.metrics_refresh_secs)
+  }
+
+  fn cors_config(&self) -> CorsConfig<'_> {
+    CorsConfig {
+      allowed_origins: &self.cors_allowed_origins,
+      allowed_methods: &self.cors_allowed_methods,
+      allowed_headers: &self.cors_allowed_headers,
+      max_age_secs: self.cors_max_age_secs,
+    }
+  }
+
+  // browsers may read the versions and pages of elements and the
+  // deprecation of the legacy routes besides the request id
+  pub fn cors(&self) -> Cors {
+    cors::cors(
+      &self.cors_config(),
+      &[ETAG.as_str(), LINK.as_str(), "Deprecation", "Sunset"],
+    )
+  }
+
+  // the configuration as toml, without the password of the mongodb
+  // connection string and the metrics token
+  pub fn print(&self) -> String {
+    let mut redacted = self.clone();
+    redacted.mongodb_uri = redact_password(&self.mongodb_uri);
+    redacted.metrics_token = self.metrics_token.as_ref()
+      .map(|_| String::from(REDACTED));
+    redacted.service_token = self.service_token.as_ref()
+      .map(|_| String::from(REDACTED));
+    toml::to_string(&redacted).unwrap()
+  }
+}
+
+fn redact_password(uri: &str) -> String {
+  let (scheme, rest) = match uri.find("://") {
+    Some(i) => (&uri[..i + 3], &uri[i + 3..]),
+    None => return String::from(uri),
+  };
+
+  let credentials = match rest.find("@") {
+    Some(i) => &rest[..i],
+    None => return String::from(uri),
+  };
+
+  match credentials.find(":") {
+    Some(i) => format!(
+      "{}{}:{}{}", scheme, &credentials[..i], REDACTED,
+      &rest[credentials.len()..],
+    ),
+    None => String::from(uri),
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigError {
+  file: Option<String>,
+  missing: Vec<String>,
+  invalid: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "invalid configuration")?;
+
+    if let Some(file) = &self.file {
+      writeln!(f, "  config file: {}", file)?;
+    }
+
+    for key in &self.missing {
+      writeln!(f, "  missing: {}", key)?;
+    }
+
+    for key in &self.invalid {
+      writeln!(f, "  invalid: {}", key)?;
+    }
+
+    Ok(())
+  }
+}
+
+fn read_file(path: &str) -> Result<Table, ConfigError> {
+  let error = |e: String| ConfigError {
+    file: Some(format!("{}: {}", path, e)), ..Default::default()
+  };
+
+  let content = fs::read_to_string(path)
+    .map_err(|e| error(e.to_string()))?;
+  toml::from_str(&content).map_err(|e| error(e.to_string()))
+}
+
+// looks up keys first in the environment (upper case, prefixed with
+// YATA_API_), then in the config file. Missing and invalid keys
+// are collected, so they can all be reported at once
+struct Source<F> {
+  file: Table,
+  env: F,
+  error: ConfigError,
+}
+
+impl<F: Fn(&str) -> Option<String>> Source<F> {
+  fn new(file: Table, env: F) -> Source<F> {
+    Source{file, env, error: ConfigError::default()}
+  }
+
+  fn env_key(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+  }
+
+  fn raw(&self, key: &str) -> Option<String> {
+    (self.env)(&Self::env_key(key)).or_else(|| {
+      match self.file.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(values) => Some(values.iter()
+          .map(|v| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+          })
+          .collect::<Vec<String>>()
+          .join(",")),
+        v => Some(v.to_string()),
+      }
+    })
+  }
+
+  fn optional<T: FromStr>(&mut self, key: &str) -> Option<T> {
+    let raw = self.raw(key)?;
+
+    match raw.parse() {
+      Ok(v) => Some(v),
+      Err(_) => {
+        self.error.invalid.push(format!(
+          "{} (or {} in the config file): {:?}",
+          Self::env_key(key), key, raw,
+        ));
+        None
+      }
+    }
+  }
+
+  fn get<T: FromStr + Default>(&mut self, key: &str, default: Option<T>)
+    -> T
+  {
+    if self.raw(key).is_none() {
+      match default {
+        Some(default) => return default,
+        None => self.error.missing.push(format!(
+          "{} (or {} in the config file)", Self::env_key(key), key,
+        )),
+      }
+    }
+
+    self.optional(key).unwrap_or_default()
+  }
+
+  // for values that parse, but are still not valid
+  fn check(&mut self, key: &str, valid: bool) {
+    if !valid {
+      self.error.invalid.push(format!(
+        "{} (or {} in the config file): {:?}",
+        Self::env_key(key), key, self.raw(key).unwrap_or_default(),
+      ));
+    }
+  }
+
+  fn finish(self) -> Result<(), ConfigError> {
+    if self.error.missing.is_empty() && self.error.invalid.is_empty() {
+      Ok(())
+    } else {
+      Err(self.error)
+    }
+  }
+}
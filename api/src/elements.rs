@@ -1,36 +1,209 @@
 use serde_derive::{Serialize, Deserialize};
 
+use async_graphql::Enum;
+
+use utoipa::ToSchema;
+
 use mongodb::bson::Document;
 use mongodb::bson::from_bson;
 
 use chrono::DateTime;
 use chrono::offset::Utc;
 
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::errors::ParseDocumentError;
+use crate::shares::Permission;
+use crate::versions::version_of;
+
+#[derive(Serialize, Deserialize, Enum, ToSchema, Debug, Clone, Copy,
+  PartialEq, Eq)]
 pub enum ElementStatus { Todo, Done, Deleted }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
 pub struct Element {
   id: String,
   content: String,
   status: ElementStatus,
   created: DateTime<Utc>,
+  version: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  shared: Option<SharedElement>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct SharedElement {
+  owner: String,
+  permission: Permission,
+}
+
+// what changed for a client since its last sync. The token is passed
+// as since with the next one. Elements may be sent again with later
+// syncs, clients replace them by id
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Changes {
+  pub elements: Vec<Element>,
+  pub deleted: Vec<String>,
+  pub token: String,
+}
+
+// the revisions of the owners whose elements a client synced, as in
+// "alice:12,bob:7". Usernames contain neither ':' nor ','
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncToken(BTreeMap<String, i64>);
+
+impl SyncToken {
+  // tokens from before shared elements were synced are the revision
+  // of user alone
+  pub fn parse(token: &str, user: &str) -> Option<SyncToken> {
+    if let Ok(revision) = token.parse() {
+      return Some(SyncToken::default().with(user, revision));
+    }
+
+    let mut res = SyncToken::default();
+
+    for pair in token.split(',').filter(|p| !p.is_empty()) {
+      let colon = pair.rfind(':')?;
+      let revision = pair[colon + 1..].parse().ok()?;
+      res.0.insert(String::from(&pair[..colon]), revision);
+    }
+
+    Some(res)
+  }
+
+  pub fn with(mut self, owner: &str, revision: i64) -> SyncToken {
+    self.0.insert(String::from(owner), revision);
+    self
+  }
+
+  pub fn get(&self, owner: &str) -> Option<i64> {
+    self.0.get(owner).copied()
+  }
+}
+
+impl fmt::Display for SyncToken {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let pairs: Vec<String> = self.0.iter()
+      .map(|(owner, revision)| format!("{}:{}", owner, revision))
+      .collect();
+
+    write!(f, "{}", pairs.join(","))
+  }
+}
+
+impl Element {
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn content(&self) -> &str {
+    &self.content
+  }
+
+  pub fn status(&self) -> ElementStatus {
+    self.status
+  }
+
+  pub fn created(&self) -> DateTime<Utc> {
+    self.created
+  }
+
+  pub fn version(&self) -> i64 {
+    self.version
+  }
+
+  // the owner of an element shared with the reader and the permission
+  // the reader was granted
+  pub fn shared(&self) -> Option<(&str, Permission)> {
+    self.shared.as_ref().map(|s| (s.owner.as_str(), s.permission))
+  }
+
+  pub fn mark_shared(&mut self, owner: String, permission: Permission) {
+    self.shared = Some(SharedElement{
+      owner, permission
+    });
+  }
 }
 
 impl TryFrom<Document> for Element {
-  type Error = crate::errors::ParseDocumentError;
+  type Error = ParseDocumentError;
 
   fn try_from(doc: Document) -> Result<Self, Self::Error> {
     let id = doc.get_object_id("_id")?.to_hex();
     let content = String::from(doc.get_str("content")?);
-    let status: ElementStatus =
-      from_bson(doc.get("status")?.clone())?;
+    let status: ElementStatus = from_bson(
+      doc.get("status").ok_or(ParseDocumentError::NotPresent)?.clone(),
+    )?;
     let created = *doc.get_datetime("created")?;
+    let version = version_of(&doc);
 
     Ok(Element{
-      id: id, content: content, status: status, created: created
+      id,
+      content,
+      status,
+      created,
+      version,
+      shared: None,
     })
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use mongodb::bson::{doc, to_bson};
+  use mongodb::bson::oid::ObjectId;
+
+  #[test]
+  fn test_element_from_mongodb_document()
+    -> Result<(), ParseDocumentError>
+  {
+    let doc = doc! {
+      "_id": ObjectId::new(),
+      "content": String::from("some content"),
+      "status": to_bson(&ElementStatus::Todo).unwrap(),
+      "created": Utc::now()
+    };
+
+    Element::try_from(doc)?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_shared_element_is_marked() -> Result<(), ParseDocumentError> {
+    let doc = doc! {
+      "_id": ObjectId::new(),
+      "content": String::from("some content"),
+      "status": to_bson(&ElementStatus::Todo).unwrap(),
+      "created": Utc::now()
+    };
+
+    let mut element = Element::try_from(doc)?;
+    element.mark_shared(String::from("some owner"), Permission::Viewer);
+
+    let json = serde_json::to_value(&element).unwrap();
+    assert_eq!(json["shared"]["owner"], "some owner");
+    assert_eq!(json["shared"]["permission"], "Viewer");
+    Ok(())
+  }
+
+  #[test]
+  fn test_sync_tokens_keep_a_revision_per_owner() {
+    let token = SyncToken::default().with("bob", 7).with("alice", 12);
+    assert_eq!(token.to_string(), "alice:12,bob:7");
+    assert_eq!(
+      SyncToken::parse("alice:12,bob:7", "alice"), Some(token),
+    );
+
+    // tokens of older servers only know the revision of user
+    let old = SyncToken::parse("12", "alice").unwrap();
+    assert_eq!(old.get("alice"), Some(12));
+    assert_eq!(old.get("bob"), None);
+
+    assert_eq!(SyncToken::parse("alice", "alice"), None);
+    assert_eq!(SyncToken::parse("alice:twelve", "alice"), None);
+  }
+}
@@ -1,9 +1,16 @@
+use actix_web::{HttpResponse, ResponseError};
+
 use mongodb::bson::document::ValueAccessError;
 use mongodb::bson::de::Error as BsonDeserializationError;
 use mongodb::bson::ser::Error as BsonSerializationError;
+use mongodb::error::Error as MDBError;
+
+use serde_json::Error as JsonError;
+
+use tracing::warn;
 
 use std::convert::From;
-use std::option::NoneError;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum ParseDocumentError {
@@ -14,12 +21,6 @@ pub enum ParseDocumentError {
   Impossible
 }
 
-impl From<NoneError> for ParseDocumentError {
-  fn from(_: NoneError) -> Self {
-    ParseDocumentError::NotPresent
-  }
-}
-
 impl From<ValueAccessError> for ParseDocumentError {
   fn from(e: ValueAccessError) -> Self {
     match e {
@@ -43,3 +44,84 @@ impl From<BsonSerializationError> for ParseDocumentError {
     ParseDocumentError::BsonSerializationError(e)
   }
 }
+
+#[derive(Debug)]
+pub enum StoreError {
+  Database(MDBError),
+  ParseDocument(ParseDocumentError),
+}
+
+impl fmt::Display for StoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StoreError::Database(e) => write!(f, "database error: {}", e),
+      StoreError::ParseDocument(e) =>
+        write!(f, "could not parse document: {:?}", e),
+    }
+  }
+}
+
+// the details are logged, clients only learn that the request failed
+impl ResponseError for StoreError {
+  fn error_response(&self) -> HttpResponse {
+    warn!(error = %self, "could not handle the request");
+    HttpResponse::InternalServerError().finish()
+  }
+}
+
+impl From<MDBError> for StoreError {
+  fn from(e: MDBError) -> Self {
+    StoreError::Database(e)
+  }
+}
+
+impl From<ParseDocumentError> for StoreError {
+  fn from(e: ParseDocumentError) -> Self {
+    StoreError::ParseDocument(e)
+  }
+}
+
+impl From<ValueAccessError> for StoreError {
+  fn from(e: ValueAccessError) -> Self {
+    StoreError::ParseDocument(ParseDocumentError::from(e))
+  }
+}
+
+impl From<BsonSerializationError> for StoreError {
+  fn from(e: BsonSerializationError) -> Self {
+    StoreError::ParseDocument(ParseDocumentError::from(e))
+  }
+}
+
+// input in one of the exchange formats could not be read
+#[derive(Debug)]
+pub enum DecodeError {
+  Json(JsonError),
+  Csv(csv::Error),
+  MissingColumn(String),
+  Line(usize, String),
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DecodeError::Json(e) => write!(f, "{}", e),
+      DecodeError::Csv(e) => write!(f, "{}", e),
+      DecodeError::MissingColumn(column) =>
+        write!(f, "missing column {}", column),
+      DecodeError::Line(line, e) => write!(f, "line {}: {}", line, e),
+    }
+  }
+}
+
+impl From<JsonError> for DecodeError {
+  fn from(e: JsonError) -> Self {
+    DecodeError::Json(e)
+  }
+}
+
+impl From<csv::Error> for DecodeError {
+  fn from(e: csv::Error) -> Self {
+    DecodeError::Csv(e)
+  }
+}
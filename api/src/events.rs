@@ -0,0 +1,225 @@
+use actix_web::web::Bytes;
+use actix_web::rt::{spawn, time};
+
+use futures::channel::mpsc::{Receiver, Sender, channel};
+
+use mongodb::bson::Document;
+
+use serde_derive::{Serialize, Deserialize};
+
+use async_graphql::Enum;
+
+use utoipa::ToSchema;
+
+use tracing::warn;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::elements::Element;
+use crate::errors::StoreError;
+use crate::shares::Permission;
+use crate::store::Store;
+
+// messages a client may fall behind before it is dropped. It is
+// expected to reconnect and refetch its elements
+static CLIENT_BUFFER: usize = 64;
+
+// keeps idle connections from being closed by proxies in between
+static HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Enum, ToSchema, Debug, Clone, Copy,
+  PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind { Created, Updated, Deleted }
+
+impl EventKind {
+  fn name(&self) -> &'static str {
+    match self {
+      EventKind::Created => "created",
+      EventKind::Updated => "updated",
+      EventKind::Deleted => "deleted",
+    }
+  }
+}
+
+// a change of an element as delivered to GraphQL subscriptions
+#[derive(Debug, Clone)]
+pub struct ElementChange {
+  pub kind: EventKind,
+  pub element: Element,
+}
+
+type Clients<T> = Arc<Mutex<HashMap<String, Vec<Sender<T>>>>>;
+
+// broadcasts changes of elements as server-sent events to the clients
+// of every user able to read them.
+//
+// MongoDB change streams would also deliver writes handled by other
+// instances of the service, but the mongodb driver in use (1.x) does
+// not support them, so events are published by the routes doing the
+// writes and only reach clients connected to the same instance.
+// Listeners get the same changes unformatted
+#[derive(Clone, Default)]
+pub struct EventBus {
+  clients: Clients<Bytes>,
+  listeners: Clients<ElementChange>,
+  closed: Arc<AtomicBool>,
+}
+
+impl EventBus {
+  pub fn new() -> EventBus {
+    EventBus::default()
+  }
+
+  pub fn subscribe(&self, user: &str) -> Receiver<Bytes> {
+    let (mut sender, receiver) = channel(CLIENT_BUFFER);
+
+    // tells the client how long to wait before reconnecting
+    let _ = sender.try_send(Bytes::from_static(b"retry: 5000\n\n"));
+
+    self.clients.lock().unwrap()
+      .entry(String::from(user))
+      .or_default()
+      .push(sender);
+
+    receiver
+  }
+
+  pub fn listen(&self, user: &str) -> Receiver<ElementChange> {
+    let (sender, receiver) = channel(CLIENT_BUFFER);
+
+    self.listeners.lock().unwrap()
+      .entry(String::from(user))
+      .or_default()
+      .push(sender);
+
+    receiver
+  }
+
+  // sends the element in doc to everyone who can read it. Publishing
+  // is best effort, it must not fail the write that already happened
+  pub async fn publish(
+    &self,
+    store: &Store,
+    kind: EventKind,
+    doc: &Document)
+  {
+    if let Err(e) = self.try_publish(store, kind, doc).await {
+      warn!(error = ?e, "could not publish element event");
+    }
+  }
+
+  // sends the elements owner just created in docs. They are new, so
+  // no share of a single element includes them and their audience is
+  // the same
+  pub async fn publish_created(
+    &self,
+    store: &Store,
+    owner: &str,
+    docs: &[Document])
+  {
+    let published = async {
+      let audience = store.list_audience(owner).await?;
+
+      for doc in docs {
+        self.send_to(&audience, owner, EventKind::Created, doc)?;
+      }
+
+      Ok::<_, StoreError>(())
+    };
+
+    if let Err(e) = published.await {
+      warn!(error = ?e, "could not publish element events");
+    }
+  }
+
+  async fn try_publish(
+    &self,
+    store: &Store,
+    kind: EventKind,
+    doc: &Document) -> Result<(), StoreError>
+  {
+    let owner = doc.get_str("user")?;
+    let id = doc.get_object_id("_id")?;
+
+    let audience = store.audience(owner, id).await?;
+    self.send_to(&audience, owner, kind, doc)
+  }
+
+  // the element is marked as shared for the members of shares
+  fn send_to(
+    &self,
+    audience: &[(String, Option<Permission>)],
+    owner: &str,
+    kind: EventKind,
+    doc: &Document) -> Result<(), StoreError>
+  {
+    for (user, permission) in audience {
+      let mut element = Element::try_from(doc.clone())?;
+
+      if let Some(permission) = *permission {
+        element.mark_shared(String::from(owner), permission);
+      }
+
+      let message = format!(
+        "event: {}\ndata: {}\n\n",
+        kind.name(),
+        serde_json::to_string(&element).unwrap(),
+      );
+
+      send(&self.clients, Some(user), Bytes::from(message));
+
+      send(&self.listeners, Some(user), ElementChange {
+        kind,
+        element,
+      });
+    }
+
+    Ok(())
+  }
+
+  pub fn spawn_heartbeat(&self) {
+    let bus = self.clone();
+
+    spawn(async move {
+      let mut interval = time::interval(HEARTBEAT_INTERVAL);
+
+      while !bus.closed.load(Ordering::Relaxed) {
+        interval.tick().await;
+        let heartbeat = Bytes::from_static(b": heartbeat\n\n");
+        send(&bus.clients, None, heartbeat);
+      }
+    });
+  }
+
+  // ends all streams and stops the heartbeat
+  pub fn close(&self) {
+    self.closed.store(true, Ordering::Relaxed);
+    self.clients.lock().unwrap().clear();
+    self.listeners.lock().unwrap().clear();
+  }
+}
+
+// clients that are gone or can not keep up are dropped. Without a
+// user, the message goes to every client
+fn send<T: Clone>(clients: &Clients<T>, user: Option<&str>, message: T)
+{
+  let mut clients = clients.lock().unwrap();
+
+  for (u, senders) in clients.iter_mut() {
+    if user.is_none_or(|user| user == u) {
+      *senders = senders.drain(..)
+        .filter_map(|mut s| match s.try_send(message.clone()) {
+          Ok(()) => Some(s),
+          Err(_) => None,
+        })
+        .collect();
+    }
+  }
+
+  clients.retain(|_, senders| !senders.is_empty());
+}
@@ -0,0 +1,735 @@
+use serde_derive::{Serialize, Deserialize};
+
+use utoipa::ToSchema;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use chrono::offset::Utc;
+
+use std::str::FromStr;
+
+use crate::elements::{Element, ElementStatus};
+use crate::errors::DecodeError;
+
+// iCalendar lines must not be longer than this many octets, longer
+// ones are folded
+static ICS_LINE_LIMIT: usize = 75;
+static ICS_DATE_TIME: &str = "%Y%m%dT%H%M%SZ";
+static ICS_UID_SUFFIX: &str = "@yata";
+
+static TODO_TXT_DATE: &str = "%Y-%m-%d";
+
+static CSV_HEADER: [&str; 4] =
+  ["id", "content", "status", "created"];
+
+// the formats elements are exported in
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, PartialEq,
+  Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format { #[default] Json, Csv, Ics, TodoTxt }
+
+// an element as it is exchanged with other tools. Only the content is
+// required when reading entries written by something else
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Entry {
+  #[serde(default)]
+  pub id: Option<String>,
+  pub content: String,
+  #[serde(default = "todo")]
+  pub status: ElementStatus,
+  #[serde(default)]
+  pub created: Option<DateTime<Utc>>,
+}
+
+fn todo() -> ElementStatus {
+  ElementStatus::Todo
+}
+
+impl From<Element> for Entry {
+  fn from(element: Element) -> Self {
+    Entry {
+      id: Some(String::from(element.id())),
+      content: String::from(element.content()),
+      status: element.status(),
+      created: Some(element.created()),
+    }
+  }
+}
+
+impl Format {
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      Format::Json => "application/json",
+      Format::Csv => "text/csv; charset=utf-8",
+      Format::Ics => "text/calendar; charset=utf-8",
+      Format::TodoTxt => "text/plain; charset=utf-8",
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Format::Json => "json",
+      Format::Csv => "csv",
+      Format::Ics => "ics",
+      Format::TodoTxt => "txt",
+    }
+  }
+
+  // an export is the header, every entry and the footer, so it can
+  // be streamed entry by entry
+  pub fn header(&self) -> String {
+    match self {
+      Format::Json => String::from("["),
+      Format::Csv => csv_row(|w| w.write_record(CSV_HEADER)),
+      Format::Ics => [
+        ics_line("BEGIN", "VCALENDAR"),
+        ics_line("VERSION", "2.0"),
+        ics_line("PRODID", "-//yata//yata_api//EN"),
+      ].concat(),
+      Format::TodoTxt => String::new(),
+    }
+  }
+
+  pub fn entry(&self, entry: &Entry, first: bool) -> String {
+    match self {
+      Format::Json => {
+        let json = serde_json::to_string(entry).unwrap();
+        if first { json } else { format!(",{}", json) }
+      },
+      Format::Csv => csv_row(|w| w.serialize(entry)),
+      Format::Ics => ics_todo(entry, &ics_uid(entry)),
+      Format::TodoTxt => todo_txt_line(entry),
+    }
+  }
+
+  pub fn footer(&self) -> String {
+    match self {
+      Format::Json => String::from("]"),
+      Format::Csv => String::new(),
+      Format::Ics => ics_line("END", "VCALENDAR"),
+      Format::TodoTxt => String::new(),
+    }
+  }
+
+  pub fn encode(&self, entries: &[Entry]) -> String {
+    let mut res = self.header();
+
+    for (i, entry) in entries.iter().enumerate() {
+      res.push_str(&self.entry(entry, i == 0));
+    }
+
+    res.push_str(&self.footer());
+    res
+  }
+
+  // reads an export again, failing on the first invalid entry
+  pub fn decode(&self, input: &str) -> Result<Vec<Entry>, DecodeError> {
+    let format = match self {
+      Format::Json => ImportFormat::Json,
+      Format::Csv => ImportFormat::Csv,
+      Format::Ics => ImportFormat::Ics,
+      Format::TodoTxt => ImportFormat::TodoTxt,
+    };
+
+    format.decode(input, &Columns::default())?
+      .into_iter()
+      .map(|decoded| {
+        let line = decoded.line;
+        decoded.entry.map_err(|e| DecodeError::Line(line, e))
+      })
+      .collect()
+  }
+}
+
+// the formats elements are imported from, on top of the exported ones
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, PartialEq,
+  Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat { #[default] Json, Csv, Ics, TodoTxt }
+
+// an entry read from the input or why it could not be read. For JSON,
+// line is the position of the entry in the array
+#[derive(Debug)]
+pub struct Decoded {
+  pub line: usize,
+  pub entry: Result<Entry, String>,
+}
+
+impl ImportFormat {
+  // the input as a whole is only rejected if no entries can be told
+  // apart, invalid entries are reported one by one
+  pub fn decode(&self, input: &str, columns: &Columns)
+    -> Result<Vec<Decoded>, DecodeError>
+  {
+    let decoded = match self {
+      ImportFormat::Json => decode_json(input)?,
+      ImportFormat::Csv => decode_csv(input, columns)?,
+      ImportFormat::Ics => decode_ics(input),
+      ImportFormat::TodoTxt => decode_todo_txt(input),
+    };
+
+    Ok(decoded.into_iter()
+      .map(|decoded| Decoded {
+        line: decoded.line,
+        entry: decoded.entry.and_then(|entry| {
+          if entry.content.trim().is_empty() {
+            return Err(String::from("empty content"));
+          }
+          Ok(entry)
+        }),
+      })
+      .collect())
+  }
+}
+
+// the CSV columns the fields of an entry are read from, given as e.g.
+// "content=Task,status=State". Unmapped fields keep the names of the
+// exported columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct Columns {
+  pub id: String,
+  pub content: String,
+  pub status: String,
+  pub created: String,
+}
+
+impl Default for Columns {
+  fn default() -> Self {
+    Columns {
+      id: String::from(CSV_HEADER[0]),
+      content: String::from(CSV_HEADER[1]),
+      status: String::from(CSV_HEADER[2]),
+      created: String::from(CSV_HEADER[3]),
+    }
+  }
+}
+
+impl FromStr for Columns {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut columns = Columns::default();
+
+    for mapping in s.split(',').filter(|m| !m.trim().is_empty()) {
+      let eq = mapping.find('=').ok_or(())?;
+      let column = String::from(mapping[eq + 1..].trim());
+
+      match mapping[..eq].trim() {
+        "id" => columns.id = column,
+        "content" => columns.content = column,
+        "status" => columns.status = column,
+        "created" => columns.created = column,
+        _ => return Err(()),
+      }
+    }
+
+    Ok(columns)
+  }
+}
+
+// what an import created, or would create in a dry run. Nothing is
+// created if there are errors
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ImportReport {
+  pub dry_run: bool,
+  pub created: Vec<Entry>,
+  pub errors: Vec<ImportError>,
+}
+
+// line is missing if the input could not be read at all
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ImportError {
+  pub line: Option<usize>,
+  pub error: String,
+}
+
+fn csv_row<F>(write: F) -> String
+  where F: FnOnce(&mut csv::Writer<Vec<u8>>) -> csv::Result<()>
+{
+  let mut writer = csv::WriterBuilder::new()
+    .has_headers(false)
+    .terminator(csv::Terminator::CRLF)
+    .from_writer(Vec::new());
+
+  // writing to memory only fails for entries not fitting a row, which
+  // entries always do
+  write(&mut writer).unwrap();
+
+  String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+// RFC 5545 has no status for elements in the bin, they are exported
+// as cancelled
+fn ics_status(status: ElementStatus) -> &'static str {
+  match status {
+    ElementStatus::Todo => "NEEDS-ACTION",
+    ElementStatus::Done => "COMPLETED",
+    ElementStatus::Deleted => "CANCELLED",
+  }
+}
+
+pub fn ics_uid(entry: &Entry) -> String {
+  match &entry.id {
+    Some(id) => format!("{}{}", id, ICS_UID_SUFFIX),
+    None => format!("{}{}", rand::random::<u64>(), ICS_UID_SUFFIX),
+  }
+}
+
+// a calendar holding only entry, as served to CalDAV clients
+pub fn ics_calendar(entry: &Entry, uid: &str) -> String {
+  let mut res = Format::Ics.header();
+  res.push_str(&ics_todo(entry, uid));
+  res.push_str(&Format::Ics.footer());
+  res
+}
+
+fn ics_todo(entry: &Entry, uid: &str) -> String {
+  let mut res = ics_line("BEGIN", "VTODO");
+  res.push_str(&ics_line("UID", uid));
+  res.push_str(&ics_line(
+    "DTSTAMP", &Utc::now().format(ICS_DATE_TIME).to_string(),
+  ));
+
+  if let Some(created) = entry.created {
+    res.push_str(&ics_line(
+      "CREATED", &created.format(ICS_DATE_TIME).to_string(),
+    ));
+  }
+
+  res.push_str(&ics_line("SUMMARY", &ics_escape(&entry.content)));
+  res.push_str(&ics_line("STATUS", ics_status(entry.status)));
+  res.push_str(&ics_line("END", "VTODO"));
+  res
+}
+
+fn ics_line(name: &str, value: &str) -> String {
+  ics_fold(&format!("{}:{}", name, value))
+}
+
+// TEXT values escape backslashes, semicolons, commas and newlines
+fn ics_escape(text: &str) -> String {
+  let mut res = String::with_capacity(text.len());
+
+  for c in text.chars() {
+    match c {
+      '\\' => res.push_str("\\\\"),
+      ';' => res.push_str("\\;"),
+      ',' => res.push_str("\\,"),
+      '\n' => res.push_str("\\n"),
+      '\r' => {},
+      c => res.push(c),
+    }
+  }
+
+  res
+}
+
+fn ics_unescape(text: &str) -> String {
+  let mut res = String::with_capacity(text.len());
+  let mut chars = text.chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      res.push(c);
+      continue;
+    }
+
+    match chars.next() {
+      Some('n') | Some('N') => res.push('\n'),
+      Some(c) => res.push(c),
+      None => res.push('\\'),
+    }
+  }
+
+  res
+}
+
+// splits line into lines of at most ICS_LINE_LIMIT octets, continued
+// lines start with a space. Characters are never split
+fn ics_fold(line: &str) -> String {
+  let mut res = String::with_capacity(line.len() + 8);
+  let mut len = 0;
+
+  for c in line.chars() {
+    if len + c.len_utf8() > ICS_LINE_LIMIT {
+      res.push_str("\r\n ");
+      len = 1;
+    }
+
+    res.push(c);
+    len += c.len_utf8();
+  }
+
+  res.push_str("\r\n");
+  res
+}
+
+// joins folded lines again, tolerating bare newlines as line breaks.
+// Every line comes with the number it starts at
+fn ics_unfold(input: &str) -> Vec<(usize, String)> {
+  let mut lines: Vec<(usize, String)> = Vec::new();
+
+  for (number, line) in input.split('\n').enumerate() {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let continued = line.starts_with(' ') || line.starts_with('\t');
+
+    match lines.last_mut() {
+      Some((_, last)) if continued => last.push_str(&line[1..]),
+      _ => lines.push((number + 1, String::from(line))),
+    }
+  }
+
+  lines
+}
+
+// the line a VTODO started at, its entry and the first error in it
+// with the line of the error
+type OpenTodo = (usize, Entry, Option<(usize, String)>);
+
+// reads the VTODO components of a calendar, everything else is
+// skipped. Properties of components nested in a VTODO, like the
+// summary of an alarm, do not belong to the entry
+fn decode_ics(input: &str) -> Vec<Decoded> {
+  let mut res: Vec<Decoded> = Vec::new();
+
+  let mut todo: Option<OpenTodo> = None;
+  let mut nested = 0;
+
+  for (number, line) in ics_unfold(input) {
+    if line.is_empty() {
+      continue;
+    }
+
+    let colon = match line.find(':') {
+      Some(colon) => colon,
+      None => {
+        res.push(Decoded {
+          line: number,
+          entry: Err(String::from("missing colon")),
+        });
+        continue;
+      },
+    };
+
+    // parameters like in DUE;VALUE=DATE are not needed
+    let name = line[..colon].split(';').next().unwrap_or("")
+      .to_ascii_uppercase();
+    let value = &line[colon + 1..];
+
+    let (entry, error) = match todo.as_mut() {
+      Some((_, entry, error)) => (entry, error),
+      None => {
+        if name == "BEGIN" && value == "VTODO" {
+          todo = Some((number, Entry {
+            id: None,
+            content: String::new(),
+            status: ElementStatus::Todo,
+            created: None,
+          }, None));
+        }
+
+        continue;
+      },
+    };
+
+    match name.as_str() {
+      "BEGIN" => nested += 1,
+      "END" if nested > 0 => nested -= 1,
+      "END" => {
+        let (start, entry, error) = todo.take().unwrap();
+
+        res.push(match error {
+          Some((line, error)) => Decoded {
+            line,
+            entry: Err(error),
+          },
+          None => Decoded { line: start, entry: Ok(entry) },
+        });
+      },
+      _ if nested > 0 => {},
+      "UID" => entry.id = Some(String::from(
+        value.strip_suffix(ICS_UID_SUFFIX).unwrap_or(value)
+      )),
+      "SUMMARY" => entry.content = ics_unescape(value),
+      "STATUS" => entry.status = match value {
+        "COMPLETED" => ElementStatus::Done,
+        "CANCELLED" => ElementStatus::Deleted,
+        _ => ElementStatus::Todo,
+      },
+      "CREATED" => match parse_ics_date_time(value) {
+        Some(created) => entry.created = Some(created),
+        None => if error.is_none() {
+          *error = Some((
+            number, format!("invalid date-time {}", value),
+          ));
+        },
+      },
+      _ => {},
+    }
+  }
+
+  if let Some((start, _, _)) = todo {
+    res.push(Decoded {
+      line: start,
+      entry: Err(String::from("unterminated VTODO")),
+    });
+  }
+
+  res
+}
+
+fn parse_ics_date_time(value: &str) -> Option<DateTime<Utc>> {
+  NaiveDateTime::parse_from_str(value, ICS_DATE_TIME)
+    .map(|created| DateTime::from_utc(created, Utc))
+    .ok()
+}
+
+fn decode_json(input: &str) -> Result<Vec<Decoded>, DecodeError> {
+  let items: Vec<serde_json::Value> = serde_json::from_str(input)?;
+
+  Ok(items.into_iter().enumerate()
+    .map(|(i, item)| Decoded {
+      line: i + 1,
+      entry: serde_json::from_value(item).map_err(|e| e.to_string()),
+    })
+    .collect())
+}
+
+fn decode_csv(input: &str, columns: &Columns)
+  -> Result<Vec<Decoded>, DecodeError>
+{
+  let mut reader = csv::Reader::from_reader(input.as_bytes());
+  let headers = reader.headers()?.clone();
+
+  let index = |name: &str| headers.iter()
+    .position(|h| h.trim().eq_ignore_ascii_case(name));
+
+  let content = index(&columns.content).ok_or_else(|| {
+    DecodeError::MissingColumn(columns.content.clone())
+  })?;
+
+  let indices = CsvIndices {
+    id: index(&columns.id),
+    content,
+    status: index(&columns.status),
+    created: index(&columns.created),
+  };
+
+  Ok(reader.records()
+    .map(|record| match record {
+      Ok(record) => Decoded {
+        line: record.position().map_or(0, |p| p.line() as usize),
+        entry: csv_entry(&record, &indices),
+      },
+      Err(e) => Decoded {
+        line: e.position().map_or(0, |p| p.line() as usize),
+        entry: Err(e.to_string()),
+      },
+    })
+    .collect())
+}
+
+struct CsvIndices {
+  id: Option<usize>,
+  content: usize,
+  status: Option<usize>,
+  created: Option<usize>,
+}
+
+fn csv_entry(record: &csv::StringRecord, indices: &CsvIndices)
+  -> Result<Entry, String>
+{
+  let field = |i: Option<usize>| i
+    .and_then(|i| record.get(i))
+    .filter(|f| !f.trim().is_empty());
+
+  let status = match field(indices.status) {
+    Some(status) => parse_status(status)?,
+    None => ElementStatus::Todo,
+  };
+
+  Ok(Entry {
+    id: field(indices.id).map(String::from),
+    content: String::from(field(Some(indices.content)).unwrap_or("")),
+    status,
+    created: field(indices.created).map(parse_date).transpose()?,
+  })
+}
+
+// the statuses of the export plus the ones other tools commonly use
+fn parse_status(status: &str) -> Result<ElementStatus, String> {
+  match status.trim().to_ascii_lowercase().as_str() {
+    "todo" | "open" | "needs-action" => Ok(ElementStatus::Todo),
+    "done" | "completed" | "x" => Ok(ElementStatus::Done),
+    "deleted" | "cancelled" => Ok(ElementStatus::Deleted),
+    _ => Err(format!("unknown status {}", status)),
+  }
+}
+
+// RFC 3339 date-times or plain dates, taken as midnight UTC
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+  let date = date.trim();
+
+  DateTime::parse_from_rfc3339(date)
+    .map(|date| date.with_timezone(&Utc))
+    .or_else(|_| NaiveDate::parse_from_str(date, "%Y-%m-%d")
+      .map(|date| DateTime::from_utc(date.and_hms(0, 0, 0), Utc)))
+    .map_err(|_| format!("invalid date {}", date))
+}
+
+// one task per line, as in
+// "x 2020-09-13 2020-09-01 (A) call mom +family @phone". Elements have
+// no priority, so it becomes a "(A) " marker the content starts with,
+// also when given as pri:A like completed tasks do. Projects and
+// contexts are words of the content already. The completion date is
+// not kept
+fn decode_todo_txt(input: &str) -> Vec<Decoded> {
+  input.lines().enumerate()
+    .filter(|(_, line)| !line.trim().is_empty())
+    .map(|(i, line)| Decoded {
+      line: i + 1,
+      entry: Ok(todo_txt_entry(line.trim())),
+    })
+    .collect()
+}
+
+fn todo_txt_entry(line: &str) -> Entry {
+  let mut rest = line;
+  let mut status = ElementStatus::Todo;
+
+  if let Some(completed) = rest.strip_prefix("x ") {
+    status = ElementStatus::Done;
+    rest = completed.trim_start();
+
+    // the completion date is not stored. A single date is taken for
+    // the creation date, as todo_txt_line writes it
+    if let Some((_, after)) = date_prefix(rest) {
+      if date_prefix(after).is_some() {
+        rest = after;
+      }
+    }
+  }
+
+  let mut priority = None;
+
+  if let Some((before_date, after)) = priority_prefix(rest) {
+    priority = Some(before_date);
+    rest = after;
+  }
+
+  let mut created = None;
+
+  if let Some((date, after)) = date_prefix(rest) {
+    created = Some(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    rest = after;
+  }
+
+  // some tools write the priority after the creation date
+  if let Some((after_date, after)) = priority_prefix(rest) {
+    priority = priority.or(Some(after_date));
+    rest = after;
+  }
+
+  let mut content = String::from(rest);
+  let tag = rest.split(' ').find(|word| pri_tag(word).is_some());
+
+  if let Some(tag) = tag {
+    priority = priority.or_else(|| pri_tag(tag));
+    content = rest.split(' ')
+      .filter(|word| pri_tag(word).is_none())
+      .collect::<Vec<&str>>()
+      .join(" ");
+  }
+
+  if let Some(priority) = priority {
+    content = format!("{} {}", priority, content);
+  }
+
+  Entry {
+    id: None,
+    content,
+    status,
+    created,
+  }
+}
+
+// the line decode_todo_txt reads entry from again. The bin has no
+// place in todo.txt, so deleted entries are left out, and completed
+// ones keep their priority as pri:A
+fn todo_txt_line(entry: &Entry) -> String {
+  if entry.status == ElementStatus::Deleted {
+    return String::new();
+  }
+
+  let content = entry.content.replace('\r', "").replace('\n', " ");
+
+  let (priority, rest) = match priority_prefix(&content) {
+    Some((priority, rest)) => (Some(priority), rest),
+    None => (None, content.as_str()),
+  };
+
+  let created = entry.created
+    .map(|created| created.format(TODO_TXT_DATE).to_string());
+
+  let mut words: Vec<String> = Vec::new();
+
+  // the completion date is not known, the creation date takes its
+  // place
+  if entry.status == ElementStatus::Done {
+    words.push(String::from("x"));
+  } else if let Some(priority) = priority {
+    words.push(String::from(priority));
+  }
+
+  words.extend(created);
+  words.push(String::from(rest));
+
+  match priority {
+    Some(priority) if entry.status == ElementStatus::Done =>
+      words.push(format!("pri:{}", &priority[1..2])),
+    _ => {},
+  }
+
+  format!("{}\n", words.join(" "))
+}
+
+fn date_prefix(s: &str) -> Option<(NaiveDate, &str)> {
+  let (date, rest) = split_word(s);
+  let date = NaiveDate::parse_from_str(date, TODO_TXT_DATE).ok()?;
+  Some((date, rest))
+}
+
+// "(A)" up to "(Z)"
+fn priority_prefix(s: &str) -> Option<(&str, &str)> {
+  let (priority, rest) = split_word(s);
+  let bytes = priority.as_bytes();
+
+  let valid = bytes.len() == 3 && bytes[0] == b'('
+    && bytes[1].is_ascii_uppercase() && bytes[2] == b')';
+
+  if valid { Some((priority, rest)) } else { None }
+}
+
+// the "(A)" of "pri:A"
+fn pri_tag(word: &str) -> Option<&'static str> {
+  static PRIORITIES: [&str; 26] = [
+    "(A)", "(B)", "(C)", "(D)", "(E)", "(F)", "(G)", "(H)", "(I)",
+    "(J)", "(K)", "(L)", "(M)", "(N)", "(O)", "(P)", "(Q)", "(R)",
+    "(S)", "(T)", "(U)", "(V)", "(W)", "(X)", "(Y)", "(Z)",
+  ];
+
+  let letter = word.strip_prefix("pri:")?.as_bytes();
+
+  match letter {
+    [l] if l.is_ascii_uppercase() =>
+      Some(PRIORITIES[(l - b'A') as usize]),
+    _ => None,
+  }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+  match s.find(' ') {
+    Some(i) => (&s[..i], s[i + 1..].trim_start()),
+    None => (s, ""),
+  }
+}
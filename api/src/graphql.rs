@@ -0,0 +1,569 @@
+use actix_web::{get, post, web, Error as ActixError, HttpRequest,
+  HttpResponse};
+use actix_web::dev::RequestHead;
+
+use async_graphql::{Context, Data, Error, ID, InputObject, Object,
+  Result, Schema, SimpleObject, Subscription};
+use async_graphql_actix_web::{Request, Response, WSSubscription};
+
+use mongodb::bson::{Document, doc, to_bson};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::FindOptions;
+
+use futures::stream::{Stream, StreamExt};
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+
+use tracing::error;
+
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+use crate::elements::{Element, ElementStatus};
+use crate::events::{ElementChange, EventBus, EventKind};
+use crate::inputs::{SingleContent, UserPath};
+use crate::middlewares::AuthenticatedUser;
+use crate::routes::{change_status, conditional_element,
+  delete_matching, insert_todo, purge_bin};
+use crate::shares::Permission;
+use crate::store::{ReadAccess, Store};
+use crate::tokens::Scope;
+use crate::versions::version_match;
+
+static PAGE_SIZE: i32 = 50;
+static MAX_PAGE_SIZE: i32 = 200;
+
+pub type YataSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn schema(store: Store, events: EventBus) -> YataSchema {
+  Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+    .data(store)
+    .data(events)
+    .finish()
+}
+
+// the schema acts on the user of the path, which the bearer auth in
+// front of it made sure the request is authenticated as. Access
+// tokens with only the read scope can send queries with GET
+#[utoipa::path(
+  post, path = "/{user}/graphql", tag = "graphql",
+  params(UserPath),
+  request_body(content = Object, description = "a GraphQL request"),
+  responses((status = 200, description = "the GraphQL response")),
+)]
+#[post("/{user}/graphql")]
+pub async fn graphql(
+  schema: web::Data<YataSchema>,
+  user: web::ReqData<AuthenticatedUser>,
+  req: Request) -> Response
+{
+  schema.execute(req.into_inner().data(user.into_inner())).await.into()
+}
+
+// subscriptions, over websockets. Browsers can not send the
+// Authorization header with them and pass a ticket from POST
+// /me/tickets as the ticket query parameter instead. A ticket opens a
+// single connection, reconnecting needs a new one
+#[get("/{user}/graphql", guard = "websocket")]
+pub async fn graphql_subscriptions(
+  schema: web::Data<YataSchema>,
+  user: web::ReqData<AuthenticatedUser>,
+  req: HttpRequest,
+  payload: web::Payload) -> Result<HttpResponse, ActixError>
+{
+  let user = user.into_inner();
+
+  WSSubscription::start_with_initializer(
+    Schema::clone(&*schema),
+    &req,
+    payload,
+    move |_| async move {
+      let mut data = Data::default();
+      data.insert(user);
+      Ok(data)
+    },
+  )
+}
+
+#[utoipa::path(
+  get, path = "/{user}/graphql", tag = "graphql",
+  params(
+    UserPath,
+    ("query" = String, Query, description = "the GraphQL document"),
+    ("variables" = Option<String>, Query,
+      description = "the variables as JSON object"),
+    ("operationName" = Option<String>, Query,
+      description = "the operation of the document to run"),
+  ),
+  responses((status = 200, description = "the GraphQL response")),
+)]
+#[get("/{user}/graphql")]
+pub async fn graphql_query(
+  schema: web::Data<YataSchema>,
+  user: web::ReqData<AuthenticatedUser>,
+  req: Request) -> Response
+{
+  schema.execute(req.into_inner().data(user.into_inner())).await.into()
+}
+
+fn websocket(head: &RequestHead) -> bool {
+  head.headers().get("Upgrade")
+    .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+// an element as seen by the user
+pub struct GraphElement(pub Element);
+
+#[Object(name = "Element")]
+impl GraphElement {
+  async fn id(&self) -> ID {
+    ID::from(self.0.id())
+  }
+
+  async fn content(&self) -> &str {
+    self.0.content()
+  }
+
+  async fn status(&self) -> ElementStatus {
+    self.0.status()
+  }
+
+  async fn created(&self) -> DateTime<Utc> {
+    self.0.created()
+  }
+
+  // changes with every update, see ifVersion of the mutations
+  async fn version(&self) -> i64 {
+    self.0.version()
+  }
+
+  // null for elements of the user
+  async fn owner(&self) -> Option<&str> {
+    self.0.shared().map(|(owner, _)| owner)
+  }
+
+  async fn permission(&self) -> Option<Permission> {
+    self.0.shared().map(|(_, permission)| permission)
+  }
+}
+
+pub struct GraphChange(ElementChange);
+
+#[Object(name = "ElementChange")]
+impl GraphChange {
+  async fn kind(&self) -> EventKind {
+    self.0.kind
+  }
+
+  async fn element(&self) -> GraphElement {
+    GraphElement(self.0.element.clone())
+  }
+}
+
+#[derive(InputObject, Default)]
+pub struct ElementFilter {
+  pub status: Option<ElementStatus>,
+  // matches the content, ignoring case
+  pub search: Option<String>,
+}
+
+impl ElementFilter {
+  pub fn clauses(&self) -> Result<Vec<Document>> {
+    let mut res: Vec<Document> = Vec::new();
+
+    if let Some(status) = self.status {
+      res.push(doc!{"status": to_bson(&status).map_err(internal)?});
+    }
+
+    if let Some(search) = &self.search {
+      res.push(doc!{
+        "content": {"$regex": regex_escape(search), "$options": "i"},
+      });
+    }
+
+    Ok(res)
+  }
+}
+
+// search is matched literally
+fn regex_escape(text: &str) -> String {
+  let mut res = String::with_capacity(text.len());
+
+  for c in text.chars() {
+    if "\\^$.|?*+()[]{}".contains(c) {
+      res.push('\\');
+    }
+
+    res.push(c);
+  }
+
+  res
+}
+
+// elements ordered by id, so by creation. The id of the last element
+// is passed as after to get the next page
+#[derive(SimpleObject)]
+pub struct ElementPage {
+  pub elements: Vec<GraphElement>,
+  pub end_cursor: Option<ID>,
+  pub has_next_page: bool,
+  pub total_count: i32,
+}
+
+pub fn page_size(first: Option<i32>) -> Result<i64> {
+  let first = first.unwrap_or(PAGE_SIZE);
+
+  if first < 0 || first > MAX_PAGE_SIZE {
+    return Err(Error::new(
+      format!("first has to be between 0 and {}", MAX_PAGE_SIZE)
+    ));
+  }
+
+  Ok(first as i64)
+}
+
+// the query of the elements readable matches with filter, without
+// after it is the one of the total count
+pub fn page_query(
+  readable: Document,
+  filter: &ElementFilter,
+  after: Option<&ID>) -> Result<Document>
+{
+  let mut clauses = vec![readable];
+  clauses.extend(filter.clauses()?);
+
+  if let Some(after) = after {
+    clauses.push(doc!{"_id": {"$gt": object_id(after)?}});
+  }
+
+  Ok(doc!{"$and": clauses})
+}
+
+// the page is read with one element more than asked for, which tells
+// if there is a next one
+pub async fn page(
+  store: &Store,
+  access: &ReadAccess,
+  owner: Option<&str>,
+  filter: Option<ElementFilter>,
+  first: Option<i32>,
+  after: Option<ID>) -> Result<ElementPage>
+{
+  let first = page_size(first)?;
+  let filter = filter.unwrap_or_default();
+  let readable = access.filter(owner);
+
+  let total = store.elements
+    .count_documents(page_query(readable.clone(), &filter, None)?, None)
+    .await
+    .map_err(internal)?;
+
+  let options = FindOptions::builder()
+    .sort(doc!{"_id": 1})
+    .limit(first + 1)
+    .build();
+
+  let query = page_query(readable, &filter, after.as_ref())?;
+  let mut cursor = store.elements.find(query, options)
+    .await
+    .map_err(internal)?;
+
+  let mut elements: Vec<GraphElement> = Vec::new();
+
+  while let Some(result) = cursor.next().await {
+    let element = access.element(result.map_err(internal)?)
+      .map_err(internal)?;
+    elements.push(GraphElement(element));
+  }
+
+  let has_next_page = elements.len() as i64 > first;
+  elements.truncate(first as usize);
+
+  Ok(ElementPage {
+    end_cursor: elements.last().map(|e| ID::from(e.0.id())),
+    has_next_page,
+    total_count: total as i32,
+    elements,
+  })
+}
+
+#[derive(SimpleObject, Default, Debug, PartialEq)]
+pub struct Stats {
+  pub todo: i32,
+  pub done: i32,
+  pub deleted: i32,
+  pub total: i32,
+}
+
+impl Stats {
+  pub async fn of(store: &Store, readable: Document) -> Result<Stats> {
+    let count = |status: ElementStatus| {
+      let readable = readable.clone();
+
+      async move {
+        let status = to_bson(&status).map_err(internal)?;
+        let filter = doc!{"$and": [readable, {"status": status}]};
+
+        store.elements.count_documents(filter, None)
+          .await
+          .map(|count| count as i32)
+          .map_err(internal)
+      }
+    };
+
+    let todo = count(ElementStatus::Todo).await?;
+    let done = count(ElementStatus::Done).await?;
+    let deleted = count(ElementStatus::Deleted).await?;
+
+    Ok(Stats {
+      todo,
+      done,
+      deleted,
+      total: todo + done + deleted,
+    })
+  }
+}
+
+// the elements of owner the user can read
+pub struct List {
+  owner: String,
+}
+
+#[Object]
+impl List {
+  async fn owner(&self) -> &str {
+    &self.owner
+  }
+
+  async fn elements(
+    &self,
+    ctx: &Context<'_>,
+    filter: Option<ElementFilter>,
+    first: Option<i32>,
+    after: Option<ID>) -> Result<ElementPage>
+  {
+    let store = ctx.data::<Store>()?;
+    let access = read_access(ctx).await?;
+
+    page(store, &access, Some(&self.owner), filter, first, after).await
+  }
+
+  async fn stats(&self, ctx: &Context<'_>) -> Result<Stats> {
+    let store = ctx.data::<Store>()?;
+    let access = read_access(ctx).await?;
+
+    Stats::of(store, access.filter(Some(&self.owner))).await
+  }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+  // every element the user can read, optionally only those of owner
+  async fn elements(
+    &self,
+    ctx: &Context<'_>,
+    owner: Option<String>,
+    filter: Option<ElementFilter>,
+    first: Option<i32>,
+    after: Option<ID>) -> Result<ElementPage>
+  {
+    let store = ctx.data::<Store>()?;
+    let access = read_access(ctx).await?;
+
+    page(store, &access, owner.as_deref(), filter, first, after).await
+  }
+
+  async fn element(&self, ctx: &Context<'_>, id: ID)
+    -> Result<Option<GraphElement>>
+  {
+    let store = ctx.data::<Store>()?;
+    let access = read_access(ctx).await?;
+
+    let id = match ObjectId::with_string(id.as_str()) {
+      Ok(id) => id,
+      Err(_) => return Ok(None),
+    };
+
+    let filter = doc!{"$and": [access.filter(None), {"_id": id}]};
+
+    let doc = store.elements.find_one(filter, None)
+      .await
+      .map_err(internal)?;
+
+    match doc {
+      Some(doc) =>
+        Ok(Some(GraphElement(access.element(doc).map_err(internal)?))),
+      None => Ok(None),
+    }
+  }
+
+  // the list of the user first, then the lists shared with them
+  async fn lists(&self, ctx: &Context<'_>) -> Result<Vec<List>> {
+    let access = read_access(ctx).await?;
+
+    Ok(access.owners().into_iter()
+      .map(|owner| List { owner })
+      .collect())
+  }
+
+  async fn stats(&self, ctx: &Context<'_>) -> Result<Stats> {
+    let store = ctx.data::<Store>()?;
+    let access = read_access(ctx).await?;
+
+    Stats::of(store, access.filter(None)).await
+  }
+}
+
+// the mutations mirror the REST routes. ifVersion makes a change
+// conditional like If-Match does
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+  async fn add_todo(&self, ctx: &Context<'_>, content: String)
+    -> Result<GraphElement>
+  {
+    let user = writer(ctx)?;
+    let store = ctx.data::<Store>()?;
+    let events = ctx.data::<EventBus>()?;
+
+    let todo = SingleContent { content };
+    let inserted = insert_todo(store, events, &user.username, todo)
+      .await;
+
+    graph_element(inserted, user)
+  }
+
+  async fn set_status(
+    &self,
+    ctx: &Context<'_>,
+    id: ID,
+    status: ElementStatus,
+    if_version: Option<i64>) -> Result<GraphElement>
+  {
+    let user = writer(ctx)?;
+    let store = ctx.data::<Store>()?;
+    let events = ctx.data::<EventBus>()?;
+
+    let id = object_id(&id)?;
+    let mut filter = store.writable_filter(&user.username, id)
+      .await
+      .map_err(internal)?;
+
+    let if_match = if_version.map(version_match);
+
+    let current = conditional_element(
+      if_match.as_ref(), store, &mut filter,
+    ).await.map_err(refused)?;
+
+    let updated = change_status(
+      store, events, &user.username, &current, filter, status,
+    ).await.map_err(refused)?;
+
+    graph_element(updated, user)
+  }
+
+  async fn delete_element(
+    &self,
+    ctx: &Context<'_>,
+    id: ID,
+    if_version: Option<i64>) -> Result<ID>
+  {
+    let user = writer(ctx)?;
+    let store = ctx.data::<Store>()?;
+    let events = ctx.data::<EventBus>()?;
+
+    let filter = store.writable_filter(&user.username, object_id(&id)?)
+      .await
+      .map_err(internal)?;
+
+    let if_match = if_version.map(version_match);
+
+    delete_matching(
+      if_match.as_ref(), store, events, &user.username, filter,
+    ).await.map_err(refused)?;
+
+    Ok(id)
+  }
+
+  // returns how many elements were in the bin
+  async fn empty_bin(&self, ctx: &Context<'_>) -> Result<i32> {
+    let user = writer(ctx)?;
+    let store = ctx.data::<Store>()?;
+    let events = ctx.data::<EventBus>()?;
+
+    Ok(purge_bin(store, events, &user.username).await as i32)
+  }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+  // changes of every element the user can read. Like the server-sent
+  // events, they only cover writes handled by the same instance
+  async fn element_changes(&self, ctx: &Context<'_>)
+    -> Result<impl Stream<Item = GraphChange>>
+  {
+    let user = user(ctx)?;
+    let events = ctx.data::<EventBus>()?;
+
+    Ok(events.listen(&user.username).map(GraphChange))
+  }
+}
+
+fn user<'a>(ctx: &Context<'a>) -> Result<&'a AuthenticatedUser> {
+  ctx.data::<AuthenticatedUser>()
+}
+
+// mutations can also be sent with GET, so the scope of access tokens
+// is checked again
+pub fn writer<'a>(ctx: &Context<'a>) -> Result<&'a AuthenticatedUser> {
+  let user = user(ctx)?;
+
+  if !user.has_scope(Scope::Write) {
+    return Err(Error::new("the access token does not allow writing"));
+  }
+
+  Ok(user)
+}
+
+async fn read_access(ctx: &Context<'_>) -> Result<ReadAccess> {
+  let store = ctx.data::<Store>()?;
+  let user = user(ctx)?;
+
+  store.read_access(&user.username).await.map_err(internal)
+}
+
+// elements of others can only be changed by editors
+fn graph_element(doc: Document, user: &AuthenticatedUser)
+  -> Result<GraphElement>
+{
+  let owner = String::from(doc.get_str("user").map_err(internal)?);
+
+  let mut element = Element::try_from(doc).map_err(internal)?;
+
+  if owner != user.username {
+    element.mark_shared(owner, Permission::Editor);
+  }
+
+  Ok(GraphElement(element))
+}
+
+fn object_id(id: &ID) -> Result<ObjectId> {
+  ObjectId::with_string(id.as_str())
+    .map_err(|_| Error::new(format!("invalid id {}", id.as_str())))
+}
+
+// the REST response a change was refused with, as an error
+fn refused(res: HttpResponse) -> Error {
+  Error::new(res.status().canonical_reason().unwrap_or("refused"))
+}
+
+fn internal<E: Debug>(e: E) -> Error {
+  error!(error = ?e, "could not resolve GraphQL request");
+  Error::new("internal error")
+}
@@ -0,0 +1,161 @@
+use serde_derive::Serialize;
+
+use utoipa::ToSchema;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::bson::from_bson;
+use mongodb::bson::oid::ObjectId;
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+
+use std::convert::TryFrom;
+
+use crate::elements::Element;
+use crate::errors::ParseDocumentError;
+use crate::events::EventKind;
+
+// the actor of the events users changed before they were deleted,
+// which no username can be
+pub static DELETED_ACTOR: &str = "";
+
+// the events written by a single request share a change, so a request
+// purging many elements is undone as a whole
+#[derive(Debug, Clone)]
+pub struct Change {
+  pub id: ObjectId,
+  pub actor: String,
+  pub reverts: Option<ObjectId>,
+}
+
+impl Change {
+  pub fn new(actor: &str) -> Change {
+    Change {
+      id: ObjectId::new(),
+      actor: String::from(actor),
+      reverts: None,
+    }
+  }
+
+  pub fn reverting(actor: &str, change: ObjectId) -> Change {
+    Change {
+      reverts: Some(change),
+      ..Change::new(actor)
+    }
+  }
+}
+
+// an entry of the audit log, with the element before and after it was
+// changed by actor
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ElementEvent {
+  id: String,
+  change: String,
+  element: String,
+  owner: String,
+  actor: String,
+  action: EventKind,
+  at: DateTime<Utc>,
+  before: Option<Element>,
+  after: Option<Element>,
+  undone: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  reverts: Option<String>,
+}
+
+impl ElementEvent {
+  pub fn owner(&self) -> &str {
+    &self.owner
+  }
+}
+
+fn element(doc: &Document, key: &str)
+  -> Result<Option<Element>, ParseDocumentError>
+{
+  match doc.get(key) {
+    Some(Bson::Document(element)) =>
+      Ok(Some(Element::try_from(element.clone())?)),
+    _ => Ok(None),
+  }
+}
+
+impl TryFrom<Document> for ElementEvent {
+  type Error = ParseDocumentError;
+
+  fn try_from(doc: Document) -> Result<Self, Self::Error> {
+    let id = doc.get_object_id("_id")?.to_hex();
+    let change = doc.get_object_id("change")?.to_hex();
+    let element_id = doc.get_object_id("element")?.to_hex();
+    let owner = String::from(doc.get_str("owner")?);
+    let actor = String::from(doc.get_str("actor")?);
+    let action: EventKind = from_bson(
+      doc.get("action").ok_or(ParseDocumentError::NotPresent)?.clone(),
+    )?;
+    let at = *doc.get_datetime("at")?;
+    let undone = doc.get_bool("undone").unwrap_or(false);
+
+    let reverts = match doc.get("reverts") {
+      Some(Bson::ObjectId(id)) => Some(id.to_hex()),
+      _ => None,
+    };
+
+    Ok(ElementEvent{
+      id,
+      change,
+      element: element_id,
+      owner,
+      actor,
+      action,
+      at,
+      before: element(&doc, "before")?,
+      after: element(&doc, "after")?,
+      undone,
+      reverts,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use mongodb::bson::{doc, to_bson};
+
+  use crate::elements::ElementStatus;
+
+  #[test]
+  fn test_element_event_from_mongodb_document()
+    -> Result<(), ParseDocumentError>
+  {
+    let before = doc! {
+      "_id": ObjectId::new(),
+      "user": String::from("some owner"),
+      "content": String::from("some content"),
+      "status": to_bson(&ElementStatus::Deleted).unwrap(),
+      "created": Utc::now(),
+      "version": 2i64,
+    };
+
+    let doc = doc! {
+      "_id": ObjectId::new(),
+      "change": ObjectId::new(),
+      "element": before.get_object_id("_id").unwrap().clone(),
+      "owner": String::from("some owner"),
+      "actor": String::from("some member"),
+      "action": to_bson(&EventKind::Deleted).unwrap(),
+      "at": Utc::now(),
+      "before": before,
+      "after": Bson::Null,
+      "undone": false,
+    };
+
+    let event = serde_json::to_value(ElementEvent::try_from(doc)?)
+      .unwrap();
+
+    assert_eq!(event["action"], "deleted");
+    assert_eq!(event["before"]["version"], 2);
+    assert!(event["after"].is_null());
+    assert!(event.get("reverts").is_none());
+    Ok(())
+  }
+}
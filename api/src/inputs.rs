@@ -1,13 +1,86 @@
 use serde_derive::Deserialize;
 
+use utoipa::{IntoParams, ToSchema};
+
 use crate::elements::ElementStatus;
+use crate::formats::{Format, ImportFormat};
+use crate::shares::Permission;
+use crate::tokens::Scope;
+
+// the path parameters of the routes, for the API documentation. The
+// user is the owner of the list a route acts on
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct UserPath {
+  pub user: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct ElementPath {
+  pub user: String,
+  pub id: String,
+}
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct SharePath {
+  pub user: String,
+  pub id: String,
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct SingleContent {
   pub content: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct SingleStatus {
   pub status: ElementStatus,
 }
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesQuery {
+  // the token of the last sync, everything is returned without it
+  pub since: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ActivityQuery {
+  pub limit: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+  #[serde(default)]
+  pub format: Format,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+  #[serde(default)]
+  pub format: ImportFormat,
+  #[serde(default)]
+  pub dry_run: bool,
+  // CSV columns to read the fields from, e.g. content=Task,status=State
+  pub columns: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ShareInvitation {
+  pub member: String,
+  pub permission: Permission,
+  pub element: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewAccessToken {
+  pub name: String,
+  pub scopes: Vec<Scope>,
+  // 1 up to TOKEN_MAX_DAYS, tokens without it do not expire
+  pub expires_in_days: Option<i64>,
+}
@@ -1,15 +1,30 @@
-#![feature(try_trait)]
-
-use mongodb::bson::{Document, doc, to_bson};
+use mongodb::bson::{Bson, Document, doc, to_bson};
 
+use chrono::Duration;
 use chrono::offset::Utc;
 
+pub mod api_version;
 pub mod apps;
+pub mod caldav;
+pub mod config;
 pub mod errors;
 pub mod inputs;
 pub mod elements;
+pub mod events;
+pub mod formats;
+pub mod graphql;
+pub mod history;
+pub mod logging;
+pub mod metrics;
+pub mod shares;
+pub mod store;
+pub mod tokens;
+pub mod versions;
 pub mod routes;
 pub mod middlewares;
+pub mod openapi;
+pub mod rate_limit;
+pub mod retry;
 
 pub fn to_mongodb_entry(c: crate::inputs::SingleContent, user: String)
   -> Result<Document, crate::errors::ParseDocumentError>
@@ -19,6 +34,47 @@ pub fn to_mongodb_entry(c: crate::inputs::SingleContent, user: String)
     "content": c.content,
     "status": to_bson(&crate::elements::ElementStatus::Todo)?,
     "created": Utc::now(),
+    "version": 1i64,
+  })
+}
+
+// imported entries keep their status and creation time. Their ids
+// are not taken over, so importing twice creates duplicates
+pub fn to_mongodb_import_entry(e: crate::formats::Entry, user: String)
+  -> Result<Document, crate::errors::ParseDocumentError>
+{
+  let content = crate::inputs::SingleContent{content: e.content};
+
+  let mut doc = to_mongodb_entry(content, user)?;
+  doc.insert("status", to_bson(&e.status)?);
+
+  if let Some(created) = e.created {
+    doc.insert("created", created);
+  }
+
+  Ok(doc)
+}
+
+pub fn to_mongodb_token_entry(
+  t: crate::inputs::NewAccessToken,
+  user: String,
+  hash: String,
+) -> Result<Document, crate::errors::ParseDocumentError> {
+  let now = Utc::now();
+
+  let expires = match t.expires_in_days {
+    Some(days) => Bson::DateTime(now + Duration::days(days)),
+    None => Bson::Null,
+  };
+
+  Ok(doc! {
+    "user": user,
+    "name": t.name,
+    "hash": hash,
+    "scopes": to_bson(&t.scopes)?,
+    "created": now,
+    "expires": expires,
+    "last_used": Bson::Null,
   })
 }
 
@@ -26,16 +82,53 @@ pub fn to_mongodb_entry(c: crate::inputs::SingleContent, user: String)
 mod tests {
   use super::*;
 
+  use actix_web::{test, web, App, HttpResponse};
+  use actix_web::dev::{Service, ServiceResponse};
+  use actix_web::web::Bytes;
+  use actix_web::http::{Method, StatusCode};
+  use actix_web::http::header::{EntityTag, IfMatch};
+
+  use actix_web_httpauth::middleware::HttpAuthentication;
+
+  use chrono::TimeZone;
   use chrono::offset::Utc;
 
+  use futures::StreamExt;
+  use futures::future::ready;
+
   use mongodb::bson::oid::ObjectId;
 
+  use crate::api_version::{API_PREFIX, LegacyRoutes};
+  use crate::caldav::{Object, PropFind, PropName, Report, Resource};
+  use crate::caldav::{parse_propfind, parse_report};
   use crate::errors::ParseDocumentError;
-  use crate::to_mongodb_entry;
+  use crate::{to_mongodb_entry, to_mongodb_import_entry};
+  use crate::config::Config;
   use crate::elements::{Element, ElementStatus};
+  use crate::events::EventBus;
+  use crate::formats::{Columns, Entry, Format, ImportFormat};
+  use crate::graphql::{ElementFilter, MutationRoot, QueryRoot,
+    SubscriptionRoot, page_query, page_size};
   use crate::inputs::SingleContent;
+  use crate::middlewares::{AuthenticatedUser, Credential,
+    ticket_header};
+  use crate::openapi::ApiDoc;
+  use crate::metrics::ElementMetrics;
+  use crate::rate_limit::RateLimits;
+  use crate::retry::backoff;
+  use crate::shares::Permission;
+  use crate::tokens::Scope;
+  use crate::versions::{if_match, matches};
+
+  use utoipa::OpenApi;
 
+  use yata_common::metrics::Metrics;
+
+  use std::collections::BTreeSet;
   use std::convert::TryFrom;
+  use std::net::SocketAddr;
+  use std::sync::Arc;
+  use std::time::Duration;
 
   #[test]
   fn test_to_mongodb_entry() -> Result<(), ParseDocumentError> {
@@ -47,17 +140,755 @@ mod tests {
   }
 
   #[test]
-  fn test_element_from_mongodb_document()
+  fn test_config_defaults_and_overrides() {
+    let file = toml::from_str(r#"
+      mongodb_server = "mongodb"
+      keycloak_proxy_server = "keycloak_proxy"
+    "#).unwrap();
+
+    let config = Config::from_sources(file, |key| match key {
+      "YATA_API_PORT" => Some(String::from("8000")),
+      _ => None,
+    }).unwrap();
+
+    assert_eq!(config.port, 8000);
+    assert_eq!(config.mongodb_uri, "mongodb://mongodb:27017");
+    assert_eq!(config.certs_url(), "http://keycloak_proxy:9998/certs");
+  }
+
+  #[test]
+  fn test_config_reports_all_missing_keys() {
+    let e = Config::from_sources(Default::default(), |_| None)
+      .unwrap_err()
+      .to_string();
+
+    assert!(e.contains("YATA_API_MONGODB_SERVER"));
+    assert!(e.contains("YATA_API_KEYCLOAK_PROXY_SERVER"));
+  }
+
+  #[test]
+  fn test_printed_config_hides_mongodb_password() {
+    let config = Config::from_sources(Default::default(), |key| {
+      match key {
+        "YATA_API_MONGODB_URI" => Some(String::from(
+          "mongodb://yata:some_password@a:27017,b:27017/?replicaSet=rs0"
+        )),
+        "YATA_API_KEYCLOAK_PROXY_URL" => Some(String::from("http://p")),
+        "YATA_API_METRICS_TOKEN" => Some(String::from("some_token")),
+        "YATA_API_SERVICE_TOKEN" => Some(String::from("some_secret")),
+        _ => None,
+      }
+    }).unwrap();
+
+    let printed = config.print();
+    assert!(!printed.contains("some_password"));
+    assert!(!printed.contains("some_token"));
+    assert!(!printed.contains("some_secret"));
+    assert!(printed.contains("mongodb://yata:********@a:27017,b:27017"));
+  }
+
+  #[test]
+  fn test_metrics_render_domain_gauges() {
+    let metrics = Metrics::new();
+    let element_metrics = ElementMetrics::new(&metrics);
+    element_metrics.set_elements("Todo", 3);
+    element_metrics.set_bin_size(2);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("yata_elements{status=\"Todo\"} 3"));
+    assert!(rendered.contains("yata_bin_size 2"));
+  }
+
+  // the Authorization header the routes behind ticket_header see
+  async fn authorization_of(req: test::TestRequest) -> String {
+    let mut app = test::init_service(App::new()
+      .wrap_fn(ticket_header)
+      .route("/", web::get().to(|req: actix_web::HttpRequest| {
+        let header = req.headers().get("Authorization")
+          .map(|v| v.to_str().unwrap().to_string())
+          .unwrap_or_default();
+        async move { header }
+      }))).await;
+
+    let res = test::call_service(&mut app, req.to_request()).await;
+    String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+  }
+
+  #[actix_rt::test]
+  async fn test_tickets_stand_in_for_the_authorization_header() {
+    let ticket = test::TestRequest::get()
+      .uri("/?x=1&ticket=yata_ticket_ab");
+    assert_eq!(authorization_of(ticket).await, "Bearer yata_ticket_ab");
+
+    // only tickets, and never over the header of the request
+    let token = test::TestRequest::get().uri("/?ticket=yata_ab");
+    assert_eq!(authorization_of(token).await, "");
+
+    let header = test::TestRequest::get().uri("/?ticket=yata_ticket_ab")
+      .header("Authorization", "Bearer other");
+    assert_eq!(authorization_of(header).await, "Bearer other");
+
+    let ticket = AuthenticatedUser {
+      username: String::from("alice"),
+      credential: Credential::Ticket,
+    };
+    assert!(ticket.has_scope(Scope::Read));
+    assert!(!ticket.has_scope(Scope::Write));
+  }
+
+  fn cors_config() -> Config {
+    Config::from_sources(Default::default(), |key| match key {
+      "YATA_API_MONGODB_SERVER" => Some(String::from("mongodb")),
+      "YATA_API_KEYCLOAK_PROXY_SERVER" => Some(String::from("proxy")),
+      "YATA_API_CORS_ALLOWED_ORIGINS" => Some(String::from(
+        "https://yata.example.com, http://localhost:8000"
+      )),
+      _ => None,
+    }).unwrap()
+  }
+
+  async fn preflight(origin: &str, method: &str)
+    -> (StatusCode, Option<String>)
+  {
+    let mut app = test::init_service(App::new()
+      .wrap(cors_config().cors())
+      .route("/", web::put().to(|| {
+        ready(HttpResponse::Ok().finish())
+      }))).await;
+
+    let req = test::TestRequest::with_header("Origin", origin)
+      .method(Method::OPTIONS)
+      .header("Access-Control-Request-Method", method)
+      .header("Access-Control-Request-Headers", "authorization")
+      .to_request();
+
+    match app.call(req).await {
+      Ok(res) => (
+        res.status(),
+        res.headers().get("access-control-allow-origin")
+          .map(|v| v.to_str().unwrap().to_string()),
+      ),
+      Err(e) => (e.as_response_error().status_code(), None),
+    }
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_allowed_origin() {
+    let (status, allowed_origin) =
+      preflight("https://yata.example.com", "PUT").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(allowed_origin.unwrap(), "https://yata.example.com");
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_unknown_origin_is_rejected() {
+    let (status, allowed_origin) =
+      preflight("https://evil.example.com", "PUT").await;
+
+    assert!(status.is_client_error());
+    assert!(allowed_origin.is_none());
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_disallowed_method_is_rejected() {
+    let (status, _) = preflight("http://localhost:8000", "PATCH").await;
+    assert!(status.is_client_error());
+  }
+
+  #[test]
+  fn test_config_rejects_invalid_cors_methods() {
+    let e = Config::from_sources(Default::default(), |key| match key {
+      "YATA_API_MONGODB_SERVER" => Some(String::from("mongodb")),
+      "YATA_API_KEYCLOAK_PROXY_SERVER" => Some(String::from("proxy")),
+      "YATA_API_CORS_ALLOWED_METHODS" => Some(String::from("GET, P UT")),
+      _ => None,
+    }).unwrap_err().to_string();
+
+    assert!(e.contains("YATA_API_CORS_ALLOWED_METHODS"));
+  }
+
+  #[actix_rt::test]
+  async fn test_legacy_routes_are_marked_deprecated() {
+    let legacy_routes = LegacyRoutes::new(&cors_config());
+
+    let mut app = test::init_service(App::new()
+      .wrap_fn(move |req, srv| legacy_routes.mark(req, srv))
+      .route("/{user}/elements", web::get().to(|| {
+        ready(HttpResponse::Ok().finish())
+      }))).await;
+
+    let req = test::TestRequest::get()
+      .uri("/alice/elements?status=Todo")
+      .to_request();
+    let res = app.call(req).await.unwrap();
+    let header = |name: &str| res.headers().get(name).unwrap()
+      .to_str().unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header("deprecation"), "@1792368000");
+    assert_eq!(header("sunset"), "Mon, 19 Apr 2027 00:00:00 GMT");
+    assert_eq!(
+      header("link"),
+      "</api/v1/alice/elements?status=Todo>; rel=\"successor-version\"",
+    );
+  }
+
+  #[test]
+  fn test_config_rejects_invalid_legacy_route_dates() {
+    let e = Config::from_sources(Default::default(), |key| match key {
+      "YATA_API_MONGODB_SERVER" => Some(String::from("mongodb")),
+      "YATA_API_KEYCLOAK_PROXY_SERVER" => Some(String::from("proxy")),
+      "YATA_API_LEGACY_ROUTES_SUNSET" =>
+        Some(String::from("2027-13-01")),
+      _ => None,
+    }).unwrap_err().to_string();
+
+    assert!(e.contains("YATA_API_LEGACY_ROUTES_SUNSET"));
+    assert!(!e.contains("YATA_API_LEGACY_ROUTES_DEPRECATED"));
+  }
+
+  #[actix_rt::test]
+  async fn test_failed_logins_are_limited_per_client() {
+    let config = Config::from_sources(Default::default(), |key| {
+      match key {
+        "YATA_API_MONGODB_SERVER" => Some(String::from("mongodb")),
+        "YATA_API_KEYCLOAK_PROXY_SERVER" => Some(String::from("proxy")),
+        "YATA_API_CLIENT_RATE_LIMITS" => Some(String::from("* * 2/60")),
+        _ => None,
+      }
+    }).unwrap();
+
+    let rate_limits = Arc::new(RateLimits::new(&config));
+    let client_rate_limits = rate_limits.clone();
+
+    let mut app = test::init_service(App::new()
+      .service(web::scope("")
+        .wrap_fn(move |req, srv| rate_limits.limit(req, srv))
+        .wrap(HttpAuthentication::bearer(|req, _| async { Ok(req) }))
+        .wrap_fn(move |req, srv| {
+          client_rate_limits.limit_client(req, srv)
+        })
+        .route("/", web::get().to(|| {
+          ready(HttpResponse::Ok().finish())
+        })))).await;
+
+    let mut login = |peer: &str| {
+      // without credentials, so the authentication fails
+      let req = test::TestRequest::get()
+        .peer_addr(SocketAddr::new(peer.parse().unwrap(), 80))
+        .to_request();
+
+      app.call(req)
+    };
+
+    fn status<B>(res: Result<ServiceResponse<B>, actix_web::Error>)
+      -> StatusCode
+    {
+      match res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+      }
+    }
+
+    for _ in 0..2 {
+      assert_eq!(
+        status(login("1.2.3.4").await), StatusCode::UNAUTHORIZED,
+      );
+    }
+    assert_eq!(
+      status(login("1.2.3.4").await), StatusCode::TOO_MANY_REQUESTS,
+    );
+    assert_eq!(
+      status(login("5.6.7.8").await), StatusCode::UNAUTHORIZED,
+    );
+
+    // not limited under an empty key shared by every unknown client
+    let unknown = test::TestRequest::get().to_request();
+    assert_eq!(
+      status(app.call(unknown).await), StatusCode::BAD_REQUEST,
+    );
+  }
+
+  #[test]
+  fn test_startup_backoff_is_bounded() {
+    let max = Duration::from_secs(30);
+
+    assert_eq!(backoff(1, max), Duration::from_secs(1));
+    assert_eq!(backoff(3, max), Duration::from_secs(4));
+    assert_eq!(backoff(6, max), max);
+    assert_eq!(backoff(100, max), max);
+  }
+
+  #[actix_rt::test]
+  async fn test_event_stream_starts_with_retry_and_ends_on_close() {
+    let events = EventBus::new();
+    let mut stream = events.subscribe("some user");
+
+    assert_eq!(
+      stream.next().await,
+      Some(Bytes::from_static(b"retry: 5000\n\n")),
+    );
+
+    events.close();
+    assert_eq!(stream.next().await, None);
+  }
+
+  #[test]
+  fn test_if_match_compares_versions_strongly() {
+    let req = test::TestRequest::default()
+      .header("If-Match", "\"2\", \"3\"")
+      .to_http_request();
+
+    let condition = if_match(&req).unwrap().unwrap();
+    assert!(matches(&condition, 3));
+    assert!(!matches(&condition, 4));
+
+    let weak = IfMatch::Items(vec![EntityTag::weak(String::from("3"))]);
+    assert!(!matches(&weak, 3));
+    assert!(matches(&IfMatch::Any, 3));
+
+    let req = test::TestRequest::default().to_http_request();
+    assert!(if_match(&req).unwrap().is_none());
+  }
+
+  fn exported_entries() -> Vec<Entry> {
+    vec![
+      Entry {
+        id: Some(ObjectId::new().to_hex()),
+        content: String::from("plain"),
+        status: ElementStatus::Todo,
+        created: Some(Utc.timestamp(1_600_000_000, 0)),
+      },
+      Entry {
+        id: Some(ObjectId::new().to_hex()),
+        content: String::from(
+          "quotes \", commas, semicolons; back\\slashes\nand lines"
+        ),
+        status: ElementStatus::Done,
+        created: Some(Utc.timestamp(1_600_000_100, 0)),
+      },
+      Entry {
+        id: Some(ObjectId::new().to_hex()),
+        content: "long and ünicödé ".repeat(10),
+        status: ElementStatus::Deleted,
+        created: Some(Utc.timestamp(1_600_000_200, 0)),
+      },
+    ]
+  }
+
+  #[test]
+  fn test_export_round_trips() {
+    let entries = exported_entries();
+
+    for format in &[Format::Json, Format::Csv, Format::Ics] {
+      let exported = format.encode(&entries);
+      assert_eq!(format.decode(&exported).unwrap(), entries);
+    }
+
+    assert_eq!(Format::Json.decode(&Format::Json.encode(&[])).unwrap(),
+      vec![]);
+  }
+
+  #[test]
+  fn test_ics_export_is_valid_rfc_5545() {
+    let exported = Format::Ics.encode(&exported_entries());
+
+    assert!(exported.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+    assert!(exported.ends_with("END:VCALENDAR\r\n"));
+    assert!(exported.split("\r\n").all(|line| line.len() <= 75));
+
+    for status in &["NEEDS-ACTION", "COMPLETED", "CANCELLED"] {
+      assert!(exported.contains(&format!("STATUS:{}\r\n", status)));
+    }
+
+    assert!(exported.contains("CREATED:20200913T122640Z\r\n"));
+    assert!(
+      exported.contains("commas\\, semicolons\\; back\\\\slashes")
+    );
+  }
+
+  #[test]
+  fn test_todo_txt_import() {
+    let input = "\
+      x 2020-09-13 2020-09-01 (A) call mom +family @phone\n\n\
+      (B) 2020-09-02 buy milk @store\n\
+      just a task due:2020-10-01\n\
+      x 2020-09-14 water plants pri:C +garden\n";
+
+    let decoded = ImportFormat::TodoTxt
+      .decode(input, &Columns::default())
+      .unwrap();
+
+    let entries: Vec<Entry> = decoded.into_iter()
+      .map(|d| d.entry.unwrap())
+      .collect();
+
+    assert_eq!(entries[0].status, ElementStatus::Done);
+    assert_eq!(entries[0].content, "(A) call mom +family @phone");
+    assert_eq!(
+      entries[0].created, Some(Utc.ymd(2020, 9, 1).and_hms(0, 0, 0)),
+    );
+    assert_eq!(entries[1].status, ElementStatus::Todo);
+    assert_eq!(entries[1].content, "(B) buy milk @store");
+    assert_eq!(entries[2].content, "just a task due:2020-10-01");
+    assert_eq!(entries[2].created, None);
+    assert_eq!(entries[3].status, ElementStatus::Done);
+    assert_eq!(entries[3].content, "(C) water plants +garden");
+    assert_eq!(
+      entries[3].created, Some(Utc.ymd(2020, 9, 14).and_hms(0, 0, 0)),
+    );
+  }
+
+  #[test]
+  fn test_todo_txt_export_round_trips() {
+    let input = "\
+      (A) 2020-09-01 call mom +family @phone\n\
+      x 2020-09-02 buy milk @store pri:B\n\
+      just a task due:2020-10-01\n";
+
+    let entries = Format::TodoTxt.decode(input).unwrap();
+
+    assert_eq!(entries[0].content, "(A) call mom +family @phone");
+    assert_eq!(entries[1].content, "(B) buy milk @store");
+    assert_eq!(Format::TodoTxt.encode(&entries), input);
+
+    let deleted = Entry {
+      status: ElementStatus::Deleted,
+      ..entries[2].clone()
+    };
+    assert_eq!(Format::TodoTxt.encode(&[deleted]), "");
+  }
+
+  #[test]
+  fn test_csv_import_maps_columns_and_reports_lines() {
+    let input = "Task,State,Notes\n\
+      first,completed,\n\
+      second,,\n\
+      third,unknown,\n\
+      ,open,no content\n";
+
+    let columns: Columns = "content=Task, status=State".parse()
+      .unwrap();
+
+    let decoded = ImportFormat::Csv.decode(input, &columns).unwrap();
+
+    assert_eq!(decoded[0].entry.as_ref().unwrap().status,
+      ElementStatus::Done);
+    assert_eq!(decoded[1].entry.as_ref().unwrap().status,
+      ElementStatus::Todo);
+    assert_eq!(decoded[2].line, 4);
+    assert!(decoded[2].entry.is_err());
+    assert_eq!(decoded[3].entry.as_ref().unwrap_err(), "empty content");
+
+    assert!("owner=Someone".parse::<Columns>().is_err());
+    assert!(
+      ImportFormat::Csv.decode(input, &Columns::default()).is_err()
+    );
+  }
+
+  #[test]
+  fn test_ics_import_reports_invalid_todos() {
+    let input = "BEGIN:VCALENDAR\r\n\
+      BEGIN:VTODO\r\n\
+      SUMMARY:valid\r\n\
+      BEGIN:VALARM\r\n\
+      SUMMARY:not the content\r\n\
+      END:VALARM\r\n\
+      END:VTODO\r\n\
+      BEGIN:VTODO\r\n\
+      SUMMARY:invalid\r\n\
+      CREATED:yesterday\r\n\
+      END:VTODO\r\n\
+      BEGIN:VTODO\r\n\
+      SUMMARY:unterminated\r\n";
+
+    let decoded = ImportFormat::Ics
+      .decode(input, &Columns::default())
+      .unwrap();
+
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(decoded[0].entry.as_ref().unwrap().content, "valid");
+    assert_eq!(decoded[1].line, 10);
+    assert_eq!(decoded[1].entry.as_ref().unwrap_err(),
+      "invalid date-time yesterday");
+    assert_eq!(decoded[2].line, 12);
+    assert!(decoded[2].entry.is_err());
+  }
+
+  #[test]
+  fn test_import_entry_keeps_status_and_creation_time()
+    -> Result<(), ParseDocumentError>
+  {
+    let created = Utc.timestamp(1_600_000_000, 0);
+
+    let doc = to_mongodb_import_entry(Entry {
+      id: Some(String::from("foreign id")),
+      content: String::from("some content"),
+      status: ElementStatus::Done,
+      created: Some(created),
+    }, String::from("some user"))?;
+
+    assert!(doc.get("_id").is_none());
+    assert_eq!(doc.get_datetime("created")?, &created);
+    assert_eq!(
+      doc.get("status"), Some(&to_bson(&ElementStatus::Done)?),
+    );
+    Ok(())
+  }
+
+  // the fixtures are written by hand in the shape of the requests of
+  // CalDAV clients, they are not recorded traffic
+  #[test]
+  fn test_caldav_requests_are_understood() {
+    let propfind = parse_propfind(
+      include_str!("../tests/fixtures/caldav/propfind_calendar.xml")
+    ).unwrap();
+
+    match propfind {
+      PropFind::Props(props) => {
+        assert_eq!(props.len(), 6);
+        assert_eq!(props[5], PropName {
+          namespace: String::from("http://calendarserver.org/ns/"),
+          name: String::from("getctag"),
+        });
+      },
+      PropFind::All => panic!("expected the listed properties"),
+    }
+
+    assert_eq!(parse_propfind("").unwrap(), PropFind::All);
+
+    let query = |fixture| match parse_report(fixture).unwrap() {
+      Some(Report::Query { todos, .. }) => todos,
+      other => panic!("expected a calendar-query, got {:?}", other),
+    };
+
+    assert!(query(
+      include_str!("../tests/fixtures/caldav/calendar_query_vtodo.xml")
+    ));
+    assert!(!query(
+      include_str!("../tests/fixtures/caldav/calendar_query_vevent.xml")
+    ));
+
+    let multiget = parse_report(
+      include_str!("../tests/fixtures/caldav/calendar_multiget.xml")
+    ).unwrap();
+
+    match multiget {
+      Some(Report::Multiget { props, names }) => {
+        assert_eq!(props.len(), 2);
+        assert_eq!(names, vec![
+          "5f5b4e0b0000000000000000.ics", "0c1e7a52-client.ics",
+        ]);
+      },
+      other => panic!("expected a calendar-multiget, got {:?}", other),
+    }
+
+    let sync = "<d:sync-collection xmlns:d=\"DAV:\"/>";
+    assert_eq!(parse_report(sync).unwrap(), None);
+    assert!(parse_report("<unterminated").is_err());
+
+    assert_eq!(
+      Scope::required_for(&Method::from_bytes(b"PROPFIND").unwrap()),
+      Scope::Read,
+    );
+  }
+
+  #[test]
+  fn test_caldav_responses_list_missing_properties()
     -> Result<(), ParseDocumentError>
   {
-    let doc = doc! {
-      "_id": ObjectId::new(),
-      "content": String::from("some content"),
-      "status": to_bson(&ElementStatus::Todo).unwrap(),
-      "created": Utc::now()
+    let mut doc = to_mongodb_entry(SingleContent {
+      content: String::from("buy <milk> & eggs"),
+    }, String::from("alice"))?;
+    doc.insert("_id", ObjectId::with_string("5f5b4e0b0000000000000000")
+      .unwrap());
+
+    let props = PropFind::Props(vec![
+      PropName {
+        namespace: String::from("DAV:"),
+        name: String::from("getetag"),
+      },
+      PropName {
+        namespace: String::from("urn:ietf:params:xml:ns:caldav"),
+        name: String::from("calendar-data"),
+      },
+      PropName {
+        namespace: String::from("http://example.org/ns/"),
+        name: String::from("color"),
+      },
+    ]);
+
+    let res = Resource::Object(Object::try_from(&doc)?)
+      .response("alice", &props);
+
+    assert!(res.starts_with("<d:response><d:href>\
+      /dav/alice/todos/5f5b4e0b0000000000000000.ics</d:href>"));
+    assert!(res.contains("<d:getetag>&quot;1&quot;</d:getetag>"));
+    assert!(res.contains("SUMMARY:buy &lt;milk&gt; &amp; eggs"));
+    assert!(res.contains("UID:5f5b4e0b0000000000000000@yata"));
+    assert!(res.contains("<d:propstat><d:prop>\
+      <x:color xmlns:x=\"http://example.org/ns/\"/></d:prop>\
+      <d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>"));
+
+    let calendar = Resource::Calendar { ctag: 7 }
+      .response("alice", &PropFind::All);
+
+    assert!(calendar.contains("<c:calendar/>"));
+    assert!(calendar.contains("<cs:getctag>7</cs:getctag>"));
+    assert!(!calendar.contains("404"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_graphql_pages_are_queried_in_mongodb() {
+    let filter = ElementFilter {
+      status: Some(ElementStatus::Todo),
+      search: Some(String::from("1+1 (maybe)")),
+    };
+
+    let after = async_graphql::ID::from("5f5b4e0b0000000000000003");
+    let query = page_query(
+      doc!{"user": "alice"}, &filter, Some(&after),
+    ).unwrap();
+
+    assert_eq!(query, doc!{"$and": [
+      {"user": "alice"},
+      {"status": "Todo"},
+      {"content": {"$regex": "1\\+1 \\(maybe\\)", "$options": "i"}},
+      {"_id": {"$gt": ObjectId::with_string(after.as_str()).unwrap()}},
+    ]});
+
+    let total = page_query(
+      doc!{"user": "alice"}, &ElementFilter::default(), None,
+    ).unwrap();
+    assert_eq!(total, doc!{"$and": [{"user": "alice"}]});
+
+    assert!(page_query(
+      doc!{}, &filter, Some(&async_graphql::ID::from("not an id")),
+    ).is_err());
+
+    assert_eq!(page_size(None).unwrap(), 50);
+    assert!(page_size(Some(-1)).is_err());
+    assert!(page_size(Some(1000)).is_err());
+  }
+
+  #[actix_rt::test]
+  async fn test_graphql_mutations_need_the_write_scope() {
+    let schema = async_graphql::Schema::build(
+      QueryRoot, MutationRoot, SubscriptionRoot,
+    ).finish();
+
+    let reader = AuthenticatedUser {
+      username: String::from("alice"),
+      credential: Credential::AccessToken(vec![Scope::Read]),
     };
 
-    Element::try_from(doc)?;
+    let req = async_graphql::Request::new("mutation { emptyBin }")
+      .data(reader);
+
+    let res = schema.execute(req).await;
+
+    assert_eq!(res.errors.len(), 1);
+    assert_eq!(
+      res.errors[0].message, "the access token does not allow writing",
+    );
+  }
+
+  // the routes declared with the attribute macros of actix in source,
+  // as method and path
+  fn declared_routes(source: &str) -> Vec<(String, String)> {
+    source.lines()
+      .filter_map(|line| {
+        let line = line.trim().strip_prefix("#[")?;
+        let (method, rest) = line.split_at(line.find('(')?);
+
+        if !["get", "post", "put", "delete"].contains(&method) {
+          return None;
+        }
+
+        let path = rest.strip_prefix("(\"")?.split('"').next()?;
+        Some((String::from(method), String::from(path)))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_openapi_documents_every_route() {
+    let methods = ["get", "post", "put", "delete"];
+    let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+    let documented: BTreeSet<(String, String)> = spec["paths"]
+      .as_object()
+      .unwrap()
+      .iter()
+      .flat_map(|(path, item)| item.as_object().unwrap()
+        .keys()
+        .filter(|m| methods.contains(&m.as_str()))
+        .map(move |m| (m.clone(), path.clone())))
+      .collect();
+
+    // declared relative to their scope
+    let paths = &spec["paths"];
+    assert!(paths.get("/api/v1/{user}/{id}/status").is_some());
+    assert!(paths.get("/{user}/{id}/status").is_none());
+    assert!(paths.get("/healthz").is_some());
+
+    let documented: BTreeSet<(String, String)> = documented.into_iter()
+      .map(|(method, path)| match path.strip_prefix(API_PREFIX) {
+        Some(path) => (method, String::from(path)),
+        None => (method, path),
+      })
+      .collect();
+
+    let declared: BTreeSet<(String, String)> = [
+      include_str!("routes.rs"),
+      include_str!("graphql.rs"),
+      include_str!("openapi.rs"),
+    ].iter().flat_map(|source| declared_routes(source)).collect();
+
+    assert!(declared.contains(
+      &(String::from("put"), String::from("/{user}/{id}/status"))
+    ));
+    assert_eq!(documented, declared);
+  }
+
+  #[test]
+  fn test_openapi_schema_of_elements_matches_their_json()
+    -> Result<(), ParseDocumentError>
+  {
+    let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    let schemas = &spec["components"]["schemas"];
+
+    let mut doc = to_mongodb_entry(SingleContent {
+      content: String::from("some content"),
+    }, String::from("alice"))?;
+    doc.insert("_id", ObjectId::new());
+
+    let mut element = Element::try_from(doc)?;
+    element.mark_shared(String::from("bob"), Permission::Viewer);
+
+    let json = serde_json::to_value(&element).unwrap();
+
+    let keys = |value: &serde_json::Value| value.as_object().unwrap()
+      .keys()
+      .cloned()
+      .collect::<BTreeSet<String>>();
+
+    assert_eq!(keys(&json), keys(&schemas["Element"]["properties"]));
+    assert_eq!(
+      keys(&json["shared"]),
+      keys(&schemas["SharedElement"]["properties"]),
+    );
+
+    let statuses = schemas["ElementStatus"]["enum"].as_array().unwrap();
+
+    for status in &[ElementStatus::Todo, ElementStatus::Done,
+      ElementStatus::Deleted]
+    {
+      let status = serde_json::to_value(status).unwrap();
+      assert!(statuses.contains(&status));
+    }
     Ok(())
   }
 }
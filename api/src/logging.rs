@@ -0,0 +1,41 @@
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent,
+  CommandStartedEvent, CommandSucceededEvent};
+
+use tracing::{debug, warn};
+
+// logs the commands sent to mongodb. They are emitted while the
+// operation is awaited, so they end up in the span of the request
+pub struct CommandLogger;
+
+impl CommandEventHandler for CommandLogger {
+  fn handle_command_started_event(&self, event: CommandStartedEvent) {
+    debug!(
+      command = %event.command_name,
+      db = %event.db,
+      mongodb_request_id = event.request_id,
+      "mongodb command started",
+    );
+  }
+
+  fn handle_command_succeeded_event(
+    &self,
+    event: CommandSucceededEvent,
+  ) {
+    debug!(
+      command = %event.command_name,
+      mongodb_request_id = event.request_id,
+      duration_ms = event.duration.as_secs_f64() * 1000.0,
+      "mongodb command succeeded",
+    );
+  }
+
+  fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+    warn!(
+      command = %event.command_name,
+      mongodb_request_id = event.request_id,
+      duration_ms = event.duration.as_secs_f64() * 1000.0,
+      error = %event.failure,
+      "mongodb command failed",
+    );
+  }
+}
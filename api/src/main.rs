@@ -1,25 +1,42 @@
-#![feature(try_trait)]
-
-use actix_web::{App, HttpServer};
+use actix_web::{web, App, HttpServer};
+use actix_service::Service;
 
 use actix_web_httpauth::middleware::HttpAuthentication;
 
-use actix_cors::Cors;
-
 use jwks_client::keyset::KeyStore;
 
-use mongodb::{Client, Collection};
+use futures::FutureExt;
+
+use mongodb::Client;
 use mongodb::options::ClientOptions;
-use mongodb::error::Result as MDBResult;
+
+use tracing::{error, info};
+
+use yata_common::logging::{self, trace_request};
+use yata_common::metrics::Metrics;
 
 #[macro_use]
 extern crate partial_application;
 
 use std::sync::Arc;
 use std::env;
-
-use yata_api::middlewares::auth;
+use std::process;
+
+use yata_api::api_version::{API_PREFIX, LegacyRoutes};
+use yata_api::caldav;
+use yata_api::config::Config;
+use yata_api::errors::StoreError;
+use yata_api::events::EventBus;
+use yata_api::graphql::{graphql, graphql_query, graphql_subscriptions};
+use yata_api::logging::CommandLogger;
+use yata_api::metrics::ElementMetrics;
+use yata_api::middlewares::{auth, dav_auth, ticket_header};
+use yata_api::openapi::{get_openapi, swagger_ui};
+use yata_api::rate_limit::RateLimits;
+use yata_api::retry;
 use yata_api::routes::*;
+use yata_api::store::Store;
+use yata_api::tokens::ServiceToken;
 
 //use yata_api::apps::App;
 
@@ -27,60 +44,195 @@ use yata_api::routes::*;
 // TODO: created -> last modified?
 // TODO: timestamp in id -> no extra field created necessary
 
-async fn init_database(database_server: String)
-  -> MDBResult<Collection>
-{
-  let client_options = ClientOptions::parse(
-    &format!("mongodb://{}:27017", database_server)
-  ).await?;
+// the client connects lazily, so the connection is only known to work
+// once mongodb answered a ping
+async fn init_database(config: &Config) -> Result<Store, StoreError> {
+  let mut client_options =
+    ClientOptions::parse(&config.mongodb_uri).await?;
+  client_options.command_event_handler = Some(Arc::new(CommandLogger));
+
   let client = Client::with_options(client_options)?;
-  let database = client.database("yata_db");
-  Ok(database.collection("yata_collection"))
+  let database = client.database(&config.database);
+  let store = Store::new(&database);
+  store.ping().await?;
+  Ok(store)
+}
+
+// the routes of users, served below API_PREFIX and, deprecated, at
+// the root
+fn routes(cfg: &mut web::ServiceConfig) {
+  cfg
+    .service(get_elements)
+    .service(get_changes)
+    .service(export)
+    .service(import)
+    .service(get_activity)
+    .service(undo)
+    .service(add_todo)
+    .service(set_status)
+    .service(delete_element)
+    .service(get_history)
+    .service(empty_bin)
+    .service(get_events)
+    .service(graphql)
+    // before graphql_query, which would take the upgrade requests
+    .service(graphql_subscriptions)
+    .service(graphql_query)
+    .service(delete_user)
+    .service(get_shares)
+    .service(add_share)
+    .service(delete_share)
+    .service(get_access_tokens)
+    .service(add_access_token)
+    .service(delete_access_token)
+    .service(add_ticket);
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-  println!("STARTING YATA_API SERVER");
+  let args: Vec<String> = env::args().collect();
+
+  let config = match Config::load(&args) {
+    Ok(config) => config,
+    Err(e) => {
+      eprint!("{}", e);
+      process::exit(1);
+    }
+  };
+
+  if args.iter().any(|a| a == "--print-config") {
+    print!("{}", config.print());
+    return Ok(());
+  }
+
+  if let Err(e) = logging::init(&config.log_level, config.log_format) {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+
+  info!("starting yata_api server");
+
+  let addr = format!("0.0.0.0:{}", config.port);
+
+  let store = match retry::with_backoff(
+    "connecting to mongodb",
+    config.startup_attempts,
+    config.startup_backoff_max(),
+    || init_database(&config),
+  ).await {
+    Ok(store) => store,
+    Err(e) => {
+      error!(error = ?e, "could not connect to mongodb, giving up");
+      process::exit(1);
+    }
+  };
+
+  let url = config.certs_url();
+  info!(url = %url, "getting key set");
+
+  let key_set = match retry::with_backoff(
+    "getting key set",
+    config.startup_attempts,
+    config.startup_backoff_max(),
+    || KeyStore::new_from(&url),
+  ).await {
+    Ok(key_set) => Arc::new(key_set),
+    Err(e) => {
+      error!(error = ?e, "could not get key set, giving up");
+      process::exit(1);
+    }
+  };
+
+  let shutdown_timeout = config.shutdown_timeout_secs;
+  let serve_swagger_ui = config.swagger_ui;
+
+  let metrics = Metrics::new()
+    .require_token(config.metrics_token.as_deref());
+  let service_token =
+    ServiceToken::new(config.service_token.as_deref());
+  ElementMetrics::new(&metrics)
+    .spawn_refresh(store.clone(), config.metrics_refresh());
+  let rate_limits = Arc::new(RateLimits::new(&config));
+  let legacy_routes = LegacyRoutes::new(&config);
+
+  let events = EventBus::new();
+  events.spawn_heartbeat();
+  let app_events = events.clone();
+
+  let schema = yata_api::graphql::schema(store.clone(), events.clone());
 
-  let addr = format!(
-    "0.0.0.0:{}", env::var("YATA_API_PORT").unwrap()
-  );
-
-  let database_server = env::var("YATA_API_MONGODB_SERVER").unwrap();
-  let collection = init_database(database_server).await.unwrap();
-
-  let url = format!(
-    "http://{}:{}/certs",
-    env::var("YATA_API_KEYCLOAK_PROXY_SERVER").unwrap(),
-    env::var("YATA_API_KEYCLOAK_PROXY_PORT").unwrap(),
-  );
-  println!("getting keystore from: {}", url);
-
-  let key_set = Arc::new(KeyStore::new_from(&url).await.unwrap());
   HttpServer::new(move || {
     let key_set2 = key_set.clone();
     let auth_fn = partial!(move auth => _, _, key_set2.clone());
+    let request_metrics = metrics.clone();
+    let rate_limits = rate_limits.clone();
+    let client_rate_limits = rate_limits.clone();
+    let dav_rate_limits = rate_limits.clone();
+    let dav_client_rate_limits = rate_limits.clone();
+    let legacy_rate_limits = rate_limits.clone();
+    let legacy_client_rate_limits = rate_limits.clone();
+    let legacy_routes = legacy_routes.clone();
 
     App::new()
-      .data(collection.clone())
-      .wrap(HttpAuthentication::bearer(auth_fn.clone()))
-      .wrap(Cors::permissive()) // TODO: only yata_frontend
-      /*
-      .wrap_fn(|req, srv| {
-        println!("OMG req!");
-        srv.call(req).map(|res| {
-          println!("{:?}", res);
+      .data(store.clone())
+      .data(key_set.clone())
+      .data(metrics.clone())
+      .data(service_token.clone())
+      .data(app_events.clone())
+      .data(schema.clone())
+      .wrap(config.cors())
+      .wrap_fn(move |req, srv| {
+        let timer = request_metrics.start(&req);
+        srv.call(req).map(move |res| {
+          timer.finish(&res);
           res
         })
       })
-      */
-      .service(get_elements)
-      .service(add_todo)
-      .service(set_status)
-      .service(delete_element)
-      .service(empty_bin)
+      .wrap_fn(trace_request)
+      // registered before the authenticated scopes, which would
+      // answer every other path
+      .service(healthz)
+      .service(readyz)
+      .service(get_metrics)
+      .service(get_openapi)
+      .configure(|cfg| if serve_swagger_ui {
+        cfg.service(swagger_ui);
+      })
+      // middlewares registered last run first, so requests are limited
+      // per client before and per user after the authentication
+      .service(web::scope(caldav::DAV_ROOT)
+        .wrap_fn(move |req, srv| dav_rate_limits.limit(req, srv))
+        .wrap(HttpAuthentication::basic(dav_auth))
+        .wrap_fn(move |req, srv| {
+          dav_client_rate_limits.limit_client(req, srv)
+        })
+        .configure(caldav::configure))
+      .service(web::scope(API_PREFIX)
+        .wrap_fn(move |req, srv| rate_limits.limit(req, srv))
+        .wrap(HttpAuthentication::bearer(auth_fn.clone()))
+        .wrap_fn(ticket_header)
+        .wrap_fn(move |req, srv| {
+          client_rate_limits.limit_client(req, srv)
+        })
+        .configure(routes))
+      .service(web::scope("")
+        .wrap_fn(move |req, srv| legacy_rate_limits.limit(req, srv))
+        .wrap(HttpAuthentication::bearer(auth_fn.clone()))
+        .wrap_fn(ticket_header)
+        .wrap_fn(move |req, srv| {
+          legacy_client_rate_limits.limit_client(req, srv)
+        })
+        .wrap_fn(move |req, srv| legacy_routes.mark(req, srv))
+        .configure(routes))
   })
+  // actix stops accepting connections on SIGTERM and waits for the
+  // in-flight requests to finish
+  .shutdown_timeout(shutdown_timeout)
   .bind(&addr)?
   .run()
-  .await
+  .await?;
+
+  events.close();
+  info!("yata_api server stopped");
+  Ok(())
 }
@@ -0,0 +1,90 @@
+use actix_web::rt::{spawn, time};
+
+use mongodb::bson::to_bson;
+
+use prometheus::{IntGauge, IntGaugeVec, Opts};
+
+use tracing::warn;
+
+use yata_common::metrics::Metrics;
+
+use std::time::Duration;
+
+use crate::elements::ElementStatus;
+use crate::errors::StoreError;
+use crate::store::Store;
+
+// the stored elements, rendered with the metrics of the requests
+#[derive(Clone)]
+pub struct ElementMetrics {
+  elements: IntGaugeVec,
+  bin_size: IntGauge,
+}
+
+impl ElementMetrics {
+  pub fn new(metrics: &Metrics) -> ElementMetrics {
+    let elements = IntGaugeVec::new(
+      Opts::new("yata_elements", "Number of stored elements"),
+      &["status"],
+    ).unwrap();
+
+    let bin_size = IntGauge::new(
+      "yata_bin_size", "Number of deleted elements not yet purged",
+    ).unwrap();
+
+    metrics.register(elements.clone());
+    metrics.register(bin_size.clone());
+
+    ElementMetrics{elements, bin_size}
+  }
+
+  // counts the elements in the background, so scrapes do not query
+  // the database
+  pub fn spawn_refresh(&self, store: Store, every: Duration) {
+    let metrics = self.clone();
+
+    spawn(async move {
+      let mut interval = time::interval(every);
+
+      loop {
+        interval.tick().await;
+
+        if let Err(e) = metrics.refresh(&store).await {
+          warn!(error = ?e, "could not count elements for metrics");
+        }
+      }
+    });
+  }
+
+  async fn refresh(&self, store: &Store) -> Result<(), StoreError> {
+    let counts = store.element_counts().await?;
+
+    for status in &[ElementStatus::Todo, ElementStatus::Done,
+      ElementStatus::Deleted]
+    {
+      let name = to_bson(status)?;
+      let name = name.as_str().unwrap_or_default();
+
+      let count = counts.iter()
+        .find(|(s, _)| s == name)
+        .map(|(_, count)| *count)
+        .unwrap_or(0);
+
+      self.set_elements(name, count);
+
+      if *status == ElementStatus::Deleted {
+        self.set_bin_size(count);
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn set_elements(&self, status: &str, count: i64) {
+    self.elements.with_label_values(&[status]).set(count);
+  }
+
+  pub fn set_bin_size(&self, count: i64) {
+    self.bin_size.set(count);
+  }
+}
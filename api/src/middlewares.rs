@@ -1,38 +1,241 @@
-use actix_web::Error as ActixError;
-use actix_web::dev::ServiceRequest;
+use actix_web::{web, Error as ActixError, HttpMessage};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderValue, Method};
+use actix_web::http::header::AUTHORIZATION;
+
+use actix_service::Service;
 
 use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::extractors::basic::Config as BasicConfig;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::extractors::bearer::Config as BearerConfig;
 
 use jwks_client::keyset::KeyStore;
 use jwks_client::error::Error as JWTError;
 
+use tracing::{debug, warn};
+
 use std::sync::Arc;
 
+use crate::store::Store;
+use crate::tokens::{Scope, TICKET_PREFIX, TOKEN_PREFIX};
+
+// routes below this path root act on the authenticated user instead
+// of the user named in the path. Only the listed resources are
+// reachable, so "/me" can not be used to read the elements of a user
+// called "me"
+pub static ME_PATH_ROOT: &str = "me";
+static ME_RESOURCES: [&str; 2] = ["tokens", "tickets"];
+
+// the resources of users tickets can open, graphql only for the
+// websocket of subscriptions
+static STREAM_RESOURCES: [&str; 2] = ["events", "graphql"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Credential {
+  Keycloak,
+  AccessToken(Vec<Scope>),
+  Ticket,
+}
+
+// inserted into the request extensions by auth, handlers can
+// extract it with web::ReqData<AuthenticatedUser>
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+  pub username: String,
+  pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+  fn may_access(&self, path_root: &str, req: &ServiceRequest) -> bool {
+    if path_root == ME_PATH_ROOT {
+      let resource = req.match_info().unprocessed()
+        .split("/").nth(2).unwrap_or("");
+
+      // personal access tokens can neither manage other tokens nor
+      // get tickets
+      return ME_RESOURCES.contains(&resource)
+        && self.credential == Credential::Keycloak;
+    }
+
+    if self.username != path_root {
+      return false;
+    }
+
+    if self.credential == Credential::Ticket {
+      let segments: Vec<&str> = req.match_info().unprocessed()
+        .split("/").collect();
+
+      let upgrade = req.headers().get("Upgrade").is_some_and(|v| {
+        v.as_bytes().eq_ignore_ascii_case(b"websocket")
+      });
+
+      return req.method() == Method::GET
+        && segments.len() == 3
+        && STREAM_RESOURCES.contains(&segments[2])
+        && (segments[2] != "graphql" || upgrade);
+    }
+
+    self.has_scope(Scope::required_for(req.method()))
+  }
+
+  pub fn has_scope(&self, scope: Scope) -> bool {
+    match &self.credential {
+      Credential::Keycloak => true,
+      Credential::AccessToken(scopes) => scopes.contains(&scope),
+      Credential::Ticket => scope == Scope::Read,
+    }
+  }
+}
 
 pub async fn auth(
   req: ServiceRequest,
   bearer: BearerAuth,
   key_set:Arc<KeyStore>) -> Result<ServiceRequest, ActixError>
 {
-  match key_set.verify(bearer.token()) {
+  // tickets are used up even if they do not grant access to the path,
+  // so every ticket is checked once at most
+  let user = if bearer.token().starts_with(TICKET_PREFIX) {
+    verify_ticket(&req, bearer.token()).await
+  } else if bearer.token().starts_with(TOKEN_PREFIX) {
+    verify_access_token(&req, bearer.token()).await
+  } else {
+    verify_jwt(&key_set, bearer.token())
+  };
+
+  if let Some(user) = user {
+    // relative to the scope, the routes are served both at the root
+    // and below API_PREFIX
+    let path_root = req.match_info().unprocessed()
+      .split("/").nth(1).unwrap_or("");
+
+    if user.may_access(path_root, &req) {
+      req.extensions_mut().insert(user);
+      return Ok(req);
+    }
+  }
+
+  let config = req.app_data::<BearerConfig>().cloned()
+    .unwrap_or_default();
+
+  Err(AuthenticationError::from(config).into())
+}
+
+// EventSource and WebSocket can not set the Authorization header, so
+// the ticket query parameter of requests without it stands in for it.
+// Registered after the bearer auth, so it runs before it
+pub fn ticket_header<S, B>(mut req: ServiceRequest, srv: &mut S)
+  -> S::Future
+  where S: Service<
+    Request = ServiceRequest,
+    Response = ServiceResponse<B>,
+    Error = ActixError,
+  >
+{
+  if !req.headers().contains_key(AUTHORIZATION) {
+    let header = req.query_string().split("&")
+      .find_map(|pair| pair.strip_prefix("ticket="))
+      .filter(|ticket| ticket.starts_with(TICKET_PREFIX))
+      .and_then(|ticket| {
+        HeaderValue::from_str(&format!("Bearer {}", ticket)).ok()
+      });
+
+    if let Some(header) = header {
+      req.headers_mut().insert(AUTHORIZATION, header);
+    }
+  }
+
+  srv.call(req)
+}
+
+// CalDAV clients only support basic auth. The password is a personal
+// access token of the user, paths look like /dav/{user}/...
+pub async fn dav_auth(
+  req: ServiceRequest,
+  basic: BasicAuth) -> Result<ServiceRequest, ActixError>
+{
+  let token = basic.password()
+    .map(|password| password.to_string())
+    .unwrap_or_default();
+
+  let user = verify_access_token(&req, &token).await
+    .filter(|user| user.username == basic.user_id().as_ref());
+
+  if let Some(user) = user {
+    let path_user = req.path().split("/").nth(2).unwrap_or("");
+
+    let allowed = match &user.credential {
+      Credential::AccessToken(scopes) =>
+        scopes.contains(&Scope::required_for(req.method())),
+      Credential::Keycloak | Credential::Ticket => false,
+    };
+
+    if allowed && (path_user.is_empty() || path_user == user.username) {
+      req.extensions_mut().insert(user);
+      return Ok(req);
+    }
+  }
+
+  let config = req.app_data::<BasicConfig>().cloned()
+    .unwrap_or_else(|| BasicConfig::default().realm("yata"));
+
+  Err(AuthenticationError::from(config).into())
+}
+
+fn verify_jwt(key_set: &KeyStore, token: &str)
+  -> Option<AuthenticatedUser>
+{
+  match key_set.verify(token) {
     Ok(jwt) => {
-      let username = jwt.payload().get_str("preferred_username").unwrap();
-      let path_root = req.path().split("/").nth(1).unwrap();
+      let username = jwt.payload().get_str("preferred_username")?;
 
-      if username == path_root {
-        return Ok(req);
-      }
+      Some(AuthenticatedUser {
+        username: String::from(username),
+        credential: Credential::Keycloak,
+      })
     },
     Err(JWTError { msg, typ: _ }) => {
-      eprintln!("Could not verify token. Reason: {}", msg);
+      debug!("could not verify token: {}", msg);
+      None
     }
   }
+}
 
-  let config = req.app_data::<BearerConfig>()
-    .map(|data| data.clone())
-    .unwrap_or_else(Default::default);
+async fn verify_access_token(req: &ServiceRequest, token: &str)
+  -> Option<AuthenticatedUser>
+{
+  let store = req.app_data::<web::Data<Store>>()?;
 
-  Err(AuthenticationError::from(config).into())
+  match store.use_access_token(token).await {
+    Ok(Some(access_token)) => Some(AuthenticatedUser {
+      username: String::from(access_token.user()),
+      credential: Credential::AccessToken(
+        access_token.scopes().to_vec()
+      ),
+    }),
+    Ok(None) => None,
+    Err(e) => {
+      warn!("could not verify access token: {:?}", e);
+      None
+    }
+  }
+}
+
+async fn verify_ticket(req: &ServiceRequest, ticket: &str)
+  -> Option<AuthenticatedUser>
+{
+  let store = req.app_data::<web::Data<Store>>()?;
+
+  match store.use_ticket(ticket).await {
+    Ok(Some(user)) => Some(AuthenticatedUser {
+      username: user,
+      credential: Credential::Ticket,
+    }),
+    Ok(None) => None,
+    Err(e) => {
+      warn!("could not verify ticket: {:?}", e);
+      None
+    }
+  }
 }
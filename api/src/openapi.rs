@@ -0,0 +1,144 @@
+use actix_web::{get, HttpResponse, Responder};
+
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+
+use yata_common::health::{ComponentHealth, Health, Status};
+
+use crate::api_version::API_PREFIX;
+use crate::elements::{Changes, Element, ElementStatus, SharedElement};
+use crate::events::EventKind;
+use crate::formats::{Entry, Format, ImportError, ImportFormat,
+  ImportReport};
+use crate::history::ElementEvent;
+use crate::inputs::{NewAccessToken, ShareInvitation, SingleContent,
+  SingleStatus};
+use crate::shares::{Permission, Share};
+use crate::tokens::{AccessToken, CreatedAccessToken, Scope,
+  StreamTicket};
+
+// the REST routes and the types they exchange. The CalDAV routes are
+// left out, OpenAPI can not describe WebDAV methods. Every route has
+// to be listed here, which a test makes sure of
+#[derive(OpenApi)]
+#[openapi(
+  info(title = "yata API"),
+  paths(
+    crate::routes::get_elements,
+    crate::routes::get_changes,
+    crate::routes::export,
+    crate::routes::import,
+    crate::routes::add_todo,
+    crate::routes::set_status,
+    crate::routes::delete_element,
+    crate::routes::empty_bin,
+    crate::routes::get_history,
+    crate::routes::get_activity,
+    crate::routes::undo,
+    crate::routes::delete_user,
+    crate::routes::get_events,
+    crate::routes::get_shares,
+    crate::routes::add_share,
+    crate::routes::delete_share,
+    crate::routes::get_access_tokens,
+    crate::routes::add_access_token,
+    crate::routes::delete_access_token,
+    crate::routes::add_ticket,
+    crate::routes::healthz,
+    crate::routes::readyz,
+    crate::routes::get_metrics,
+    crate::graphql::graphql,
+    crate::graphql::graphql_query,
+    get_openapi,
+    swagger_ui,
+  ),
+  components(schemas(
+    Element, ElementStatus, SharedElement, Changes, SingleContent,
+    SingleStatus, EventKind, ElementEvent, Entry, Format, ImportFormat,
+    ImportReport, ImportError, Permission, Share, ShareInvitation,
+    Scope, AccessToken, NewAccessToken, CreatedAccessToken,
+    StreamTicket, Health, ComponentHealth, Status,
+  )),
+  modifiers(&BearerAuth, &VersionedPaths),
+  security(("bearer" = [])),
+)]
+pub struct ApiDoc;
+
+// Keycloak's JWTs or personal access tokens
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    if let Some(components) = openapi.components.as_mut() {
+      let bearer = Http::new(HttpAuthScheme::Bearer);
+      components.add_security_scheme(
+        "bearer", SecurityScheme::Http(bearer),
+      );
+    }
+  }
+}
+
+// the routes are declared relative to their scope. Only the ones of
+// users are served below API_PREFIX, the operational ones are not
+// versioned
+struct VersionedPaths;
+
+static UNVERSIONED_PATHS: [&str; 5] =
+  ["/healthz", "/readyz", "/metrics", "/openapi.json", "/docs"];
+
+impl Modify for VersionedPaths {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let paths = std::mem::take(&mut openapi.paths.paths);
+
+    openapi.paths.paths = paths.into_iter()
+      .map(|(path, item)| match UNVERSIONED_PATHS.contains(&&*path) {
+        true => (path, item),
+        false => (format!("{}{}", API_PREFIX, path), item),
+      })
+      .collect();
+  }
+}
+
+#[utoipa::path(
+  get, path = "/openapi.json", tag = "operations", security(()),
+  responses((status = 200, description = "this document")),
+)]
+#[get("/openapi.json")]
+pub async fn get_openapi() -> impl Responder {
+  HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// only served if swagger_ui is configured. Swagger UI itself is
+// loaded from a CDN
+#[utoipa::path(
+  get, path = "/docs", tag = "operations", security(()),
+  responses(
+    (status = 200, description = "Swagger UI", body = String,
+      content_type = "text/html"),
+  ),
+)]
+#[get("/docs")]
+pub async fn swagger_ui() -> impl Responder {
+  HttpResponse::Ok()
+    .content_type("text/html; charset=utf-8")
+    .body(SWAGGER_UI)
+}
+
+static SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+  <head>
+    <title>yata API</title>
+    <link rel="stylesheet"
+      href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script
+      src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js">
+    </script>
+    <script>
+      SwaggerUIBundle({url: "/openapi.json", dom_id: "#swagger-ui"});
+    </script>
+  </body>
+</html>
+"##;
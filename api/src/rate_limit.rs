@@ -0,0 +1,157 @@
+use actix_web::{Error as ActixError, HttpMessage, HttpResponse,
+  ResponseError};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+
+use actix_service::Service;
+
+use futures::future::{Either, Future, FutureExt, ready};
+
+use yata_common::rate_limit::{ClientIp, Limits, Quota, TrustedProxies};
+
+use std::fmt;
+
+use crate::api_version::API_PREFIX;
+use crate::config::Config;
+use crate::middlewares::AuthenticatedUser;
+
+#[derive(Debug)]
+pub struct TooManyRequests(pub Quota);
+
+impl fmt::Display for TooManyRequests {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "too many requests, retry in {}s", self.0.reset_secs())
+  }
+}
+
+impl ResponseError for TooManyRequests {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::TOO_MANY_REQUESTS
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let mut response = HttpResponse::TooManyRequests();
+    response.header("Retry-After", self.0.reset_secs());
+
+    for (name, value) in self.0.headers() {
+      response.header(name, value);
+    }
+
+    response.body(self.to_string())
+  }
+}
+
+// only requests not coming in over TCP have no address
+#[derive(Debug)]
+pub struct UnknownClient;
+
+impl fmt::Display for UnknownClient {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "the address of the client is unknown")
+  }
+}
+
+impl ResponseError for UnknownClient {
+  fn status_code(&self) -> StatusCode {
+    StatusCode::BAD_REQUEST
+  }
+}
+
+// every request is limited per client address before it is
+// authenticated, so failed logins count as well. Authenticated
+// requests are limited per user on top. Clients are told about their
+// remaining quota per user on every limited response
+pub struct RateLimits {
+  per_client: Limits,
+  per_user: Limits,
+  trusted_proxies: TrustedProxies,
+}
+
+impl RateLimits {
+  pub fn new(config: &Config) -> RateLimits {
+    RateLimits {
+      per_client: Limits::new(&config.client_rate_limits),
+      per_user: Limits::new(&config.rate_limits),
+      trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+    }
+  }
+
+  // wraps the authentication, so it runs before it
+  pub fn limit_client<S, B>(&self, req: ServiceRequest, srv: &mut S)
+    -> impl Future<Output = Result<ServiceResponse<B>, ActixError>>
+    where S: Service<
+      Request = ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = ActixError,
+    >
+  {
+    let client_ip = match self.trusted_proxies
+      .client_ip(req.peer_addr(), req.headers())
+    {
+      Some(ip) => ClientIp(ip),
+      None => return Either::Left(ready(
+        Err(ActixError::from(UnknownClient))
+      )),
+    };
+
+    req.extensions_mut().insert(client_ip);
+
+    match acquire(&self.per_client, &req, &client_ip.key()) {
+      Some(Err(quota)) => Either::Left(ready(
+        Err(ActixError::from(TooManyRequests(quota)))
+      )),
+      _ => Either::Right(srv.call(req)),
+    }
+  }
+
+  // wrapped by the authentication, so it runs after it
+  pub fn limit<S, B>(&self, req: ServiceRequest, srv: &mut S)
+    -> impl Future<Output = Result<ServiceResponse<B>, ActixError>>
+    where S: Service<
+      Request = ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = ActixError,
+    >
+  {
+    let user = req.extensions().get::<AuthenticatedUser>()
+      .map(|user| format!("user:{}", user.username));
+
+    // limit_client rejected the request if the client is unknown
+    let key = match user.or_else(|| {
+      req.extensions().get::<ClientIp>().map(|ip| ip.key())
+    }) {
+      Some(key) => key,
+      None => return Either::Left(ready(
+        Err(ActixError::from(UnknownClient))
+      )),
+    };
+
+    let quota = match acquire(&self.per_user, &req, &key) {
+      Some(Ok(quota)) => Some(quota),
+      Some(Err(quota)) => return Either::Left(ready(
+        Err(ActixError::from(TooManyRequests(quota)))
+      )),
+      None => None,
+    };
+
+    Either::Right(srv.call(req).map(move |res| {
+      res.map(|mut res| {
+        if let Some(quota) = &quota {
+          quota.insert_headers(res.headers_mut());
+        }
+        res
+      })
+    }))
+  }
+}
+
+// rules name the routes without API_PREFIX, they apply to the
+// deprecated routes at the root as well
+fn acquire(limits: &Limits, req: &ServiceRequest, key: &str)
+  -> Option<Result<Quota, Quota>>
+{
+  let pattern = req.match_pattern().unwrap_or_default();
+  let route = pattern.strip_prefix(API_PREFIX).unwrap_or(&pattern);
+
+  limits.acquire(req.method().as_str(), route, key)
+}
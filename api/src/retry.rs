@@ -0,0 +1,57 @@
+use actix_web::rt::time;
+
+use tracing::{info, warn};
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+static BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+// calls f until it succeeds or all attempts failed, waiting twice as
+// long after every failure, but never longer than max_delay
+pub async fn with_backoff<F, Fut, T, E>(
+  what: &str,
+  attempts: u32,
+  max_delay: Duration,
+  mut f: F,
+) -> Result<T, E>
+  where F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Debug,
+{
+  let mut attempt = 1;
+
+  loop {
+    match f().await {
+      Ok(res) => {
+        if attempt > 1 {
+          info!(what, attempt, "succeeded after retrying");
+        }
+        return Ok(res);
+      },
+      Err(e) if attempt >= attempts => return Err(e),
+      Err(e) => {
+        let delay = backoff(attempt, max_delay);
+
+        warn!(
+          what,
+          attempt,
+          attempts,
+          retry_in_secs = delay.as_secs_f64(),
+          error = ?e,
+          "failed, retrying",
+        );
+
+        time::delay_for(delay).await;
+        attempt += 1;
+      }
+    }
+  }
+}
+
+pub fn backoff(attempt: u32, max_delay: Duration) -> Duration {
+  2u32.checked_pow(attempt.saturating_sub(1))
+    .and_then(|factor| BACKOFF_BASE.checked_mul(factor))
+    .map_or(max_delay, |delay| delay.min(max_delay))
+}
@@ -1,107 +1,1145 @@
-use actix_web::{get, post, put, delete, web, HttpResponse, Responder};
+use actix_web::{get, post, put, delete, web, Error as ActixError,
+  HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::IfMatch;
+use actix_web::web::Bytes;
 
-use mongodb::Collection;
-use mongodb::bson::{doc, to_bson};
+use mongodb::bson::{Document, doc, to_bson};
 use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions,
+  ReturnDocument};
 
-use futures::stream::StreamExt;
+use futures::future::ready;
+use futures::stream::{self, StreamExt};
 
+use jwks_client::keyset::KeyStore;
+
+use yata_common::health::{ComponentHealth, Health, Status};
+use yata_common::metrics::Metrics;
+
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::slice;
+use std::sync::Arc;
+
+use crate::{to_mongodb_entry, to_mongodb_import_entry,
+  to_mongodb_token_entry};
+use crate::inputs::{ActivityQuery, ChangesQuery, ExportQuery,
+  ImportQuery, SingleContent, SingleStatus, ShareInvitation,
+  NewAccessToken};
+use crate::inputs::{ElementPath, SharePath, UserPath};
+use crate::elements::{Element, ElementStatus, SyncToken};
+use crate::errors::StoreError;
+use crate::events::{EventBus, EventKind};
+use crate::formats::{Columns, Entry, ImportError, ImportReport};
+use crate::history::{Change, ElementEvent};
+use crate::middlewares::{AuthenticatedUser, Credential};
+use crate::shares::Share;
+use crate::store::Store;
+use crate::tokens::{AccessToken, CreatedAccessToken, ServiceToken,
+  generate_token, hash_token, valid_expiry};
+use crate::versions::{etag, if_match, matches, version_clause,
+  version_of};
 
-use crate::to_mongodb_entry;
-use crate::inputs::{SingleContent, SingleStatus};
-use crate::elements::{Element, ElementStatus};
+static ACTIVITY_LIMIT: i64 = 50;
+static ACTIVITY_MAX_LIMIT: i64 = 200;
 
+#[utoipa::path(
+  get, path = "/{user}", tag = "elements",
+  params(UserPath),
+  responses(
+    (status = 200, description = "the elements of user and the ones \
+      shared with user", body = [Element]),
+  ),
+)]
 #[get("/{user}")]
 pub async fn get_elements(
   web::Path((user,)): web::Path<(String,)>,
-  collection: web::Data<Collection>) -> impl Responder
+  store: web::Data<Store>) -> impl Responder
 {
-  let filter = doc!{"user": user};
-  let mut cursor = collection.find(filter, None).await.unwrap();
+  let res = store.readable_elements(&user).await.unwrap();
 
-  let mut res: Vec<Element> = Vec::new();
+  HttpResponse::Ok().json(res)
+}
 
-  while let Some(result) = cursor.next().await {
-    res.push(Element::try_from(result.unwrap()).unwrap());
-  }
+// the elements of user changed since the token returned by the last
+// call, for clients keeping an offline copy
+#[utoipa::path(
+  get, path = "/{user}/changes", tag = "elements",
+  params(UserPath, ChangesQuery),
+  responses(
+    (status = 200, description = "the changes", body = Changes),
+    (status = 400, description = "since is not a valid token"),
+  ),
+)]
+#[get("/{user}/changes")]
+pub async fn get_changes(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  query: web::Query<ChangesQuery>) -> impl Responder
+{
+  let since = match &query.since {
+    Some(since) => match SyncToken::parse(since, &user) {
+      Some(since) => Some(since),
+      None => return HttpResponse::BadRequest().finish(),
+    },
+    None => None,
+  };
+
+  let res = store.changes(&user, since.as_ref()).await.unwrap();
 
   HttpResponse::Ok().json(res)
 }
 
+// every element of user, streamed so large exports do not have to be
+// held in memory. Elements shared with user are not part of it
+#[utoipa::path(
+  get, path = "/{user}/export", tag = "elements",
+  params(UserPath, ExportQuery),
+  responses(
+    (status = 200, description = "the elements as JSON, CSV or \
+      iCalendar", body = String),
+  ),
+)]
+#[get("/{user}/export")]
+pub async fn export(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  query: web::Query<ExportQuery>) -> impl Responder
+{
+  let format = query.format;
+
+  let options = FindOptions::builder().sort(doc!{"created": 1}).build();
+
+  let cursor = store.elements.find(doc!{"user": &user}, options)
+    .await
+    .unwrap();
+
+  let entries = cursor.enumerate().map(move |(i, doc)| {
+    let element = Element::try_from(doc?)?;
+    Ok::<_, StoreError>(format.entry(&Entry::from(element), i == 0))
+  });
+
+  let body = stream::once(ready(Ok(format.header())))
+    .chain(entries)
+    .chain(stream::once(ready(Ok(format.footer()))))
+    .map(|chunk| chunk
+      .map(Bytes::from)
+      .map_err(|e| ErrorInternalServerError(format!("{:?}", e))));
+
+  HttpResponse::Ok()
+    .content_type(format.content_type())
+    .header("Content-Disposition", format!(
+      "attachment; filename=\"yata.{}\"", format.extension(),
+    ))
+    .streaming(body)
+}
+
+// creates an element for every entry of the body. If an entry can
+// not be read, nothing is created and the errors are reported. A dry
+// run only reports what would be created. The import is a single
+// change, so it can be undone as a whole
+#[utoipa::path(
+  post, path = "/{user}/import", tag = "elements",
+  params(UserPath, ImportQuery),
+  request_body(
+    content = String, description = "the entries in the format",
+  ),
+  responses(
+    (status = 200, description = "the created elements",
+      body = ImportReport),
+    (status = 400, description = "invalid columns"),
+    (status = 422, description = "nothing was created",
+      body = ImportReport),
+  ),
+)]
+#[post("/{user}/import")]
+pub async fn import(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>,
+  query: web::Query<ImportQuery>,
+  body: String) -> impl Responder
+{
+  let mut report = ImportReport {
+    dry_run: query.dry_run,
+    created: Vec::new(),
+    errors: Vec::new(),
+  };
+
+  let columns = match &query.columns {
+    Some(columns) => match columns.parse::<Columns>() {
+      Ok(columns) => columns,
+      Err(_) => return HttpResponse::BadRequest().finish(),
+    },
+    None => Columns::default(),
+  };
+
+  let decoded = match query.format.decode(&body, &columns) {
+    Ok(decoded) => decoded,
+    Err(e) => {
+      report.errors.push(ImportError {
+        line: None,
+        error: e.to_string(),
+      });
+      return HttpResponse::UnprocessableEntity().json(report);
+    },
+  };
+
+  let mut entries: Vec<Entry> = Vec::new();
+
+  for decoded in decoded {
+    match decoded.entry {
+      Ok(entry) => entries.push(entry),
+      Err(error) => report.errors.push(ImportError {
+        line: Some(decoded.line),
+        error,
+      }),
+    }
+  }
+
+  if !report.errors.is_empty() {
+    return HttpResponse::UnprocessableEntity().json(report);
+  }
+
+  if query.dry_run || entries.is_empty() {
+    report.created = entries;
+    return HttpResponse::Ok().json(report);
+  }
+
+  let last = store.reserve_revisions(&user, entries.len() as i64)
+    .await
+    .unwrap();
+  let first = last - entries.len() as i64 + 1;
+
+  let docs: Vec<Document> = entries.into_iter().zip(first..)
+    .map(|(entry, revision)| {
+      let mut doc = to_mongodb_import_entry(entry, user.clone())
+        .unwrap();
+      doc.insert("_id", ObjectId::new());
+      doc.insert("revision", revision);
+      doc
+    })
+    .collect();
+
+  store.elements.insert_many(docs.clone(), None).await.unwrap();
+
+  store.record_created(&Change::new(&user), &docs).await.unwrap();
+
+  events.publish_created(&store, &user, &docs).await;
+
+  for doc in docs {
+    report.created.push(Entry::from(Element::try_from(doc).unwrap()));
+  }
+
+  HttpResponse::Ok().json(report)
+}
+
+#[utoipa::path(
+  post, path = "/{user}/add_todo", tag = "elements",
+  params(UserPath),
+  request_body = SingleContent,
+  responses(
+    (status = 200, description = "the created element", body = Element,
+      headers(("ETag" = String))),
+  ),
+)]
 #[post("/{user}/add_todo")]
 pub async fn add_todo(
   web::Path((user,)): web::Path<(String,)>,
-  collection: web::Data<Collection>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>,
   todo: web::Json<SingleContent>) -> impl Responder
 {
-  let insert = to_mongodb_entry(todo.into_inner(), user).unwrap();
+  let todo = todo.into_inner();
+  let inserted_elem = insert_todo(&store, &events, &user, todo).await;
+
+  let element = Element::try_from(inserted_elem).unwrap();
+
+  HttpResponse::Ok().set(etag(element.version())).json(element)
+}
 
-  let id = collection.insert_one(insert, None)
+// adds todo to the list of user and returns the stored element
+pub async fn insert_todo(
+  store: &Store,
+  events: &EventBus,
+  user: &str,
+  todo: SingleContent) -> Document
+{
+  let revision = store.next_revision(user).await.unwrap();
+
+  let mut insert = to_mongodb_entry(todo, String::from(user)).unwrap();
+  insert.insert("revision", revision);
+
+  let id = store.elements.insert_one(insert, None)
     .await
     .unwrap()
     .inserted_id;
 
   let filter = doc!{"_id": id};
 
-  let inserted_elem = collection.find_one(filter, None)
+  let inserted_elem = store.elements.find_one(filter, None)
     .await
     .unwrap()
     .unwrap();
 
-  HttpResponse::Ok().json(Element::try_from(inserted_elem).unwrap())
+  store.record(
+    &Change::new(user), EventKind::Created, None, Some(&inserted_elem),
+  ).await.unwrap();
+
+  events.publish(store, EventKind::Created, &inserted_elem).await;
+
+  inserted_elem
+}
+
+// the If-Match header of req, requests with an invalid one are bad
+pub fn if_match_header(req: &HttpRequest)
+  -> Result<Option<IfMatch>, HttpResponse>
+{
+  if_match(req).map_err(|_| HttpResponse::BadRequest().finish())
+}
+
+// the element matching filter, if it exists and if_match allows
+// changing it. For conditional requests, filter is narrowed to the
+// checked version, so the element can not change in between
+pub async fn conditional_element(
+  if_match: Option<&IfMatch>,
+  store: &Store,
+  filter: &mut Document) -> Result<Document, HttpResponse>
+{
+  let current = store.elements.find_one(filter.clone(), None)
+    .await
+    .map_err(store_error)?
+    .ok_or_else(|| HttpResponse::NotFound().finish())?;
+
+  if let Some(if_match) = if_match {
+    let version = version_of(&current);
+
+    if !matches(if_match, version) {
+      return Err(HttpResponse::PreconditionFailed().finish());
+    }
+
+    filter.insert("version", version_clause(version));
+  }
+
+  Ok(current)
+}
+
+// the response to a request that failed because of the database
+pub fn store_error<E: Into<StoreError>>(e: E) -> HttpResponse {
+  e.into().error_response()
+}
+
+// the element matching filter was gone when it was changed, either
+// it was deleted or, for conditional requests, updated concurrently
+pub fn vanished(filter: &Document) -> HttpResponse {
+  if filter.contains_key("version") {
+    HttpResponse::PreconditionFailed().finish()
+  } else {
+    HttpResponse::NotFound().finish()
+  }
 }
 
+#[utoipa::path(
+  put, path = "/{user}/{id}/status", tag = "elements",
+  params(
+    ElementPath,
+    ("If-Match" = Option<String>, Header,
+      description = "ETag the element is expected to have"),
+  ),
+  request_body = SingleStatus,
+  responses(
+    (status = 200, description = "the status was set",
+      headers(("ETag" = String))),
+    (status = 404, description = "no such element"),
+    (status = 412, description = "the element changed since"),
+  ),
+)]
 #[put("/{user}/{id}/status")]
 pub async fn set_status(
+  req: HttpRequest,
   web::Path((user, id)): web::Path<(String, String)>,
-  collection: web::Data<Collection>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>,
   new_status: web::Json<SingleStatus>) -> impl Responder
 {
-  let id = ObjectId::with_string(&id).unwrap();
+  let id = match ObjectId::with_string(&id) {
+    Ok(id) => id,
+    Err(_) => return HttpResponse::NotFound().finish(),
+  };
 
-  let filter = doc!{
-    "_id": id,
-    "user": user,
+  let mut filter = match store.writable_filter(&user, id).await {
+    Ok(filter) => filter,
+    Err(e) => return store_error(e),
   };
 
+  let if_match = match if_match_header(&req) {
+    Ok(if_match) => if_match,
+    Err(res) => return res,
+  };
+
+  let current = match conditional_element(
+    if_match.as_ref(), &store, &mut filter,
+  ).await {
+    Ok(current) => current,
+    Err(res) => return res,
+  };
+
+  let status = new_status.into_inner().status;
+
+  match change_status(&store, &events, &user, &current, filter, status)
+    .await
+  {
+    Ok(updated) =>
+      HttpResponse::Ok().set(etag(version_of(&updated))).finish(),
+    Err(res) => res,
+  }
+}
+
+// sets the status of current, the element matching filter, on behalf
+// of user and returns the updated element
+pub async fn change_status(
+  store: &Store,
+  events: &EventBus,
+  user: &str,
+  current: &Document,
+  filter: Document,
+  status: ElementStatus) -> Result<Document, HttpResponse>
+{
+  // revisions are counted per owner, who is not user for elements
+  // shared with user
+  let owner = current.get_str("user").map_err(store_error)?;
+
+  let revision = store.next_revision(owner).await.map_err(store_error)?;
+
   let update = doc!{
-    "$set": {"status": to_bson(&new_status.status).unwrap()}
+    "$set": {
+      "status": to_bson(&status).map_err(store_error)?,
+      "revision": revision,
+    },
+    "$inc": {"version": 1i64},
   };
 
-  collection.update_one(filter, update, None).await.unwrap();
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
 
-  HttpResponse::Ok().finish()
+  let updated = store.elements
+    .find_one_and_update(filter.clone(), update, options)
+    .await
+    .map_err(store_error)?;
+
+  let updated = updated.ok_or_else(|| vanished(&filter))?;
+
+  store.record(
+    &Change::new(user),
+    EventKind::Updated,
+    Some(current),
+    Some(&updated),
+  ).await.map_err(store_error)?;
+
+  events.publish(store, EventKind::Updated, &updated).await;
+
+  Ok(updated)
 }
 
+#[utoipa::path(
+  delete, path = "/{user}/{id}", tag = "elements",
+  params(
+    ElementPath,
+    ("If-Match" = Option<String>, Header,
+      description = "ETag the element is expected to have"),
+  ),
+  responses(
+    (status = 200, description = "the element was deleted"),
+    (status = 404, description = "no such element"),
+    (status = 412, description = "the element changed since"),
+  ),
+)]
 #[delete("/{user}/{id}")]
 pub async fn delete_element(
+  req: HttpRequest,
   web::Path((user, id)): web::Path<(String, String)>,
-  collection: web::Data<Collection>) -> impl Responder
+  store: web::Data<Store>,
+  events: web::Data<EventBus>) -> impl Responder
 {
-  let id = ObjectId::with_string(&id).unwrap();
+  let id = match ObjectId::with_string(&id) {
+    Ok(id) => id,
+    Err(_) => return HttpResponse::NotFound().finish(),
+  };
 
-  let filter = doc!{
-    "_id": id,
-    "user": user,
+  let filter = match store.writable_filter(&user, id).await {
+    Ok(filter) => filter,
+    Err(e) => return store_error(e),
+  };
+
+  let if_match = match if_match_header(&req) {
+    Ok(if_match) => if_match,
+    Err(res) => return res,
   };
 
-  collection.delete_one(filter, None).await.unwrap();
+  let deleted = delete_matching(
+    if_match.as_ref(), &store, &events, &user, filter,
+  ).await;
 
-  HttpResponse::Ok().finish()
+  match deleted {
+    Ok(_) => HttpResponse::Ok().finish(),
+    Err(res) => res,
+  }
+}
+
+// deletes the element matching filter on behalf of user, if if_match
+// allows it, and returns it
+pub async fn delete_matching(
+  if_match: Option<&IfMatch>,
+  store: &Store,
+  events: &EventBus,
+  user: &str,
+  mut filter: Document) -> Result<Document, HttpResponse>
+{
+  conditional_element(if_match, store, &mut filter).await?;
+
+  let deleted = store.elements.find_one_and_delete(filter.clone(), None)
+    .await
+    .map_err(store_error)?
+    .ok_or_else(|| vanished(&filter))?;
+
+  // published before the shares are forgotten, so their members are
+  // told as well
+  events.publish(store, EventKind::Deleted, &deleted).await;
+
+  store.record(
+    &Change::new(user), EventKind::Deleted, Some(&deleted), None,
+  ).await.map_err(store_error)?;
+
+  let owner = deleted.get_str("user").map_err(store_error)?;
+  let id = deleted.get_object_id("_id").map_err(store_error)?.clone();
+
+  store.bury(owner, slice::from_ref(&id)).await.map_err(store_error)?;
+  store.forget_shared_elements(vec![id]).await.map_err(store_error)?;
+
+  Ok(deleted)
 }
 
+#[utoipa::path(
+  post, path = "/{user}/empty_bin", tag = "elements",
+  params(UserPath),
+  responses((status = 200, description = "the bin was emptied")),
+)]
 #[post("/{user}/empty_bin")]
 pub async fn empty_bin(
   web::Path((user,)): web::Path<(String,)>,
-  collection: web::Data<Collection>) -> impl Responder
+  store: web::Data<Store>,
+  events: web::Data<EventBus>) -> impl Responder
+{
+  purge_bin(&store, &events, &user).await;
+
+  HttpResponse::Ok().finish()
+}
+
+// deletes the elements in the bin of user for good and returns how
+// many there were
+pub async fn purge_bin(store: &Store, events: &EventBus, user: &str)
+  -> usize
 {
   let filter = doc!{
     "user": user,
     "status": to_bson(&ElementStatus::Deleted).unwrap(),
   };
 
-  collection.delete_many(filter, None).await.unwrap();
+  let mut cursor = store.elements.find(filter.clone(), None)
+    .await
+    .unwrap();
+
+  let mut deleted = Vec::new();
+
+  while let Some(result) = cursor.next().await {
+    deleted.push(result.unwrap());
+  }
+
+  let ids: Vec<ObjectId> = deleted.iter()
+    .map(|d| d.get_object_id("_id").unwrap().clone())
+    .collect();
+
+  store.elements.delete_many(filter, None).await.unwrap();
+
+  // a single change, so undo restores the whole bin
+  let change = Change::new(user);
+
+  for doc in &deleted {
+    store.record(&change, EventKind::Deleted, Some(doc), None)
+      .await
+      .unwrap();
+
+    events.publish(store, EventKind::Deleted, doc).await;
+  }
+
+  store.bury(user, &ids).await.unwrap();
+  store.forget_shared_elements(ids).await.unwrap();
+
+  deleted.len()
+}
+
+// the audit log of the element with id, readable by everyone the
+// element is shared with
+#[utoipa::path(
+  get, path = "/{user}/{id}/history", tag = "history",
+  params(ElementPath),
+  responses(
+    (status = 200, description = "the audit log",
+      body = [ElementEvent]),
+    (status = 404, description = "no such element"),
+  ),
+)]
+#[get("/{user}/{id}/history")]
+pub async fn get_history(
+  web::Path((user, id)): web::Path<(String, String)>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let id = match ObjectId::with_string(&id) {
+    Ok(id) => id,
+    Err(_) => return HttpResponse::NotFound().finish(),
+  };
+
+  let history = store.element_history(&id).await.unwrap();
+
+  let owner = match history.first() {
+    Some(event) => String::from(event.owner()),
+    None => return HttpResponse::NotFound().finish(),
+  };
+
+  let audience = store.audience(&owner, &id).await.unwrap();
+
+  if !audience.iter().any(|(member, _)| member == &user) {
+    return HttpResponse::NotFound().finish();
+  }
+
+  HttpResponse::Ok().json(history)
+}
+
+// the latest changes made by user or to the elements of user
+#[utoipa::path(
+  get, path = "/{user}/activity", tag = "history",
+  params(UserPath, ActivityQuery),
+  responses(
+    (status = 200, description = "the latest changes",
+      body = [ElementEvent]),
+  ),
+)]
+#[get("/{user}/activity")]
+pub async fn get_activity(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  query: web::Query<ActivityQuery>) -> impl Responder
+{
+  let limit = query.limit.unwrap_or(ACTIVITY_LIMIT)
+    .max(1)
+    .min(ACTIVITY_MAX_LIMIT);
+
+  HttpResponse::Ok().json(store.activity(&user, limit).await.unwrap())
+}
+
+// reverts the last change of user which was not undone yet, restoring
+// purged elements from the audit log. Nothing is reverted if one of
+// the elements was changed since. Shares of purged elements are gone
+// for good
+#[utoipa::path(
+  post, path = "/{user}/undo", tag = "history",
+  params(UserPath),
+  responses(
+    (status = 200, description = "the events recording the revert",
+      body = [ElementEvent]),
+    (status = 403, description = "an element may not be changed"),
+    (status = 404, description = "nothing to undo"),
+    (status = 409, description = "an element changed since"),
+  ),
+)]
+#[post("/{user}/undo")]
+pub async fn undo(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  events: web::Data<EventBus>) -> impl Responder
+{
+  let last = store.last_change(&user).await.unwrap();
+
+  let undone = match last.first() {
+    Some(event) => event.get_object_id("change").unwrap().clone(),
+    None => return HttpResponse::NotFound().finish(),
+  };
+
+  for event in &last {
+    let owner = event.get_str("owner").unwrap();
+    let id = event.get_object_id("element").unwrap();
+
+    if !store.may_write(&user, owner, id).await.unwrap() {
+      return HttpResponse::Forbidden().finish();
+    }
+
+    let current = store.elements.find_one(doc!{"_id": id.clone()}, None)
+      .await
+      .unwrap();
+
+    let unchanged = match (current, event.get_document("after").ok()) {
+      (Some(current), Some(after)) =>
+        version_of(&current) == version_of(after),
+      (None, None) => true,
+      _ => false,
+    };
+
+    if !unchanged {
+      return HttpResponse::Conflict().finish();
+    }
+  }
+
+  let change = Change::reverting(&user, undone.clone());
+  let mut reverted: Vec<ElementEvent> = Vec::new();
+
+  for event in &last {
+    let recorded = revert(&store, &events, &change, event)
+      .await
+      .unwrap();
+
+    if let Some(recorded) = recorded {
+      reverted.push(ElementEvent::try_from(recorded).unwrap());
+    }
+  }
+
+  store.mark_undone(&undone).await.unwrap();
+
+  HttpResponse::Ok().json(reverted)
+}
+
+// brings the element of event back to its state before the event and
+// returns the event recorded for it. Elements changed concurrently are
+// left alone
+async fn revert(
+  store: &Store,
+  events: &EventBus,
+  change: &Change,
+  event: &Document) -> Result<Option<Document>, StoreError>
+{
+  let owner = event.get_str("owner")?;
+  let id = event.get_object_id("element")?.clone();
+
+  let before = event.get_document("before").ok();
+  let after = event.get_document("after").ok();
+
+  let unchanged = |after: &Document| doc!{
+    "_id": id.clone(),
+    "version": version_clause(version_of(after)),
+  };
+
+  let (action, before, after) = match (before, after) {
+    (None, Some(after)) => {
+      let deleted = store.elements
+        .find_one_and_delete(unchanged(after), None)
+        .await?;
+
+      let deleted = match deleted {
+        Some(deleted) => deleted,
+        None => return Ok(None),
+      };
+
+      events.publish(store, EventKind::Deleted, &deleted).await;
+
+      store.bury(owner, slice::from_ref(&id)).await?;
+      store.forget_shared_elements(vec![id]).await?;
+
+      (EventKind::Deleted, Some(deleted), None)
+    },
+    (Some(before), Some(after)) => {
+      let mut restored = before.clone();
+      restored.insert("version", version_of(after) + 1);
+      restored.insert("revision", store.next_revision(owner).await?);
+
+      let previous = store.elements
+        .find_one_and_replace(unchanged(after), restored.clone(), None)
+        .await?;
+
+      let previous = match previous {
+        Some(previous) => previous,
+        None => return Ok(None),
+      };
+
+      events.publish(store, EventKind::Updated, &restored).await;
+
+      (EventKind::Updated, Some(previous), Some(restored))
+    },
+    (Some(before), None) => {
+      let mut restored = before.clone();
+      restored.insert("version", version_of(before) + 1);
+      restored.insert("revision", store.next_revision(owner).await?);
+
+      store.elements.insert_one(restored.clone(), None).await?;
+
+      // clients syncing after the purge get the element back as a
+      // change. Tombstones of members whose shares of it were
+      // forgotten stay
+      store.tombstones.delete_many(
+        doc!{"element": id, "user": owner}, None,
+      ).await?;
+
+      events.publish(store, EventKind::Created, &restored).await;
+
+      (EventKind::Created, None, Some(restored))
+    },
+    (None, None) => return Ok(None),
+  };
+
+  let recorded = store.record(
+    change, action, before.as_ref(), after.as_ref(),
+  ).await?;
+
+  Ok(Some(recorded))
+}
+
+// called by keycloak_proxy when the account of user is deleted. It
+// passes the token of user on and adds the service token, so users
+// can not delete themselves around the proxy
+#[utoipa::path(
+  delete, path = "/{user}", tag = "users",
+  params(
+    UserPath,
+    ("X-Service-Token" = String, Header,
+      description = "the service token of keycloak_proxy"),
+  ),
+  responses(
+    (status = 200, description = "everything of user was deleted"),
+    (status = 403, description = "not called by keycloak_proxy or \
+      with an access token"),
+  ),
+)]
+#[delete("/{user}")]
+pub async fn delete_user(
+  req: HttpRequest,
+  web::Path((user,)): web::Path<(String,)>,
+  authenticated: web::ReqData<AuthenticatedUser>,
+  service_token: web::Data<ServiceToken>,
+  store: web::Data<Store>) -> impl Responder
+{
+  if authenticated.credential != Credential::Keycloak
+    || !service_token.authorized(&req)
+  {
+    return HttpResponse::Forbidden().finish();
+  }
+
+  store.purge_user(&user).await.unwrap();
 
   HttpResponse::Ok().finish()
 }
+
+// server-sent events about every change of an element user can read.
+// Clients not keeping up are dropped and have to refetch. Browsers
+// authenticate with a ticket instead of the Authorization header
+#[utoipa::path(
+  get, path = "/{user}/events", tag = "elements",
+  params(
+    UserPath,
+    ("ticket" = Option<String>, Query,
+      description = "a ticket from POST /me/tickets"),
+  ),
+  responses(
+    (status = 200, description = "created, updated and deleted events \
+      with the element as data", body = String,
+      content_type = "text/event-stream"),
+  ),
+)]
+#[get("/{user}/events")]
+pub async fn get_events(
+  web::Path((user,)): web::Path<(String,)>,
+  events: web::Data<EventBus>) -> impl Responder
+{
+  HttpResponse::Ok()
+    .content_type("text/event-stream")
+    .header("Cache-Control", "no-cache")
+    .streaming(events.subscribe(&user).map(Ok::<_, ActixError>))
+}
+
+#[utoipa::path(
+  get, path = "/{user}/shares", tag = "shares",
+  params(UserPath),
+  responses(
+    (status = 200, description = "the shares of and with user",
+      body = [Share]),
+  ),
+)]
+#[get("/{user}/shares")]
+pub async fn get_shares(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let filter = doc!{"$or": [{"owner": &user}, {"member": &user}]};
+
+  HttpResponse::Ok().json(store.shares(filter).await.unwrap())
+}
+
+#[utoipa::path(
+  post, path = "/{user}/shares", tag = "shares",
+  params(UserPath),
+  request_body = ShareInvitation,
+  responses(
+    (status = 200, description = "the share was added"),
+    (status = 400, description = "invalid invitation"),
+    (status = 404, description = "no such element"),
+  ),
+)]
+#[post("/{user}/shares")]
+pub async fn add_share(
+  web::Path((user,)): web::Path<(String,)>,
+  store: web::Data<Store>,
+  invitation: web::Json<ShareInvitation>) -> impl Responder
+{
+  let invitation = invitation.into_inner();
+
+  if invitation.member == user {
+    return HttpResponse::BadRequest().finish();
+  }
+
+  let element = match invitation.element {
+    Some(id) => {
+      let id = match ObjectId::with_string(&id) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+      };
+
+      let filter = doc!{"_id": id.clone(), "user": &user};
+
+      let owned = store.elements.count_documents(filter, None)
+        .await
+        .unwrap();
+
+      if owned == 0 {
+        return HttpResponse::NotFound().finish();
+      }
+
+      Some(id)
+    },
+    None => None,
+  };
+
+  store.share(&user, &invitation.member, invitation.permission, element)
+    .await
+    .unwrap();
+
+  HttpResponse::Ok().finish()
+}
+
+// both the owner and the member can remove a share
+#[utoipa::path(
+  delete, path = "/{user}/shares/{id}", tag = "shares",
+  params(SharePath),
+  responses(
+    (status = 200, description = "the share was removed"),
+    (status = 400, description = "the id is not a share id"),
+  ),
+)]
+#[delete("/{user}/shares/{id}")]
+pub async fn delete_share(
+  web::Path((user, id)): web::Path<(String, String)>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let id = match ObjectId::with_string(&id) {
+    Ok(id) => id,
+    Err(_) => return HttpResponse::BadRequest().finish(),
+  };
+
+  let filter = doc!{
+    "_id": id,
+    "$or": [{"owner": &user}, {"member": &user}],
+  };
+
+  let deleted = store.shares.find_one_and_delete(filter, None)
+    .await
+    .unwrap();
+
+  if let Some(deleted) = deleted {
+    store.revoke(&Share::try_from(deleted).unwrap()).await.unwrap();
+  }
+
+  HttpResponse::Ok().finish()
+}
+
+#[utoipa::path(
+  get, path = "/me/tokens", tag = "tokens",
+  responses(
+    (status = 200, description = "the access tokens of the user",
+      body = [AccessToken]),
+  ),
+)]
+#[get("/me/tokens")]
+pub async fn get_access_tokens(
+  user: web::ReqData<AuthenticatedUser>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let res = store.access_tokens(&user.username).await.unwrap();
+
+  HttpResponse::Ok().json(res)
+}
+
+#[utoipa::path(
+  post, path = "/me/tokens", tag = "tokens",
+  request_body = NewAccessToken,
+  responses(
+    (status = 201, description = "the token, which is only returned \
+      once", body = CreatedAccessToken),
+    (status = 400, description = "no scopes or an expiry out of \
+      range"),
+  ),
+)]
+#[post("/me/tokens")]
+pub async fn add_access_token(
+  user: web::ReqData<AuthenticatedUser>,
+  store: web::Data<Store>,
+  new_token: web::Json<NewAccessToken>) -> impl Responder
+{
+  let new_token = new_token.into_inner();
+
+  if new_token.scopes.is_empty()
+    || !valid_expiry(new_token.expires_in_days)
+  {
+    return HttpResponse::BadRequest().finish();
+  }
+
+  let token = generate_token();
+
+  let insert = to_mongodb_token_entry(
+    new_token, user.username.clone(), hash_token(&token)
+  ).unwrap();
+
+  let id = store.tokens.insert_one(insert, None)
+    .await
+    .unwrap()
+    .inserted_id;
+
+  let inserted_token = store.tokens.find_one(doc!{"_id": id}, None)
+    .await
+    .unwrap()
+    .unwrap();
+
+  HttpResponse::Created().json(CreatedAccessToken {
+    token,
+    access_token: AccessToken::try_from(inserted_token).unwrap(),
+  })
+}
+
+#[utoipa::path(
+  delete, path = "/me/tokens/{id}", tag = "tokens",
+  params(("id" = String, Path, description = "the id of the token")),
+  responses(
+    (status = 200, description = "the token was revoked"),
+    (status = 404, description = "no such token"),
+  ),
+)]
+#[delete("/me/tokens/{id}")]
+pub async fn delete_access_token(
+  web::Path((id,)): web::Path<(String,)>,
+  user: web::ReqData<AuthenticatedUser>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let id = match ObjectId::with_string(&id) {
+    Ok(id) => id,
+    Err(_) => return HttpResponse::NotFound().finish(),
+  };
+
+  let filter = doc!{
+    "_id": id,
+    "user": &user.username,
+  };
+
+  let res = store.tokens.delete_one(filter, None).await.unwrap();
+
+  if res.deleted_count == 0 {
+    return HttpResponse::NotFound().finish();
+  }
+
+  HttpResponse::Ok().finish()
+}
+
+// a ticket for opening the event stream or the GraphQL subscriptions
+// of the user in a browser, which can not send the Authorization
+// header with them
+#[utoipa::path(
+  post, path = "/me/tickets", tag = "tokens",
+  responses(
+    (status = 201, description = "the ticket, usable once in a minute",
+      body = StreamTicket),
+  ),
+)]
+#[post("/me/tickets")]
+pub async fn add_ticket(
+  user: web::ReqData<AuthenticatedUser>,
+  store: web::Data<Store>) -> impl Responder
+{
+  let ticket = store.add_ticket(&user.username).await.unwrap();
+
+  HttpResponse::Created().json(ticket)
+}
+
+// the process is up and answering requests
+#[utoipa::path(
+  get, path = "/healthz", tag = "operations", security(()),
+  responses((status = 200, description = "up", body = Health)),
+)]
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+  HttpResponse::Ok().json(Health::new(BTreeMap::new()))
+}
+
+#[utoipa::path(
+  get, path = "/readyz", tag = "operations", security(()),
+  responses(
+    (status = 200, description = "ready", body = Health),
+    (status = 503, description = "a component is unavailable",
+      body = Health),
+  ),
+)]
+#[get("/readyz")]
+pub async fn readyz(
+  store: web::Data<Store>,
+  key_set: web::Data<Arc<KeyStore>>) -> impl Responder
+{
+  let mut components = BTreeMap::new();
+
+  components.insert("mongodb", match store.ping().await {
+    Ok(()) => ComponentHealth::ok(),
+    Err(e) => ComponentHealth::unavailable(format!("{:?}", e)),
+  });
+
+  components.insert("key_set", if key_set.keys_len() > 0 {
+    ComponentHealth::ok()
+  } else {
+    ComponentHealth::unavailable(String::from("no keys loaded"))
+  });
+
+  let health = Health::new(components);
+
+  match health.status {
+    Status::Ok => HttpResponse::Ok().json(health),
+    Status::Unavailable => HttpResponse::ServiceUnavailable().json(health),
+  }
+}
+
+// the domain gauges are refreshed in the background, see
+// ElementMetrics::spawn_refresh. Scrapes need the metrics_token as
+// bearer token
+#[utoipa::path(
+  get, path = "/metrics", tag = "operations",
+  responses(
+    (status = 200, description = "Prometheus metrics", body = String,
+      content_type = "text/plain"),
+    (status = 401, description = "not the metrics token"),
+  ),
+)]
+#[get("/metrics")]
+pub async fn get_metrics(
+  req: HttpRequest,
+  metrics: web::Data<Metrics>) -> impl Responder
+{
+  if !metrics.authorized(&req) {
+    return HttpResponse::Unauthorized().finish();
+  }
+
+  HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(metrics.render())
+}
@@ -0,0 +1,101 @@
+use serde_derive::{Serialize, Deserialize};
+
+use async_graphql::Enum;
+
+use utoipa::ToSchema;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::bson::from_bson;
+
+use std::convert::TryFrom;
+
+use crate::errors::ParseDocumentError;
+
+// ordered, so the strongest permission of several shares can be
+// taken with max
+#[derive(Serialize, Deserialize, Enum, ToSchema, Debug, Clone, Copy,
+  PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission { Viewer, Editor }
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct Share {
+  id: String,
+  owner: String,
+  member: String,
+  permission: Permission,
+  // None if the whole list of owner is shared
+  #[serde(skip_serializing_if = "Option::is_none")]
+  element: Option<String>,
+}
+
+impl Share {
+  pub fn owner(&self) -> &str {
+    &self.owner
+  }
+
+  pub fn member(&self) -> &str {
+    &self.member
+  }
+
+  pub fn permission(&self) -> Permission {
+    self.permission
+  }
+
+  pub fn element(&self) -> Option<&str> {
+    self.element.as_deref()
+  }
+}
+
+impl TryFrom<Document> for Share {
+  type Error = ParseDocumentError;
+
+  fn try_from(doc: Document) -> Result<Self, Self::Error> {
+    let id = doc.get_object_id("_id")?.to_hex();
+    let owner = String::from(doc.get_str("owner")?);
+    let member = String::from(doc.get_str("member")?);
+    let permission: Permission = from_bson(doc.get("permission")
+      .ok_or(ParseDocumentError::NotPresent)?.clone())?;
+
+    let element = match doc.get("element") {
+      Some(Bson::ObjectId(id)) => Some(id.to_hex()),
+      _ => None,
+    };
+
+    Ok(Share{
+      id,
+      owner,
+      member,
+      permission,
+      element,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use mongodb::bson::{doc, to_bson};
+  use mongodb::bson::oid::ObjectId;
+
+  #[test]
+  fn test_share_from_mongodb_document()
+    -> Result<(), ParseDocumentError>
+  {
+    let element = ObjectId::new();
+
+    let doc = doc! {
+      "_id": ObjectId::new(),
+      "owner": String::from("some owner"),
+      "member": String::from("some member"),
+      "permission": to_bson(&Permission::Editor).unwrap(),
+      "element": element.clone(),
+    };
+
+    let share = Share::try_from(doc)?;
+
+    assert_eq!(share.element(), Some(element.to_hex().as_str()));
+    assert_eq!(share.permission(), Permission::Editor);
+    Ok(())
+  }
+}
@@ -0,0 +1,866 @@
+use mongodb::{Collection, Database};
+use mongodb::bson::{Bson, Document, doc, to_bson};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions,
+  FindOptions, ReturnDocument, UpdateModifications, UpdateOptions};
+
+use futures::stream::StreamExt;
+
+use chrono::Duration;
+use chrono::offset::Utc;
+
+use std::convert::TryFrom;
+
+use crate::errors::{ParseDocumentError, StoreError};
+use crate::elements::{Changes, Element, SyncToken};
+use crate::events::EventKind;
+use crate::history::{Change, DELETED_ACTOR, ElementEvent};
+use crate::shares::{Permission, Share};
+use crate::tokens::{AccessToken, StreamTicket, TICKET_TTL_SECS,
+  generate_ticket, hash_token};
+
+// revisions reserved less than this long ago may still be written,
+// so sync tokens do not go past them
+static WRITE_TIMEOUT_SECS: i64 = 30;
+
+#[derive(Clone)]
+pub struct Store {
+  pub database: Database,
+  pub elements: Collection,
+  pub shares: Collection,
+  pub tokens: Collection,
+  pub tickets: Collection,
+  pub revisions: Collection,
+  pub tombstones: Collection,
+  pub element_events: Collection,
+}
+
+impl Store {
+  pub fn new(database: &Database) -> Store {
+    Store {
+      database: database.clone(),
+      elements: database.collection("yata_collection"),
+      shares: database.collection("yata_shares"),
+      tokens: database.collection("yata_tokens"),
+      tickets: database.collection("yata_tickets"),
+      revisions: database.collection("yata_revisions"),
+      tombstones: database.collection("yata_tombstones"),
+      element_events: database.collection("yata_element_events"),
+    }
+  }
+
+  pub async fn ping(&self) -> Result<(), StoreError> {
+    self.database.run_command(doc!{"ping": 1}, None).await?;
+    Ok(())
+  }
+
+  // number of elements per status over all users
+  pub async fn element_counts(&self)
+    -> Result<Vec<(String, i64)>, StoreError>
+  {
+    let pipeline = vec![
+      doc!{"$group": {"_id": "$status", "count": {"$sum": 1}}},
+    ];
+
+    let mut cursor = self.elements.aggregate(pipeline, None).await?;
+
+    let mut res = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      let group = result?;
+
+      let count = match group.get("count") {
+        Some(Bson::Int32(count)) => *count as i64,
+        Some(Bson::Int64(count)) => *count,
+        _ => 0,
+      };
+
+      res.push((group.get_str("_id")?.to_string(), count));
+    }
+
+    Ok(res)
+  }
+
+  pub async fn shares(&self, filter: Document)
+    -> Result<Vec<Share>, StoreError>
+  {
+    let mut cursor = self.shares.find(filter, None).await?;
+
+    let mut res: Vec<Share> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      res.push(Share::try_from(result?)?);
+    }
+
+    Ok(res)
+  }
+
+  pub async fn share(
+    &self,
+    owner: &str,
+    member: &str,
+    permission: Permission,
+    element: Option<ObjectId>) -> Result<(), StoreError>
+  {
+    let element = match element {
+      Some(id) => Bson::ObjectId(id),
+      None => Bson::Null,
+    };
+
+    let filter = doc!{
+      "owner": owner,
+      "member": member,
+      "element": element,
+    };
+
+    let update = doc!{
+      "$set": {"permission": to_bson(&permission)?}
+    };
+
+    let options = UpdateOptions::builder().upsert(true).build();
+
+    self.shares.update_one(filter.clone(), update, options).await?;
+
+    // the shared elements get a new revision, so member receives them
+    // with the next sync even if it already synced elements of owner
+    let mut covered = doc!{"user": owner};
+
+    if let Bson::ObjectId(id) = filter.get("element").unwrap() {
+      covered.insert("_id", id.clone());
+    }
+
+    let revision = self.next_revision(owner).await?;
+
+    self.elements.update_many(
+      covered, doc!{"$set": {"revision": revision}}, None,
+    ).await?;
+
+    Ok(())
+  }
+
+  // buries the elements member can not read anymore after share was
+  // removed, so the clients of member drop them with the next sync
+  pub async fn revoke(&self, share: &Share) -> Result<(), StoreError> {
+    let remaining = self.shares(doc!{
+      "owner": share.owner(),
+      "member": share.member(),
+    }).await?;
+
+    if remaining.iter().any(|s| s.element().is_none()) {
+      return Ok(());
+    }
+
+    let readable: Vec<String> = remaining.iter()
+      .filter_map(|s| s.element().map(String::from))
+      .collect();
+
+    let mut filter = doc!{"user": share.owner()};
+
+    if let Some(id) = share.element() {
+      filter.insert("_id", ObjectId::with_string(id)
+        .map_err(|_| ParseDocumentError::NotPresent)?);
+    }
+
+    let mut cursor = self.elements.find(filter, None).await?;
+
+    let mut ids: Vec<ObjectId> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      let id = result?.get_object_id("_id")?.clone();
+
+      if !readable.contains(&id.to_hex()) {
+        ids.push(id);
+      }
+    }
+
+    self.bury(share.member(), &ids).await
+  }
+
+  // what user can read, their own elements plus the ones shared with
+  // them
+  pub async fn read_access(&self, user: &str)
+    -> Result<ReadAccess, StoreError>
+  {
+    Ok(ReadAccess {
+      user: String::from(user),
+      shares: self.shares(doc!{"member": user}).await?,
+    })
+  }
+
+  // every element of user plus every element shared with user,
+  // the latter marked with the owner and the strongest permission
+  // the shares grant
+  pub async fn readable_elements(&self, user: &str)
+    -> Result<Vec<Element>, StoreError>
+  {
+    let access = self.read_access(user).await?;
+
+    let mut cursor = self.elements.find(access.filter(None), None)
+      .await?;
+
+    let mut res: Vec<Element> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      res.push(access.element(result?)?);
+    }
+
+    Ok(res)
+  }
+
+  // the owner of the element plus the members of all shares covering
+  // it, with the strongest permission they are granted
+  pub async fn audience(&self, owner: &str, id: &ObjectId)
+    -> Result<Vec<(String, Option<Permission>)>, StoreError>
+  {
+    self.audience_of(owner, doc!{
+      "owner": owner,
+      "$or": [{"element": Bson::Null}, {"element": id.clone()}],
+    }).await
+  }
+
+  // the users who can read every element of owner
+  pub async fn list_audience(&self, owner: &str)
+    -> Result<Vec<(String, Option<Permission>)>, StoreError>
+  {
+    self.audience_of(owner, doc!{"owner": owner, "element": Bson::Null})
+      .await
+  }
+
+  async fn audience_of(&self, owner: &str, filter: Document)
+    -> Result<Vec<(String, Option<Permission>)>, StoreError>
+  {
+    let shares = self.shares(filter).await?;
+
+    let mut res: Vec<(String, Option<Permission>)> =
+      vec![(String::from(owner), None)];
+
+    for share in shares.iter().filter(|s| s.member() != owner) {
+      let known = res.iter_mut()
+        .find(|(member, _)| member == share.member());
+
+      match known {
+        Some((_, permission)) =>
+          *permission = (*permission).max(Some(share.permission())),
+        None => res.push((
+          String::from(share.member()), Some(share.permission())
+        )),
+      }
+    }
+
+    Ok(res)
+  }
+
+  // whether user may change the element with id of owner, even if it
+  // does not exist anymore
+  pub async fn may_write(&self, user: &str, owner: &str, id: &ObjectId)
+    -> Result<bool, StoreError>
+  {
+    if user == owner {
+      return Ok(true);
+    }
+
+    let shares = self.shares(doc!{
+      "owner": owner,
+      "member": user,
+      "permission": to_bson(&Permission::Editor)?,
+      "$or": [{"element": Bson::Null}, {"element": id.clone()}],
+    }).await?;
+
+    Ok(!shares.is_empty())
+  }
+
+  // filter matching the element with id, if user owns it or is
+  // allowed to edit it through a share
+  pub async fn writable_filter(&self, user: &str, id: ObjectId)
+    -> Result<Document, StoreError>
+  {
+    let shares = self.shares(doc!{
+      "member": user,
+      "permission": to_bson(&Permission::Editor)?,
+    }).await?;
+
+    Ok(doc!{
+      "_id": id,
+      "$or": access_clauses(user, &shares),
+    })
+  }
+
+  // removes the shares of the purged elements with ids. Their members
+  // get tombstones of their own, the elements are not covered by the
+  // tombstones of the owner they get
+  pub async fn forget_shared_elements(&self, ids: Vec<ObjectId>)
+    -> Result<(), StoreError>
+  {
+    let filter = doc!{"element": {"$in": ids}};
+    let shares = self.shares(filter.clone()).await?;
+
+    self.shares.delete_many(filter, None).await?;
+
+    for share in &shares {
+      if let Some(id) = share.element() {
+        let id = ObjectId::with_string(id)
+          .map_err(|_| ParseDocumentError::NotPresent)?;
+        self.bury(share.member(), &[id]).await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  // the last revision handed out for the elements of user
+  pub async fn revision(&self, user: &str) -> Result<i64, StoreError> {
+    let doc = self.revisions.find_one(doc!{"_id": user}, None).await?;
+
+    match doc {
+      Some(doc) => Ok(doc.get_i64("revision")?),
+      None => Ok(0),
+    }
+  }
+
+  // the revision up to which every write to the elements of user is
+  // done: the counter, unless a write reserved a revision up to it and
+  // may still be running
+  pub async fn synced_revision(&self, user: &str)
+    -> Result<i64, StoreError>
+  {
+    let doc = match self.revisions.find_one(doc!{"_id": user}, None)
+      .await?
+    {
+      Some(doc) => doc,
+      None => return Ok(0),
+    };
+
+    let revision = doc.get_i64("revision")?;
+    let timeout = Utc::now() - Duration::seconds(WRITE_TIMEOUT_SECS);
+
+    let running = doc.get_array("reserved").into_iter().flatten()
+      .filter_map(|r| r.as_document())
+      .filter(|r| {
+        matches!(r.get_datetime("at"), Ok(at) if *at > timeout)
+      })
+      .filter_map(|r| r.get_i64("first").ok())
+      .min();
+
+    match running {
+      Some(first) => Ok((first - 1).min(revision)),
+      None => Ok(revision),
+    }
+  }
+
+  // bumps the revision counter of user by count and returns the last
+  // of the reserved revisions
+  //
+  // Writes are done after their revision was reserved, so every
+  // reservation is logged with the counter for the time a write may
+  // take. Syncs do not go past revisions which may still be written
+  pub async fn reserve_revisions(&self, user: &str, count: i64)
+    -> Result<i64, StoreError>
+  {
+    let now = Utc::now();
+    let timeout = now - Duration::seconds(WRITE_TIMEOUT_SECS);
+
+    let reservation = doc!{
+      "first": {"$subtract": ["$revision", count - 1]},
+      "at": now,
+    };
+
+    let update = UpdateModifications::Pipeline(vec![
+      doc!{"$set": {
+        "revision": {"$add": [{"$ifNull": ["$revision", 0i64]}, count]},
+      }},
+      doc!{"$set": {"reserved": {"$filter": {
+        "input": {"$concatArrays": [
+          {"$ifNull": ["$reserved", []]},
+          [reservation],
+        ]},
+        "cond": {"$gt": ["$$this.at", timeout]},
+      }}}},
+    ]);
+
+    let options = FindOneAndUpdateOptions::builder()
+      .upsert(true)
+      .return_document(ReturnDocument::After)
+      .build();
+
+    let doc = self.revisions
+      .find_one_and_update(doc!{"_id": user}, update, options)
+      .await?
+      .ok_or(ParseDocumentError::NotPresent)?;
+
+    Ok(doc.get_i64("revision")?)
+  }
+
+  pub async fn next_revision(&self, user: &str)
+    -> Result<i64, StoreError>
+  {
+    self.reserve_revisions(user, 1).await
+  }
+
+  // records that the elements with ids of user were purged, so clients
+  // syncing their changes learn about it
+  pub async fn bury(&self, user: &str, ids: &[ObjectId])
+    -> Result<(), StoreError>
+  {
+    if ids.is_empty() {
+      return Ok(());
+    }
+
+    let last = self.reserve_revisions(user, ids.len() as i64).await?;
+    let first = last - ids.len() as i64 + 1;
+
+    let tombstones = ids.iter().zip(first..).map(|(id, revision)| doc!{
+      "user": user,
+      "element": id.clone(),
+      "revision": revision,
+    });
+
+    self.tombstones.insert_many(tombstones, None).await?;
+
+    Ok(())
+  }
+
+  // the elements user can read changed after the token since plus
+  // the ids of the ones purged or not readable anymore since then.
+  // Without since, every element user can read is returned
+  pub async fn changes(&self, user: &str, since: Option<&SyncToken>)
+    -> Result<Changes, StoreError>
+  {
+    let shares = self.shares(doc!{"member": user}).await?;
+
+    let mut owners: Vec<&str> = vec![user];
+
+    for share in &shares {
+      if !owners.contains(&share.owner()) {
+        owners.push(share.owner());
+      }
+    }
+
+    let mut token = SyncToken::default();
+    let mut elements: Vec<Element> = Vec::new();
+    let mut deleted: Vec<String> = Vec::new();
+
+    for owner in owners {
+      // read first, so writes racing with the queries below are sent
+      // again with the next sync instead of being skipped
+      token = token.with(owner, self.synced_revision(owner).await?);
+
+      let since = since.and_then(|since| since.get(owner));
+      let readable = readable_clause(user, owner, &shares);
+
+      let mut filter = readable.clone();
+
+      if let Some(since) = since {
+        filter.insert("revision", doc!{"$gt": since});
+      }
+
+      let mut cursor = self.elements.find(filter, None).await?;
+
+      while let Some(result) = cursor.next().await {
+        let mut element = Element::try_from(result?)?;
+
+        if owner != user {
+          let permission = shared_permission(&shares, owner, &element);
+          element.mark_shared(String::from(owner), permission);
+        }
+
+        elements.push(element);
+      }
+
+      let since = match since {
+        Some(since) => since,
+        None => continue,
+      };
+
+      let mut filter = doc!{"user": owner, "revision": {"$gt": since}};
+
+      if let Ok(ids) = readable.get_document("_id") {
+        filter.insert("element", ids.clone());
+      }
+
+      let mut cursor = self.tombstones.find(filter, None).await?;
+
+      while let Some(result) = cursor.next().await {
+        deleted.push(result?.get_object_id("element")?.to_hex());
+      }
+    }
+
+    // elements shared again after they were revoked are readable
+    deleted.retain(|id| !elements.iter().any(|e| e.id() == id));
+
+    Ok(Changes {
+      elements,
+      deleted,
+      token: token.to_string(),
+    })
+  }
+
+  // appends the change of an element from before to after to the
+  // audit log and returns the written event
+  pub async fn record(
+    &self,
+    change: &Change,
+    action: EventKind,
+    before: Option<&Document>,
+    after: Option<&Document>) -> Result<Document, StoreError>
+  {
+    let mut event = element_event(change, action, before, after)?;
+
+    let id = self.element_events.insert_one(event.clone(), None)
+      .await?
+      .inserted_id;
+
+    event.insert("_id", id);
+
+    Ok(event)
+  }
+
+  // appends the creation of the elements in docs to the audit log,
+  // written at once
+  pub async fn record_created(&self, change: &Change, docs: &[Document])
+    -> Result<(), StoreError>
+  {
+    let events = docs.iter()
+      .map(|doc| {
+        element_event(change, EventKind::Created, None, Some(doc))
+      })
+      .collect::<Result<Vec<Document>, StoreError>>()?;
+
+    if !events.is_empty() {
+      self.element_events.insert_many(events, None).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn element_events(&self, filter: Document, options: FindOptions)
+    -> Result<Vec<ElementEvent>, StoreError>
+  {
+    let mut cursor = self.element_events.find(filter, options).await?;
+
+    let mut res: Vec<ElementEvent> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      res.push(ElementEvent::try_from(result?)?);
+    }
+
+    Ok(res)
+  }
+
+  // every event of the element with id, oldest first
+  pub async fn element_history(&self, id: &ObjectId)
+    -> Result<Vec<ElementEvent>, StoreError>
+  {
+    let options = FindOptions::builder()
+      .sort(doc!{"at": 1, "_id": 1})
+      .build();
+
+    self.element_events(doc!{"element": id.clone()}, options).await
+  }
+
+  // the latest events of elements owned or changed by user
+  pub async fn activity(&self, user: &str, limit: i64)
+    -> Result<Vec<ElementEvent>, StoreError>
+  {
+    let options = FindOptions::builder()
+      .sort(doc!{"at": -1, "_id": -1})
+      .limit(limit)
+      .build();
+
+    let filter = doc!{"$or": [{"owner": user}, {"actor": user}]};
+
+    self.element_events(filter, options).await
+  }
+
+  // the events of the latest change made by actor which was not undone
+  // yet, latest first. Changes reverting others can not be undone
+  pub async fn last_change(&self, actor: &str)
+    -> Result<Vec<Document>, StoreError>
+  {
+    let filter = doc!{
+      "actor": actor,
+      "undone": false,
+      "reverts": {"$exists": false},
+    };
+
+    let options = FindOneOptions::builder()
+      .sort(doc!{"at": -1, "_id": -1})
+      .build();
+
+    let last = self.element_events.find_one(filter, options).await?;
+
+    let last = match last {
+      Some(last) => last,
+      None => return Ok(Vec::new()),
+    };
+
+    let options = FindOptions::builder()
+      .sort(doc!{"at": -1, "_id": -1})
+      .build();
+
+    let filter = doc!{"change": last.get_object_id("change")?.clone()};
+    let mut cursor = self.element_events.find(filter, options).await?;
+
+    let mut res: Vec<Document> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      res.push(result?);
+    }
+
+    Ok(res)
+  }
+
+  pub async fn mark_undone(&self, change: &ObjectId)
+    -> Result<(), StoreError>
+  {
+    self.element_events.update_many(
+      doc!{"change": change.clone()},
+      doc!{"$set": {"undone": true}},
+      None,
+    ).await?;
+
+    Ok(())
+  }
+
+  // removes everything stored for user, used when the account is
+  // deleted
+  pub async fn purge_user(&self, user: &str) -> Result<(), StoreError> {
+    let shared = self.shares(doc!{"owner": user}).await?;
+
+    self.shares.delete_many(
+      doc!{"$or": [{"owner": user}, {"member": user}]}, None
+    ).await?;
+
+    for share in &shared {
+      self.revoke(share).await?;
+    }
+
+    self.elements.delete_many(doc!{"user": user}, None).await?;
+    self.tokens.delete_many(doc!{"user": user}, None).await?;
+    self.tickets.delete_many(doc!{"user": user}, None).await?;
+    self.revisions.delete_one(doc!{"_id": user}, None).await?;
+    self.tombstones.delete_many(doc!{"user": user}, None).await?;
+    self.element_events.delete_many(doc!{"owner": user}, None).await?;
+
+    // the changes to elements of others stay in their history, without
+    // telling who made them
+    self.element_events.update_many(
+      doc!{"actor": user},
+      doc!{"$set": {"actor": DELETED_ACTOR}},
+      None,
+    ).await?;
+
+    Ok(())
+  }
+
+  pub async fn access_tokens(&self, user: &str)
+    -> Result<Vec<AccessToken>, StoreError>
+  {
+    let mut cursor = self.tokens.find(doc!{"user": user}, None).await?;
+
+    let mut res: Vec<AccessToken> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+      res.push(AccessToken::try_from(result?)?);
+    }
+
+    Ok(res)
+  }
+
+  pub async fn add_ticket(&self, user: &str)
+    -> Result<StreamTicket, StoreError>
+  {
+    let now = Utc::now();
+
+    // nothing else removes expired tickets
+    self.tickets.delete_many(doc!{"expires": {"$lte": now}}, None)
+      .await?;
+
+    let ticket = generate_ticket();
+    let expires = now + Duration::seconds(TICKET_TTL_SECS);
+
+    self.tickets.insert_one(doc!{
+      "hash": hash_token(&ticket),
+      "user": user,
+      "expires": expires,
+    }, None).await?;
+
+    Ok(StreamTicket {
+      ticket,
+      expires,
+    })
+  }
+
+  // the user of the ticket, unless it expired. Tickets are deleted
+  // as they are used, so each opens a single stream
+  pub async fn use_ticket(&self, ticket: &str)
+    -> Result<Option<String>, StoreError>
+  {
+    let filter = doc!{
+      "hash": hash_token(ticket),
+      "expires": {"$gt": Utc::now()},
+    };
+
+    match self.tickets.find_one_and_delete(filter, None).await? {
+      Some(doc) => Ok(Some(String::from(doc.get_str("user")?))),
+      None => Ok(None),
+    }
+  }
+
+  // looks up the personal access token by its hash and records its
+  // usage. Expired tokens are treated as if they did not exist
+  pub async fn use_access_token(&self, token: &str)
+    -> Result<Option<AccessToken>, StoreError>
+  {
+    let filter = doc!{"hash": hash_token(token)};
+    let update = doc!{"$set": {"last_used": Utc::now()}};
+
+    let doc = self.tokens.find_one_and_update(filter, update, None)
+      .await?;
+
+    match doc {
+      Some(doc) => {
+        let token = AccessToken::try_from(doc)?;
+
+        if token.is_expired() {
+          return Ok(None);
+        }
+
+        Ok(Some(token))
+      },
+      None => Ok(None),
+    }
+  }
+}
+
+pub struct ReadAccess {
+  user: String,
+  // the shares with user
+  shares: Vec<Share>,
+}
+
+impl ReadAccess {
+  // the elements user can read, only those of owner if given
+  pub fn filter(&self, owner: Option<&str>) -> Document {
+    match owner {
+      Some(owner) => readable_clause(&self.user, owner, &self.shares),
+      None => doc!{"$or": access_clauses(&self.user, &self.shares)},
+    }
+  }
+
+  // user first, then the owners sharing with user by name
+  pub fn owners(&self) -> Vec<String> {
+    let mut owners: Vec<String> = self.shares.iter()
+      .map(|s| String::from(s.owner()))
+      .filter(|owner| *owner != self.user)
+      .collect();
+
+    owners.sort();
+    owners.dedup();
+    owners.insert(0, self.user.clone());
+
+    owners
+  }
+
+  // the element of doc, marked if it is shared with user
+  pub fn element(&self, doc: Document) -> Result<Element, StoreError> {
+    let owner = String::from(doc.get_str("user")?);
+
+    let mut element = Element::try_from(doc)?;
+
+    if owner != self.user {
+      let permission =
+        shared_permission(&self.shares, &owner, &element);
+      element.mark_shared(owner, permission);
+    }
+
+    Ok(element)
+  }
+}
+
+fn access_clauses(user: &str, shares: &[Share]) -> Vec<Document> {
+  let mut owners: Vec<String> = Vec::new();
+  let mut elements: Vec<ObjectId> = Vec::new();
+
+  for share in shares {
+    match share.element() {
+      Some(id) => if let Ok(id) = ObjectId::with_string(id) {
+        elements.push(id);
+      },
+      None => owners.push(String::from(share.owner())),
+    }
+  }
+
+  vec![
+    doc!{"user": user},
+    doc!{"user": {"$in": owners}},
+    doc!{"_id": {"$in": elements}},
+  ]
+}
+
+// the elements of owner user can read
+fn readable_clause(user: &str, owner: &str, shares: &[Share])
+  -> Document
+{
+  let shares: Vec<&Share> = shares.iter()
+    .filter(|s| s.owner() == owner)
+    .collect();
+
+  if owner == user || shares.iter().any(|s| s.element().is_none()) {
+    return doc!{"user": owner};
+  }
+
+  let ids: Vec<ObjectId> = shares.iter()
+    .filter_map(|s| ObjectId::with_string(s.element()?).ok())
+    .collect();
+
+  doc!{"user": owner, "_id": {"$in": ids}}
+}
+
+// the strongest permission the shares grant on element of owner
+fn shared_permission(shares: &[Share], owner: &str, element: &Element)
+  -> Permission
+{
+  shares.iter()
+    .filter(|s| s.owner() == owner)
+    .filter(|s| match s.element() {
+      Some(id) => id == element.id(),
+      None => true,
+    })
+    .map(|s| s.permission())
+    .max()
+    .unwrap_or(Permission::Viewer)
+}
+
+// the audit log entry of the change of an element from before to after
+fn element_event(
+  change: &Change,
+  action: EventKind,
+  before: Option<&Document>,
+  after: Option<&Document>) -> Result<Document, StoreError>
+{
+  let element = before.or(after)
+    .ok_or(ParseDocumentError::NotPresent)?;
+
+  let state = |doc: Option<&Document>| match doc {
+    Some(doc) => Bson::Document(doc.clone()),
+    None => Bson::Null,
+  };
+
+  let mut event = doc!{
+    "change": change.id.clone(),
+    "element": element.get_object_id("_id")?.clone(),
+    "owner": element.get_str("user")?,
+    "actor": &change.actor,
+    "action": to_bson(&action)?,
+    "at": Utc::now(),
+    "before": state(before),
+    "after": state(after),
+    "undone": false,
+  };
+
+  if let Some(reverts) = &change.reverts {
+    event.insert("reverts", reverts.clone());
+  }
+
+  Ok(event)
+}
@@ -0,0 +1,227 @@
+use serde_derive::{Serialize, Deserialize};
+
+use utoipa::ToSchema;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::bson::from_bson;
+
+use actix_web::HttpRequest;
+use actix_web::http::Method;
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+
+use rand::RngCore;
+
+use sha2::{Digest, Sha256};
+
+use std::convert::TryFrom;
+
+use crate::errors::ParseDocumentError;
+
+// personal access tokens are told apart from Keycloak's JWTs by
+// this prefix
+pub static TOKEN_PREFIX: &str = "yata_";
+
+// tokens expire after at most this many days, when they expire at all
+pub static TOKEN_MAX_DAYS: i64 = 3650;
+
+// tickets authenticate the streams browsers open without setting
+// headers, EventSource and WebSocket. They are passed as the ticket
+// query parameter, so they only live briefly, open a single stream
+// and only allow reading
+pub static TICKET_PREFIX: &str = "yata_ticket_";
+pub static TICKET_TTL_SECS: i64 = 60;
+
+// the header keycloak_proxy sends the service token in
+pub static SERVICE_TOKEN_HEADER: &str = "X-Service-Token";
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy,
+  PartialEq, Eq)]
+pub enum Scope { Read, Write }
+
+impl Scope {
+  pub fn required_for(method: &Method) -> Scope {
+    match method.as_str() {
+      // PROPFIND and REPORT only read, they are used by CalDAV clients
+      "GET" | "HEAD" | "OPTIONS" | "PROPFIND" | "REPORT" => Scope::Read,
+      _ => Scope::Write,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct AccessToken {
+  id: String,
+  user: String,
+  name: String,
+  scopes: Vec<Scope>,
+  created: DateTime<Utc>,
+  expires: Option<DateTime<Utc>>,
+  last_used: Option<DateTime<Utc>>,
+}
+
+impl AccessToken {
+  pub fn user(&self) -> &str {
+    &self.user
+  }
+
+  pub fn scopes(&self) -> &[Scope] {
+    &self.scopes
+  }
+
+  pub fn is_expired(&self) -> bool {
+    match self.expires {
+      Some(expires) => expires < Utc::now(),
+      None => false,
+    }
+  }
+}
+
+impl TryFrom<Document> for AccessToken {
+  type Error = ParseDocumentError;
+
+  fn try_from(doc: Document) -> Result<Self, Self::Error> {
+    let id = doc.get_object_id("_id")?.to_hex();
+    let user = String::from(doc.get_str("user")?);
+    let name = String::from(doc.get_str("name")?);
+    let scopes: Vec<Scope> = from_bson(
+      doc.get("scopes").ok_or(ParseDocumentError::NotPresent)?.clone(),
+    )?;
+    let created = *doc.get_datetime("created")?;
+
+    let expires = match doc.get("expires") {
+      Some(Bson::DateTime(dt)) => Some(*dt),
+      _ => None,
+    };
+
+    let last_used = match doc.get("last_used") {
+      Some(Bson::DateTime(dt)) => Some(*dt),
+      _ => None,
+    };
+
+    Ok(AccessToken{
+      id,
+      user,
+      name,
+      scopes,
+      created,
+      expires,
+      last_used,
+    })
+  }
+}
+
+// the plain token is only ever returned once, on creation
+#[derive(Serialize, ToSchema, Debug)]
+pub struct CreatedAccessToken {
+  pub token: String,
+  pub access_token: AccessToken,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct StreamTicket {
+  pub ticket: String,
+  pub expires: DateTime<Utc>,
+}
+
+fn random_hex() -> String {
+  let mut bytes = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut bytes);
+
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn generate_token() -> String {
+  format!("{}{}", TOKEN_PREFIX, random_hex())
+}
+
+pub fn generate_ticket() -> String {
+  format!("{}{}", TICKET_PREFIX, random_hex())
+}
+
+pub fn valid_expiry(expires_in_days: Option<i64>) -> bool {
+  match expires_in_days {
+    Some(days) => days >= 1 && days <= TOKEN_MAX_DAYS,
+    None => true,
+  }
+}
+
+// the shared secret of the calls of keycloak_proxy, see
+// Config::service_token. Without one no request is authorized
+#[derive(Clone, Debug)]
+pub struct ServiceToken {
+  hash: Option<String>,
+}
+
+impl ServiceToken {
+  pub fn new(token: Option<&str>) -> ServiceToken {
+    ServiceToken {
+      hash: token.map(hash_token),
+    }
+  }
+
+  // hashes are compared, so the time taken tells nothing about the
+  // token
+  pub fn authorized(&self, req: &HttpRequest) -> bool {
+    let token = req.headers().get(SERVICE_TOKEN_HEADER)
+      .and_then(|h| h.to_str().ok());
+
+    match (&self.hash, token) {
+      (Some(hash), Some(token)) => &hash_token(token) == hash,
+      _ => false,
+    }
+  }
+}
+
+pub fn hash_token(token: &str) -> String {
+  format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::test;
+
+  #[test]
+  fn test_generated_tokens_are_prefixed_and_hashed() {
+    let token = generate_token();
+
+    assert!(token.starts_with(TOKEN_PREFIX));
+    assert_ne!(token, generate_token());
+    assert_eq!(hash_token(&token), hash_token(&token));
+    assert_ne!(hash_token(&token), token);
+  }
+
+  #[test]
+  fn test_token_expiry_must_be_in_range() {
+    assert!(valid_expiry(None));
+    assert!(valid_expiry(Some(1)));
+    assert!(valid_expiry(Some(TOKEN_MAX_DAYS)));
+    assert!(!valid_expiry(Some(0)));
+    assert!(!valid_expiry(Some(-1)));
+    assert!(!valid_expiry(Some(TOKEN_MAX_DAYS + 1)));
+    assert!(!valid_expiry(Some(i64::MAX)));
+  }
+
+  #[test]
+  fn test_service_token_is_required() {
+    let call = |token: Option<&str>| {
+      let req = test::TestRequest::delete().uri("/api/v1/alice");
+
+      match token {
+        Some(token) => req.header("X-Service-Token", token),
+        None => req,
+      }.to_http_request()
+    };
+
+    let service_token = ServiceToken::new(Some("secret"));
+    assert!(service_token.authorized(&call(Some("secret"))));
+    assert!(!service_token.authorized(&call(Some("other"))));
+    assert!(!service_token.authorized(&call(None)));
+
+    let unset = ServiceToken::new(None);
+    assert!(!unset.authorized(&call(Some("secret"))));
+  }
+}
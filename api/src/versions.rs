@@ -0,0 +1,53 @@
+use actix_web::HttpRequest;
+use actix_web::error::ParseError;
+use actix_web::http::header::{ETag, EntityTag, Header, IfMatch};
+
+use mongodb::bson::{Bson, Document, doc};
+
+// elements are stored with a version counting their updates, so
+// clients can make sure they do not overwrite changes they have not
+// seen yet. Elements stored before versions were introduced have none
+// and count as version 0
+
+pub fn version_of(doc: &Document) -> i64 {
+  doc.get_i64("version").unwrap_or(0)
+}
+
+// matches the elements stored with version
+pub fn version_clause(version: i64) -> Bson {
+  match version {
+    0 => Bson::Document(doc!{"$exists": false}),
+    version => Bson::Int64(version),
+  }
+}
+
+pub fn etag(version: i64) -> ETag {
+  ETag(EntityTag::strong(version.to_string()))
+}
+
+// the If-Match header of req, None if the request is unconditional
+pub fn if_match(req: &HttpRequest)
+  -> Result<Option<IfMatch>, ParseError>
+{
+  if !req.headers().contains_key(IfMatch::name()) {
+    return Ok(None);
+  }
+
+  IfMatch::parse(req).map(Some)
+}
+
+// the condition If-Match: "version" stands for
+pub fn version_match(version: i64) -> IfMatch {
+  IfMatch::Items(vec![EntityTag::strong(version.to_string())])
+}
+
+// If-Match uses the strong comparison, weak tags never match
+pub fn matches(if_match: &IfMatch, version: i64) -> bool {
+  match if_match {
+    IfMatch::Any => true,
+    IfMatch::Items(tags) => {
+      let current = EntityTag::strong(version.to_string());
+      tags.iter().any(|tag| tag.strong_eq(&current))
+    },
+  }
+}
@@ -1,4 +1,898 @@
-#[test]
-fn test() {
-  assert!(true);
+// these tests run the routes against a MongoDB, which is taken from
+// YATA_API_TEST_MONGODB_URI. Without it they pass without running, CI
+// sets it. Every test works in a database of its own, which it drops
+// when it passes
+
+use actix_web::{test, web, App, HttpMessage};
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{AUTHORIZATION, ETAG, IF_MATCH,
+  IF_NONE_MATCH};
+
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use actix_web_httpauth::middleware::HttpAuthentication;
+
+use mongodb::Client;
+use mongodb::bson::{Document, doc};
+use mongodb::bson::oid::ObjectId;
+
+use serde_json::{Value, json};
+
+use std::env;
+use std::sync::Arc;
+
+use jwks_client::keyset::KeyStore;
+
+use yata_api::caldav::{self, DAV_ROOT};
+use yata_api::events::{EventBus, EventKind};
+use yata_api::graphql;
+use yata_api::history::{Change, DELETED_ACTOR};
+use yata_api::inputs::NewAccessToken;
+use yata_api::middlewares::{AuthenticatedUser, Credential, auth,
+  dav_auth, ticket_header};
+use yata_api::routes::*;
+use yata_api::shares::Permission;
+use yata_api::store::Store;
+use yata_api::to_mongodb_token_entry;
+use yata_api::tokens::{Scope, ServiceToken, generate_token,
+  hash_token};
+
+static USER: &str = "alice";
+
+async fn store() -> Option<Store> {
+  let uri = match env::var("YATA_API_TEST_MONGODB_URI") {
+    Ok(uri) => uri,
+    Err(_) => {
+      eprintln!("YATA_API_TEST_MONGODB_URI is not set, skipping");
+      return None;
+    }
+  };
+
+  let client = Client::with_uri_str(&uri).await.unwrap();
+  let database = client.database(
+    &format!("yata_test_{}", rand::random::<u32>())
+  );
+
+  Some(Store::new(&database))
+}
+
+async fn call(store: &Store, req: test::TestRequest)
+  -> ServiceResponse
+{
+  let mut app = test::init_service(
+    App::new()
+      .data(store.clone())
+      .data(EventBus::new())
+      .service(undo)
+      .service(add_todo)
+      .service(set_status)
+      .service(get_history)
+      .service(empty_bin)
+      .service(get_changes)
+      .service(add_share)
+      .service(delete_share)
+      .service(get_shares)
+      .service(delete_element)
+  ).await;
+
+  test::call_service(&mut app, req.to_request()).await
+}
+
+async fn add(store: &Store, content: &str) -> ObjectId {
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/add_todo", USER))
+    .set_json(&json!({"content": content}));
+
+  let res = call(store, req).await;
+  let element: Value = test::read_body_json(res).await;
+
+  ObjectId::with_string(element["id"].as_str().unwrap()).unwrap()
+}
+
+async fn set(store: &Store, id: &ObjectId, status: &str)
+  -> ServiceResponse
+{
+  let req = test::TestRequest::put()
+    .uri(&format!("/{}/{}/status", USER, id))
+    .set_json(&json!({"status": status}));
+
+  call(store, req).await
+}
+
+async fn undo_last(store: &Store) -> ServiceResponse {
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/undo", USER));
+
+  call(store, req).await
+}
+
+async fn changes(store: &Store, user: &str, since: Option<&str>)
+  -> Value
+{
+  let uri = match since {
+    Some(since) => format!("/{}/changes?since={}", user, since),
+    None => format!("/{}/changes", user),
+  };
+
+  let res = call(store, test::TestRequest::get().uri(&uri)).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  test::read_body_json(res).await
+}
+
+fn ids(changes: &Value, key: &str) -> Vec<String> {
+  changes[key].as_array().unwrap().iter()
+    .map(|e| String::from(e["id"].as_str().or(e.as_str()).unwrap()))
+    .collect()
+}
+
+// serves the CalDAV routes behind basic auth, as main does
+async fn dav(store: &Store, req: test::TestRequest) -> ServiceResponse {
+  let mut app = test::init_service(
+    App::new()
+      .data(store.clone())
+      .data(EventBus::new())
+      .service(web::scope(DAV_ROOT)
+        .wrap(HttpAuthentication::basic(dav_auth))
+        .configure(caldav::configure))
+  ).await;
+
+  test::call_service(&mut app, req.to_request()).await
+}
+
+// a personal access token of user with scopes
+async fn access_token(store: &Store, user: &str, scopes: Vec<Scope>)
+  -> String
+{
+  let token = generate_token();
+
+  let new_token = NewAccessToken {
+    name: String::from("caldav"),
+    scopes,
+    expires_in_days: None,
+  };
+
+  store.tokens.insert_one(
+    to_mongodb_token_entry(
+      new_token, String::from(user), hash_token(&token),
+    ).unwrap(),
+    None,
+  ).await.unwrap();
+
+  token
+}
+
+fn basic(user: &str, token: &str) -> Authorization<Basic> {
+  let password = Some(String::from(token));
+  Authorization::from(Basic::new(String::from(user), password))
+}
+
+fn vtodo(summary: &str) -> String {
+  format!(
+    "BEGIN:VCALENDAR\r\n\
+    VERSION:2.0\r\n\
+    BEGIN:VTODO\r\n\
+    UID:client-uid\r\n\
+    SUMMARY:{}\r\n\
+    END:VTODO\r\n\
+    END:VCALENDAR\r\n",
+    summary,
+  )
+}
+
+fn object_uri(user: &str) -> String {
+  format!("{}/{}/todos/client.ics", DAV_ROOT, user)
+}
+
+async fn put_object(store: &Store, token: &str, summary: &str)
+  -> ServiceResponse
+{
+  let req = test::TestRequest::put()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, token))
+    .set_payload(vtodo(summary));
+
+  dav(store, req).await
+}
+
+async fn element(store: &Store, id: &ObjectId) -> Option<Document> {
+  store.elements.find_one(doc!{"_id": id.clone()}, None).await.unwrap()
+}
+
+#[actix_rt::test]
+async fn test_undoing_empty_bin_restores_elements_and_tombstones() {
+  let Some(store) = store().await else { return };
+
+  let first = add(&store, "first").await;
+  let second = add(&store, "second").await;
+
+  for id in &[&first, &second] {
+    let res = set(&store, id, "Deleted").await;
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/empty_bin", USER));
+  assert_eq!(call(&store, req).await.status(), StatusCode::OK);
+
+  assert!(element(&store, &first).await.is_none());
+  assert_eq!(
+    store.tombstones.count_documents(doc!{"user": USER}, None)
+      .await
+      .unwrap(),
+    2,
+  );
+
+  let res = undo_last(&store).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  let reverted: Value = test::read_body_json(res).await;
+  assert_eq!(reverted.as_array().unwrap().len(), 2);
+  assert!(reverted.as_array().unwrap().iter()
+    .all(|event| event["action"] == "created"));
+
+  for id in &[&first, &second] {
+    let restored = element(&store, id).await.unwrap();
+    assert_eq!(restored.get_str("status").unwrap(), "Deleted");
+  }
+
+  // clients get the elements back as changes, not as deleted
+  assert_eq!(
+    store.tombstones.count_documents(doc!{"user": USER}, None)
+      .await
+      .unwrap(),
+    0,
+  );
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_undoing_set_status_restores_the_status() {
+  let Some(store) = store().await else { return };
+
+  let id = add(&store, "todo").await;
+  set(&store, &id, "Done").await;
+
+  let version = element(&store, &id).await.unwrap()
+    .get_i64("version")
+    .unwrap();
+
+  assert_eq!(undo_last(&store).await.status(), StatusCode::OK);
+
+  let restored = element(&store, &id).await.unwrap();
+  assert_eq!(restored.get_str("status").unwrap(), "Todo");
+
+  // the revert is a change of its own, so ETags of the undone state
+  // do not match anymore
+  assert_eq!(restored.get_i64("version").unwrap(), version + 1);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_undo_conflicts_with_later_changes() {
+  let Some(store) = store().await else { return };
+
+  let id = add(&store, "todo").await;
+  set(&store, &id, "Done").await;
+
+  // changed by someone else, so it is not the last change of USER
+  store.elements.update_one(
+    doc!{"_id": id.clone()},
+    doc!{"$set": {"content": "changed"}, "$inc": {"version": 1i64}},
+    None,
+  ).await.unwrap();
+
+  assert_eq!(undo_last(&store).await.status(), StatusCode::CONFLICT);
+
+  let current = element(&store, &id).await.unwrap();
+  assert_eq!(current.get_str("status").unwrap(), "Done");
+  assert_eq!(current.get_str("content").unwrap(), "changed");
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_reverting_changes_are_not_undone() {
+  let Some(store) = store().await else { return };
+
+  let first = add(&store, "first").await;
+  let second = add(&store, "second").await;
+  set(&store, &first, "Done").await;
+
+  assert_eq!(undo_last(&store).await.status(), StatusCode::OK);
+
+  // undoing again goes on with the creation of second instead of
+  // undoing the revert, which would make first done again
+  let res = undo_last(&store).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  let reverted: Value = test::read_body_json(res).await;
+  assert_eq!(reverted[0]["action"], "deleted");
+  assert_eq!(reverted[0]["element"], second.to_hex());
+  assert!(element(&store, &second).await.is_none());
+
+  let first = element(&store, &first).await.unwrap();
+  assert_eq!(first.get_str("status").unwrap(), "Todo");
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_invalid_ids_are_not_found() {
+  let Some(store) = store().await else { return };
+
+  let req = test::TestRequest::get()
+    .uri(&format!("/{}/not-an-id/history", USER));
+  assert_eq!(call(&store, req).await.status(), StatusCode::NOT_FOUND);
+
+  let req = test::TestRequest::put()
+    .uri(&format!("/{}/not-an-id/status", USER))
+    .set_json(&json!({"status": "Done"}));
+  assert_eq!(call(&store, req).await.status(), StatusCode::NOT_FOUND);
+
+  let req = test::TestRequest::delete()
+    .uri(&format!("/{}/not-an-id", USER));
+  assert_eq!(call(&store, req).await.status(), StatusCode::NOT_FOUND);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_changes_include_shared_elements() {
+  let Some(store) = store().await else { return };
+
+  let shared = add(&store, "shared").await;
+  let private = add(&store, "private").await;
+
+  let bob = changes(&store, "bob", None).await;
+  assert!(bob["elements"].as_array().unwrap().is_empty());
+  let token = bob["token"].as_str().unwrap().to_string();
+
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/shares", USER))
+    .set_json(&json!({
+      "member": "bob",
+      "permission": "Viewer",
+      "element": shared.to_hex(),
+    }));
+  assert_eq!(call(&store, req).await.status(), StatusCode::OK);
+
+  // the share was granted after the last sync of bob, still the
+  // element is sent as it became readable
+  let bob = changes(&store, "bob", Some(&token)).await;
+  assert_eq!(ids(&bob, "elements"), vec![shared.to_hex()]);
+  assert_eq!(bob["elements"][0]["shared"]["owner"], USER);
+  assert!(!ids(&bob, "elements").contains(&private.to_hex()));
+
+  let token = bob["token"].as_str().unwrap().to_string();
+  assert!(token.contains(&format!("{}:", USER)));
+
+  set(&store, &shared, "Done").await;
+
+  let bob = changes(&store, "bob", Some(&token)).await;
+  assert_eq!(bob["elements"][0]["status"], "Done");
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_revoked_shares_are_deleted_with_the_next_sync() {
+  let Some(store) = store().await else { return };
+
+  let id = add(&store, "shared").await;
+
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/shares", USER))
+    .set_json(&json!({"member": "bob", "permission": "Editor"}));
+  assert_eq!(call(&store, req).await.status(), StatusCode::OK);
+
+  let bob = changes(&store, "bob", None).await;
+  assert_eq!(ids(&bob, "elements"), vec![id.to_hex()]);
+  let token = bob["token"].as_str().unwrap().to_string();
+
+  let req = test::TestRequest::get()
+    .uri(&format!("/{}/shares", USER));
+  let res = call(&store, req).await;
+  let shares: Value = test::read_body_json(res).await;
+  let share = shares[0]["id"].as_str().unwrap();
+
+  let req = test::TestRequest::delete()
+    .uri(&format!("/{}/shares/{}", USER, share));
+  assert_eq!(call(&store, req).await.status(), StatusCode::OK);
+
+  let bob = changes(&store, "bob", Some(&token)).await;
+  assert!(bob["elements"].as_array().unwrap().is_empty());
+  assert_eq!(ids(&bob, "deleted"), vec![id.to_hex()]);
+
+  // the owner keeps the element
+  let alice = changes(&store, USER, None).await;
+  assert_eq!(ids(&alice, "elements"), vec![id.to_hex()]);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_sync_tokens_stay_behind_running_writes() {
+  let Some(store) = store().await else { return };
+
+  // reserved by a write which did not store its element yet
+  let reserved = store.next_revision(USER).await.unwrap();
+  store.next_revision(USER).await.unwrap();
+
+  let token = store.synced_revision(USER).await.unwrap();
+  assert_eq!(token, reserved - 1);
+
+  // so the element is sent once it is stored, even if the sync was
+  // done before
+  let id = add(&store, "late").await;
+  let since = format!("{}:{}", USER, token);
+  let alice = changes(&store, USER, Some(&since)).await;
+  assert_eq!(ids(&alice, "elements"), vec![id.to_hex()]);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_changes_reject_invalid_tokens() {
+  let Some(store) = store().await else { return };
+
+  let req = test::TestRequest::get()
+    .uri(&format!("/{}/changes?since=nope", USER));
+  assert_eq!(call(&store, req).await.status(), StatusCode::BAD_REQUEST);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_caldav_objects_are_put_read_and_deleted() {
+  let Some(store) = store().await else { return };
+  let scopes = vec![Scope::Read, Scope::Write];
+  let token = access_token(&store, USER, scopes).await;
+
+  let res = put_object(&store, &token, "from a client").await;
+  assert_eq!(res.status(), StatusCode::CREATED);
+  let created = res.headers().get(ETAG).unwrap().clone();
+
+  let req = test::TestRequest::get()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token));
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::OK);
+  assert_eq!(res.headers().get(ETAG), Some(&created));
+
+  let body = test::read_body(res).await;
+  let body = String::from_utf8(body.to_vec()).unwrap();
+  assert!(body.contains("SUMMARY:from a client"));
+  assert!(body.contains("UID:client-uid"));
+
+  let req = test::TestRequest::put()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token))
+    .header(IF_MATCH, created.clone())
+    .set_payload(vtodo("changed"));
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  assert_ne!(res.headers().get(ETAG), Some(&created));
+
+  let req = test::TestRequest::delete()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token));
+  assert_eq!(dav(&store, req).await.status(), StatusCode::NO_CONTENT);
+
+  let req = test::TestRequest::get()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token));
+  assert_eq!(dav(&store, req).await.status(), StatusCode::NOT_FOUND);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_caldav_preconditions_protect_existing_objects() {
+  let Some(store) = store().await else { return };
+  let scopes = vec![Scope::Read, Scope::Write];
+  let token = access_token(&store, USER, scopes).await;
+
+  assert_eq!(
+    put_object(&store, &token, "first").await.status(),
+    StatusCode::CREATED,
+  );
+
+  // a client creating the object does not overwrite it
+  let req = test::TestRequest::put()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token))
+    .header(IF_NONE_MATCH, "*")
+    .set_payload(vtodo("second"));
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+  // neither does a client which has not seen the current version
+  let req = test::TestRequest::put()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token))
+    .header(IF_MATCH, "\"41\"")
+    .set_payload(vtodo("second"));
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+  let req = test::TestRequest::delete()
+    .uri(&object_uri(USER))
+    .header(AUTHORIZATION, basic(USER, &token))
+    .header(IF_MATCH, "\"41\"");
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+  let stored = store.elements.find_one(doc!{"user": USER}, None)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(stored.get_str("content").unwrap(), "first");
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_caldav_needs_a_token_of_the_user_in_the_path() {
+  let Some(store) = store().await else { return };
+  let scopes = vec![Scope::Read, Scope::Write];
+  let token = access_token(&store, USER, scopes).await;
+
+  let get = |user: &str, path_user: &str, token: &str| {
+    test::TestRequest::get()
+      .uri(&object_uri(path_user))
+      .header(AUTHORIZATION, basic(user, token))
+  };
+
+  // a missing object, so passing auth answers 404
+  let res = dav(&store, get(USER, USER, &token)).await;
+  assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+  let res = dav(&store, get(USER, "bob", &token)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let res = dav(&store, get("bob", "bob", &token)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let res = dav(&store, get(USER, USER, "yata_not-a-token")).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let req = test::TestRequest::get().uri(&object_uri(USER));
+  let res = dav(&store, req).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+  assert!(res.headers().contains_key("WWW-Authenticate"));
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_caldav_read_only_tokens_can_not_put() {
+  let Some(store) = store().await else { return };
+  let token = access_token(&store, USER, vec![Scope::Read]).await;
+
+  let res = put_object(&store, &token, "not stored").await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let count = store.elements.count_documents(doc!{"user": USER}, None)
+    .await
+    .unwrap();
+  assert_eq!(count, 0);
+
+  store.database.drop(None).await.unwrap();
+}
+
+async fn graphql_query(store: &Store, user: &str, query: &str)
+  -> Value
+{
+  let schema = graphql::schema(store.clone(), EventBus::new());
+
+  let user = AuthenticatedUser {
+    username: String::from(user),
+    credential: Credential::Keycloak,
+  };
+
+  let res = schema
+    .execute(async_graphql::Request::new(query).data(user))
+    .await;
+  assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+  serde_json::to_value(&res.data).unwrap()
+}
+
+#[actix_rt::test]
+async fn test_graphql_pages_follow_the_order_of_ids() {
+  let Some(store) = store().await else { return };
+
+  let todos = [
+    ("5f5b4e0b0000000000000003", USER, "Buy milk", "Todo"),
+    ("5f5b4e0b0000000000000001", USER, "buy eggs", "Done"),
+    ("5f5b4e0b0000000000000002", USER, "call Bob", "Todo"),
+    ("5f5b4e0b0000000000000004", USER, "buy bread", "Todo"),
+    ("5f5b4e0b0000000000000005", "bob", "buy tea", "Todo"),
+    ("5f5b4e0b0000000000000006", "bob", "buy cake", "Todo"),
+  ];
+
+  for (id, user, content, status) in todos.iter() {
+    store.elements.insert_one(doc!{
+      "_id": ObjectId::with_string(id).unwrap(),
+      "user": *user,
+      "content": *content,
+      "status": *status,
+      "created": chrono::Utc::now(),
+      "version": 1i64,
+    }, None).await.unwrap();
+  }
+
+  store.share("bob", USER, Permission::Viewer, Some(
+    ObjectId::with_string("5f5b4e0b0000000000000005").unwrap(),
+  )).await.unwrap();
+
+  let page = |after: &str| format!(
+    "{{ elements(filter: {{status: Todo, search: \"BUY\"}}, first: 1\
+    {}) {{ elements {{ id owner }} endCursor hasNextPage \
+    totalCount }} }}",
+    after,
+  );
+
+  let first = graphql_query(&store, USER, &page("")).await;
+  let first = &first["elements"];
+  assert_eq!(first["totalCount"], 3);
+  assert_eq!(first["hasNextPage"], true);
+  assert_eq!(first["elements"][0]["id"], "5f5b4e0b0000000000000003");
+
+  let after = format!(", after: {}", first["endCursor"]);
+  let second = graphql_query(&store, USER, &page(&after)).await;
+  let second = &second["elements"];
+  assert_eq!(second["elements"][0]["id"], "5f5b4e0b0000000000000004");
+
+  // the shared element of bob, the other one of bob is not readable
+  let after = format!(", after: {}", second["endCursor"]);
+  let third = graphql_query(&store, USER, &page(&after)).await;
+  let third = &third["elements"];
+  assert_eq!(third["elements"][0]["id"], "5f5b4e0b0000000000000005");
+  assert_eq!(third["elements"][0]["owner"], "bob");
+  assert_eq!(third["hasNextPage"], false);
+
+  let stats = graphql_query(
+    &store, USER,
+    "{ lists { owner stats { todo done total } } stats { total } }",
+  ).await;
+  assert_eq!(stats["stats"]["total"], 5);
+  assert_eq!(stats["lists"][0]["owner"], USER);
+  assert_eq!(stats["lists"][0]["stats"]["todo"], 3);
+  assert_eq!(stats["lists"][0]["stats"]["done"], 1);
+  assert_eq!(stats["lists"][1]["owner"], "bob");
+  assert_eq!(stats["lists"][1]["stats"]["total"], 1);
+
+  store.database.drop(None).await.unwrap();
+}
+
+// serves the routes behind bearer auth and tickets, as main does
+async fn authenticated(store: &Store, req: test::TestRequest)
+  -> ServiceResponse
+{
+  let key_set = Arc::new(KeyStore::new());
+  let schema = graphql::schema(store.clone(), EventBus::new());
+
+  let mut app = test::init_service(
+    App::new()
+      .data(store.clone())
+      .data(EventBus::new())
+      .data(schema)
+      .service(web::scope("")
+        .wrap(HttpAuthentication::bearer(move |req, bearer| {
+          auth(req, bearer, key_set.clone())
+        }))
+        .wrap_fn(ticket_header)
+        .service(get_events)
+        .service(get_shares)
+        .service(graphql::graphql_subscriptions)
+        .service(graphql::graphql_query))
+  ).await;
+
+  test::call_service(&mut app, req.to_request()).await
+}
+
+#[actix_rt::test]
+async fn test_tickets_only_open_the_event_stream_of_their_user() {
+  let Some(store) = store().await else { return };
+
+  let get = |path: &str, ticket: &str| test::TestRequest::get()
+    .uri(&format!("{}?ticket={}", path, ticket));
+  let events = format!("/{}/events", USER);
+
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  let res = authenticated(&store, get(&events, &ticket)).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  // tickets are used up by the stream they opened
+  let res = authenticated(&store, get(&events, &ticket)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  let res = authenticated(&store, get("/bob/events", &ticket)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  let shares = format!("/{}/shares", USER);
+  let res = authenticated(&store, get(&shares, &ticket)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  store.tickets.update_many(
+    doc!{}, doc!{"$set": {"expires": chrono::Utc::now()}}, None,
+  ).await.unwrap();
+
+  let res = authenticated(&store, get(&events, &ticket)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_tickets_open_graphql_subscriptions() {
+  let Some(store) = store().await else { return };
+
+  let uri = |ticket: &str| {
+    format!("/{}/graphql?ticket={}", USER, ticket)
+  };
+  let upgrade = |ticket: &str| test::TestRequest::get()
+    .uri(&uri(ticket))
+    .header("Upgrade", "websocket")
+    .header("Connection", "upgrade")
+    .header("Sec-WebSocket-Version", "13")
+    .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+    .header("Sec-WebSocket-Protocol", "graphql-ws");
+
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  let res = authenticated(&store, upgrade(&ticket)).await;
+  assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+  // reconnecting needs a new ticket
+  let res = authenticated(&store, upgrade(&ticket)).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  // queries are not streams, they send the Authorization header
+  let ticket = store.add_ticket(USER).await.unwrap().ticket;
+  let req = test::TestRequest::get()
+    .uri(&format!("{}&query={{stats{{total}}}}", uri(&ticket)));
+  let res = authenticated(&store, req).await;
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+  store.database.drop(None).await.unwrap();
+}
+
+// deletes USER as credential would be authenticated by auth
+async fn delete_user_as(store: &Store, credential: Credential,
+  service_token: Option<&str>) -> ServiceResponse
+{
+  let mut app = test::init_service(
+    App::new()
+      .data(store.clone())
+      .data(ServiceToken::new(Some("service secret")))
+      .wrap_fn(move |req, srv| {
+        req.extensions_mut().insert(AuthenticatedUser {
+          username: String::from(USER),
+          credential: credential.clone(),
+        });
+        srv.call(req)
+      })
+      .service(delete_user)
+  ).await;
+
+  let req = test::TestRequest::delete().uri(&format!("/{}", USER));
+
+  let req = match service_token {
+    Some(token) => req.header("X-Service-Token", token),
+    None => req,
+  };
+
+  test::call_service(&mut app, req.to_request()).await
+}
+
+#[actix_rt::test]
+async fn test_users_are_only_deleted_by_keycloak_proxy() {
+  let Some(store) = store().await else { return };
+
+  let id = add(&store, "shared").await;
+  store.share(USER, "bob", Permission::Viewer, None).await.unwrap();
+
+  let res = delete_user_as(&store, Credential::Keycloak, None).await;
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  let res = delete_user_as(
+    &store, Credential::Keycloak, Some("guessed"),
+  ).await;
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  let res = delete_user_as(
+    &store,
+    Credential::AccessToken(vec![Scope::Read, Scope::Write]),
+    Some("service secret"),
+  ).await;
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+  assert!(element(&store, &id).await.is_some());
+
+  let res = delete_user_as(
+    &store, Credential::Keycloak, Some("service secret"),
+  ).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  assert!(element(&store, &id).await.is_none());
+  assert!(store.shares(doc!{}).await.unwrap().is_empty());
+
+  // bob drops the elements with the next sync
+  let bob = changes(&store, "bob", Some("bob:0")).await;
+  assert_eq!(ids(&bob, "deleted"), vec![id.to_hex()]);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_purged_users_leave_no_tickets_and_no_name_behind() {
+  let Some(store) = store().await else { return };
+
+  let of_bob = doc!{"_id": ObjectId::new(), "user": "bob"};
+  store.record(
+    &Change::new(USER),
+    EventKind::Updated,
+    Some(&of_bob),
+    Some(&of_bob),
+  ).await.unwrap();
+  store.add_ticket(USER).await.unwrap();
+
+  store.purge_user(USER).await.unwrap();
+
+  let tickets = store.tickets.count_documents(doc!{}, None).await;
+  assert_eq!(tickets.unwrap(), 0);
+
+  // the change stays in the history of bob
+  let event = store.element_events.find_one(doc!{}, None).await
+    .unwrap()
+    .unwrap();
+  assert_eq!(event.get_str("owner").unwrap(), "bob");
+  assert_eq!(event.get_str("actor").unwrap(), DELETED_ACTOR);
+
+  store.database.drop(None).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_imports_are_recorded_and_published_as_a_whole() {
+  let Some(store) = store().await else { return };
+  let events = EventBus::new();
+
+  store.share(USER, "bob", Permission::Viewer, None).await.unwrap();
+  let mut bob = events.listen("bob");
+
+  let mut app = test::init_service(
+    App::new()
+      .data(store.clone())
+      .data(events.clone())
+      .service(import)
+  ).await;
+
+  let req = test::TestRequest::post()
+    .uri(&format!("/{}/import?format=todotxt", USER))
+    .set_payload("call mom\nbuy milk\n");
+  let res = test::call_service(&mut app, req.to_request()).await;
+  assert_eq!(res.status(), StatusCode::OK);
+
+  let recorded = store.element_events.count_documents(doc!{}, None);
+  assert_eq!(recorded.await.unwrap(), 2);
+
+  let changes = store.element_events.distinct("change", None, None);
+  assert_eq!(changes.await.unwrap().len(), 1);
+
+  for _ in 0..2 {
+    let change = bob.try_recv().unwrap();
+    assert_eq!(change.kind, EventKind::Created);
+    assert_eq!(
+      change.element.shared(), Some((USER, Permission::Viewer)),
+    );
+  }
+  assert!(bob.try_recv().is_err());
+
+  store.database.drop(None).await.unwrap();
 }
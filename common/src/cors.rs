@@ -0,0 +1,42 @@
+use actix_cors::Cors;
+
+use crate::list::List;
+use crate::logging::REQUEST_ID_HEADER;
+
+static ANY: &str = "*";
+
+// the cors_* keys of the config of a service
+pub struct CorsConfig<'a> {
+  pub allowed_origins: &'a List,
+  pub allowed_methods: &'a List,
+  pub allowed_headers: &'a List,
+  pub max_age_secs: usize,
+}
+
+// browsers may only call the service from the configured origins.
+// Besides X-Request-Id they may read the headers the service names
+// in expose. Preflight requests are answered by the middleware itself
+pub fn cors(config: &CorsConfig, expose: &[&str]) -> Cors {
+  let mut exposed = vec![REQUEST_ID_HEADER];
+  exposed.extend_from_slice(expose);
+
+  let mut cors = Cors::default()
+    .allowed_methods(config.allowed_methods.iter().map(|m| m.as_str()))
+    .expose_headers(exposed)
+    .max_age(config.max_age_secs);
+
+  if config.allowed_origins.contains(ANY) {
+    cors = cors.allow_any_origin();
+  } else {
+    for origin in config.allowed_origins.iter() {
+      cors = cors.allowed_origin(origin);
+    }
+  }
+
+  if config.allowed_headers.contains(ANY) {
+    cors.allow_any_header()
+  } else {
+    cors.allowed_headers(config.allowed_headers.iter()
+      .map(|h| h.as_str()))
+  }
+}
@@ -0,0 +1,50 @@
+use serde_derive::{Serialize, Deserialize};
+
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub enum Status { Ok, Unavailable }
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct ComponentHealth {
+  pub status: Status,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>,
+}
+
+impl ComponentHealth {
+  pub fn ok() -> ComponentHealth {
+    ComponentHealth{status: Status::Ok, message: None}
+  }
+
+  pub fn unavailable(message: String) -> ComponentHealth {
+    ComponentHealth{status: Status::Unavailable, message: Some(message)}
+  }
+}
+
+// the service is only ready if all its components are
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Health {
+  pub status: Status,
+  pub components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+impl Health {
+  pub fn new(components: BTreeMap<&'static str, ComponentHealth>)
+    -> Health
+  {
+    let status = if components.values().all(|c| c.status == Status::Ok) {
+      Status::Ok
+    } else {
+      Status::Unavailable
+    };
+
+    Health{status, components}
+  }
+}
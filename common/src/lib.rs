@@ -0,0 +1,9 @@
+// the parts yata_api and keycloak_proxy share: how they are
+// configured for browsers, report their health, log, expose metrics
+// and limit requests
+pub mod cors;
+pub mod health;
+pub mod list;
+pub mod logging;
+pub mod metrics;
+pub mod rate_limit;
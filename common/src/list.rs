@@ -0,0 +1,34 @@
+use serde_derive::{Serialize, Deserialize};
+
+use std::str::FromStr;
+
+// comma separated in the environment, an array in the config file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct List(Vec<String>);
+
+impl List {
+  pub fn of(values: &[&str]) -> List {
+    List(values.iter().map(|v| String::from(*v)).collect())
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &String> {
+    self.0.iter()
+  }
+
+  pub fn contains(&self, value: &str) -> bool {
+    self.0.iter().any(|v| v == value)
+  }
+}
+
+impl FromStr for List {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(List(s.split(",")
+      .map(|v| v.trim())
+      .filter(|v| !v.is_empty())
+      .map(String::from)
+      .collect()))
+  }
+}
@@ -0,0 +1,172 @@
+use actix_web::{Error as ActixError, FromRequest, HttpMessage,
+  HttpRequest};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+
+use actix_service::Service;
+
+use futures::future::{Future, FutureExt, Ready, ready};
+
+use serde_derive::{Serialize, Deserialize};
+
+use tracing::{info, info_span, warn};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+
+static REQUEST_ID_MAX_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq,
+  Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat { #[default] Text, Json }
+
+impl FromStr for LogFormat {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(LogFormat::Text),
+      "json" => Ok(LogFormat::Json),
+      _ => Err(()),
+    }
+  }
+}
+
+// level takes the same directives as RUST_LOG, e.g.
+// "info,yata_api=debug"
+pub fn init(level: &str, format: LogFormat) -> Result<(), String> {
+  let filter = EnvFilter::try_new(level)
+    .map_err(|e| format!("invalid log level: {}", e))?;
+
+  let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+  match format {
+    LogFormat::Text => builder.init(),
+    LogFormat::Json => builder.json().init(),
+  }
+
+  Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+  pub fn generate() -> RequestId {
+    RequestId((0..16).map(|_| format!("{:02x}", rand::random::<u8>()))
+      .collect())
+  }
+
+  // the id given by the client or a proxy in front of the service is
+  // only taken if it can not be used to inject anything into the logs
+  pub fn from_header(value: Option<&str>) -> RequestId {
+    let valid = |id: &str| !id.is_empty()
+      && id.len() <= REQUEST_ID_MAX_LEN
+      && id.chars().all(|c| c.is_ascii_alphanumeric()
+        || c == '-' || c == '_' || c == '.');
+
+    match value {
+      Some(id) if valid(id) => RequestId(String::from(id)),
+      _ => RequestId::generate(),
+    }
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl fmt::Display for RequestId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl FromRequest for RequestId {
+  type Error = ActixError;
+  type Future = Ready<Result<Self, Self::Error>>;
+  type Config = ();
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    ready(Ok(req.extensions().get::<RequestId>().cloned()
+      .unwrap_or_else(RequestId::generate)))
+  }
+}
+
+// generates or propagates the X-Request-Id of the request and logs
+// everything happening while handling it in a span carrying the id
+pub fn trace_request<S, B>(req: ServiceRequest, srv: &mut S)
+  -> impl Future<Output = Result<ServiceResponse<B>, ActixError>>
+  where S: Service<
+    Request = ServiceRequest,
+    Response = ServiceResponse<B>,
+    Error = ActixError,
+  >
+{
+  let request_id = RequestId::from_header(
+    req.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok())
+  );
+
+  req.extensions_mut().insert(request_id.clone());
+
+  let span = info_span!(
+    "request",
+    request_id = %request_id,
+    method = %req.method(),
+    path = %req.path(),
+  );
+
+  let start = Instant::now();
+
+  let fut = {
+    let _entered = span.enter();
+    srv.call(req)
+  };
+
+  fut.map(move |res| {
+    let status = match &res {
+      Ok(res) => res.status(),
+      Err(e) => e.as_response_error().status_code(),
+    };
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    if status.is_server_error() {
+      warn!(status = status.as_u16(), latency_ms, "request failed");
+    } else {
+      info!(status = status.as_u16(), latency_ms, "request handled");
+    }
+
+    // errors of middlewares are only turned into responses by actix,
+    // without access to the request they can not carry its id
+    res.map(|mut res| {
+      res.headers_mut().insert(
+        HeaderName::from_static(REQUEST_ID_HEADER),
+        HeaderValue::from_str(request_id.as_str()).unwrap(),
+      );
+      res
+    })
+  }).instrument(span)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_request_id_is_only_propagated_if_sane() {
+    let id = RequestId::from_header(Some("some-id_1.0"));
+    assert_eq!(id.as_str(), "some-id_1.0");
+
+    let id = RequestId::from_header(Some("id\nforged log line"));
+    assert_eq!(id.as_str().len(), 32);
+
+    let id = RequestId::from_header(None);
+    assert_ne!(id, RequestId::generate());
+  }
+}
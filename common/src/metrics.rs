@@ -0,0 +1,164 @@
+use actix_web::{Error as ActixError, HttpRequest};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec,
+  Opts, Registry, TextEncoder};
+use prometheus::core::Collector;
+
+use sha2::{Digest, Sha256};
+
+use std::time::Instant;
+
+// used as route label for requests not matching any route, so random
+// paths can not blow up the number of time series
+static UNMATCHED_ROUTE: &str = "unmatched";
+
+// the requests handled by a service. Metrics only the service knows
+// about are registered with it as well, so /metrics renders them
+#[derive(Clone)]
+pub struct Metrics {
+  registry: Registry,
+  requests: IntCounterVec,
+  latency: HistogramVec,
+  // hash of the bearer token scrapes need, nobody may scrape without
+  token_hash: Option<String>,
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    Metrics::new()
+  }
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    let requests = IntCounterVec::new(
+      Opts::new("http_requests_total", "Number of handled requests"),
+      &["method", "route", "status"],
+    ).unwrap();
+
+    let latency = HistogramVec::new(
+      HistogramOpts::new(
+        "http_request_duration_seconds", "Latency of handled requests"
+      ),
+      &["method", "route"],
+    ).unwrap();
+
+    let metrics = Metrics {
+      registry: Registry::new(),
+      requests,
+      latency,
+      token_hash: None,
+    };
+
+    metrics.register(metrics.requests.clone());
+    metrics.register(metrics.latency.clone());
+    metrics
+  }
+
+  // panics if a metric of the same name is registered already, which
+  // only a programming error can cause
+  pub fn register<C: Collector + 'static>(&self, collector: C) {
+    self.registry.register(Box::new(collector)).unwrap();
+  }
+
+  pub fn require_token(mut self, token: Option<&str>) -> Metrics {
+    self.token_hash = token.map(hash);
+    self
+  }
+
+  // services configured without a token can not be scraped at all.
+  // Hashes are compared, so the time taken tells nothing about the
+  // token
+  pub fn authorized(&self, req: &HttpRequest) -> bool {
+    let token = req.headers().get(AUTHORIZATION)
+      .and_then(|h| h.to_str().ok())
+      .and_then(|h| h.strip_prefix("Bearer "));
+
+    match (&self.token_hash, token) {
+      (Some(token_hash), Some(token)) => &hash(token) == token_hash,
+      _ => false,
+    }
+  }
+
+  pub fn start(&self, req: &ServiceRequest) -> RequestTimer {
+    RequestTimer {
+      metrics: self.clone(),
+      method: req.method().to_string(),
+      route: req.match_pattern()
+        .unwrap_or_else(|| String::from(UNMATCHED_ROUTE)),
+      start: Instant::now(),
+    }
+  }
+
+  // the metrics in the prometheus text format
+  pub fn render(&self) -> String {
+    let mut buffer = Vec::new();
+
+    TextEncoder::new()
+      .encode(&self.registry.gather(), &mut buffer)
+      .unwrap();
+
+    String::from_utf8(buffer).unwrap()
+  }
+}
+
+fn hash(token: &str) -> String {
+  format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub struct RequestTimer {
+  metrics: Metrics,
+  method: String,
+  route: String,
+  start: Instant,
+}
+
+impl RequestTimer {
+  pub fn finish<B>(
+    &self,
+    res: &Result<ServiceResponse<B>, ActixError>,
+  ) {
+    let status = match res {
+      Ok(res) => res.status(),
+      Err(e) => e.as_response_error().status_code(),
+    };
+
+    self.metrics.requests
+      .with_label_values(&[&self.method, &self.route, status.as_str()])
+      .inc();
+
+    self.metrics.latency
+      .with_label_values(&[&self.method, &self.route])
+      .observe(self.start.elapsed().as_secs_f64());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::test;
+
+  #[test]
+  fn test_metrics_need_the_token() {
+    let scrape = |token: Option<&str>| {
+      let req = test::TestRequest::get().uri("/metrics");
+
+      match token {
+        Some(token) => req.header("Authorization", token),
+        None => req,
+      }.to_http_request()
+    };
+
+    let metrics = Metrics::new().require_token(Some("secret"));
+    assert!(metrics.authorized(&scrape(Some("Bearer secret"))));
+    assert!(!metrics.authorized(&scrape(Some("Bearer other"))));
+    assert!(!metrics.authorized(&scrape(Some("secret"))));
+    assert!(!metrics.authorized(&scrape(None)));
+
+    let unprotected = Metrics::new();
+    assert!(!unprotected.authorized(&scrape(Some("Bearer secret"))));
+  }
+}
@@ -0,0 +1,402 @@
+use actix_web::HttpRequest;
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::list::List;
+
+// buckets are only forgotten once there are more than this, so keys
+// seen rarely do not pile up forever
+static MAX_TRACKED_KEYS: usize = 10_000;
+
+static ANY: &str = "*";
+
+struct Bucket {
+  tokens: f64,
+  last_refill: Instant,
+}
+
+// what is left of the limit of a key after a request. If the request
+// was rejected, reset is the time until the next one is allowed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+  pub limit: u32,
+  pub remaining: u32,
+  pub reset: Duration,
+}
+
+impl Quota {
+  // rounded up, so clients waiting this long are not rejected again
+  pub fn reset_secs(&self) -> u64 {
+    self.reset.as_secs() + (self.reset.subsec_nanos() > 0) as u64
+  }
+
+  // the headers proposed by draft-ietf-httpapi-ratelimit-headers
+  pub fn headers(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("ratelimit-limit", self.limit.to_string()),
+      ("ratelimit-remaining", self.remaining.to_string()),
+      ("ratelimit-reset", self.reset_secs().to_string()),
+    ]
+  }
+
+  pub fn insert_headers(&self, headers: &mut HeaderMap) {
+    for (name, value) in self.headers() {
+      headers.insert(
+        HeaderName::from_static(name),
+        HeaderValue::from_str(&value).unwrap(),
+      );
+    }
+  }
+}
+
+// token bucket per key: every key may burst up to capacity requests,
+// afterwards it gets refill_per_sec new requests per second
+pub struct RateLimiter {
+  capacity: f64,
+  refill_per_sec: f64,
+  buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+  pub fn new(capacity: u32, period: Duration) -> RateLimiter {
+    RateLimiter {
+      capacity: capacity as f64,
+      refill_per_sec: capacity as f64 / period.as_secs_f64(),
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn try_acquire(&self, key: &str) -> Result<(), Quota> {
+    self.acquire(key).map(|_| ())
+  }
+
+  // takes one token from the bucket of key or returns how long the
+  // caller has to wait until the next one is available
+  pub fn acquire(&self, key: &str) -> Result<Quota, Quota> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap();
+
+    if buckets.len() > MAX_TRACKED_KEYS {
+      let (capacity, refill) = (self.capacity, self.refill_per_sec);
+
+      buckets.retain(|_, b| {
+        let elapsed = now.duration_since(b.last_refill).as_secs_f64();
+        b.tokens + elapsed * refill < capacity
+      });
+    }
+
+    let capacity = self.capacity;
+
+    let bucket = buckets.entry(String::from(key)).or_insert(Bucket {
+      tokens: capacity,
+      last_refill: now,
+    });
+
+    let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec)
+      .min(capacity);
+    bucket.last_refill = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+
+      return Ok(Quota {
+        limit: capacity as u32,
+        remaining: bucket.tokens as u32,
+        reset: Duration::from_secs_f64(
+          (capacity - bucket.tokens) / self.refill_per_sec
+        ),
+      });
+    }
+
+    Err(Quota {
+      limit: capacity as u32,
+      remaining: 0,
+      reset: Duration::from_secs_f64(
+        (1.0 - bucket.tokens) / self.refill_per_sec
+      ),
+    })
+  }
+}
+
+// "<method> <route> <requests>/<seconds>", e.g. "POST /token 10/60".
+// The route is the pattern the request matched, "*" matches any
+// method or route
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+  method: String,
+  route: String,
+  capacity: u32,
+  period: Duration,
+}
+
+impl Rule {
+  pub fn matches(&self, method: &str, route: &str) -> bool {
+    (self.method == ANY || self.method.eq_ignore_ascii_case(method))
+      && (self.route == ANY || self.route == route)
+  }
+}
+
+impl FromStr for Rule {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+
+    let (method, route, limit) = match parts.as_slice() {
+      [method, route, limit] => (method, route, limit),
+      _ => return Err(()),
+    };
+
+    let slash = limit.find("/").ok_or(())?;
+    let capacity: u32 = limit[..slash].parse().map_err(|_| ())?;
+    let period: u64 = limit[slash + 1..].parse().map_err(|_| ())?;
+
+    if capacity == 0 || period == 0 {
+      return Err(());
+    }
+
+    Ok(Rule {
+      method: method.to_string(),
+      route: route.to_string(),
+      capacity,
+      period: Duration::from_secs(period),
+    })
+  }
+}
+
+// a single address or a network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+  addr: IpAddr,
+  prefix: u32,
+}
+
+impl Network {
+  fn contains(&self, ip: IpAddr) -> bool {
+    match (self.addr, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) =>
+        (u32::from(net) ^ u32::from(ip))
+          .checked_shr(32 - self.prefix).unwrap_or(0) == 0,
+      (IpAddr::V6(net), IpAddr::V6(ip)) =>
+        (u128::from(net) ^ u128::from(ip))
+          .checked_shr(128 - self.prefix).unwrap_or(0) == 0,
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for Network {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (addr, prefix) = match s.find("/") {
+      Some(i) => (&s[..i], Some(&s[i + 1..])),
+      None => (s, None),
+    };
+
+    let addr: IpAddr = addr.parse().map_err(|_| ())?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse().map_err(|_| ())?,
+      None => bits,
+    };
+
+    if prefix > bits {
+      return Err(());
+    }
+
+    Ok(Network{addr, prefix})
+  }
+}
+
+// the address of the client, taken from X-Forwarded-For if the request
+// came through a trusted proxy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+  // the address the limiters resolved, or the peer address if the
+  // request did not pass them. Unknown if the request did not come
+  // in over TCP
+  pub fn of(req: &HttpRequest) -> Option<ClientIp> {
+    req.extensions().get::<ClientIp>().copied()
+      .or_else(|| req.peer_addr().map(|peer| ClientIp(peer.ip())))
+  }
+
+  // the key requests of the client are limited under
+  pub fn key(&self) -> String {
+    format!("ip:{}", self.0)
+  }
+}
+
+pub struct TrustedProxies(pub Vec<Network>);
+
+impl TrustedProxies {
+  // entries that do not parse are skipped, the configs check them
+  pub fn new(networks: &List) -> TrustedProxies {
+    TrustedProxies(networks.iter().filter_map(|n| n.parse().ok())
+      .collect())
+  }
+
+  pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap)
+    -> Option<IpAddr>
+  {
+    let forwarded_for: Vec<&str> = headers.get_all("x-forwarded-for")
+      .filter_map(|v| v.to_str().ok())
+      .flat_map(|v| v.split(","))
+      .map(|v| v.trim())
+      .collect();
+
+    Some(self.resolve(peer?.ip(), &forwarded_for))
+  }
+
+  // walks X-Forwarded-For from the right for as long as the hop that
+  // added the entry is trusted, so clients can not spoof their address
+  fn resolve(&self, peer: IpAddr, forwarded_for: &[&str]) -> IpAddr {
+    let mut client = peer;
+
+    for hop in forwarded_for.iter().rev() {
+      if !self.0.iter().any(|n| n.contains(client)) {
+        break;
+      }
+
+      match hop.parse() {
+        Ok(ip) => client = ip,
+        Err(_) => break,
+      }
+    }
+
+    client
+  }
+}
+
+// the first rule matching a request decides its limit
+pub struct Limits(Vec<(Rule, RateLimiter)>);
+
+impl Limits {
+  // rules that do not parse are skipped, the configs check them
+  pub fn new(rules: &List) -> Limits {
+    Limits(rules.iter()
+      .filter_map(|r| r.parse::<Rule>().ok())
+      .map(|r| {
+        let limiter = RateLimiter::new(r.capacity, r.period);
+        (r, limiter)
+      })
+      .collect())
+  }
+
+  // takes a request of key from the limiter of the first rule matching
+  // method and route, if any
+  pub fn acquire(&self, method: &str, route: &str, key: &str)
+    -> Option<Result<Quota, Quota>>
+  {
+    self.0.iter()
+      .find(|(rule, _)| rule.matches(method, route))
+      .map(|(_, limiter)| limiter.acquire(key))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::test::TestRequest;
+
+  #[test]
+  fn test_bucket_is_exhausted_after_capacity_requests() {
+    let limiter = RateLimiter::new(2, Duration::from_secs(3600));
+
+    assert_eq!(limiter.acquire("some key").unwrap().remaining, 1);
+    assert!(limiter.try_acquire("some key").is_ok());
+
+    let quota = limiter.try_acquire("some key").unwrap_err();
+    assert_eq!(quota.remaining, 0);
+    assert_eq!(
+      quota.headers()[0], ("ratelimit-limit", String::from("2")),
+    );
+    assert!(quota.reset > Duration::from_secs(1700));
+
+    assert!(limiter.try_acquire("other key").is_ok());
+  }
+
+  #[test]
+  fn test_rules() {
+    let rule: Rule = "POST /token 10/60".parse().unwrap();
+    assert_eq!(rule.capacity, 10);
+    assert_eq!(rule.period, Duration::from_secs(60));
+    assert!(rule.matches("POST", "/token"));
+    assert!(!rule.matches("GET", "/token"));
+
+    let rule: Rule = "delete * 1/1".parse().unwrap();
+    assert!(rule.matches("DELETE", "/{user}/{id}"));
+
+    assert!("POST /token".parse::<Rule>().is_err());
+    assert!("POST /token 0/60".parse::<Rule>().is_err());
+  }
+
+  #[test]
+  fn test_first_matching_rule_decides_the_limit() {
+    let limits = Limits::new(&List::of(&[
+      "POST /token 1/60", "not a rule", "* * 2/60",
+    ]));
+
+    assert!(limits.acquire("POST", "/token", "a").unwrap().is_ok());
+    assert!(limits.acquire("POST", "/token", "a").unwrap().is_err());
+    assert!(limits.acquire("GET", "/token", "a").unwrap().is_ok());
+
+    let none = Limits::new(&List::default());
+    assert!(none.acquire("GET", "/token", "a").is_none());
+  }
+
+  #[test]
+  fn test_client_ip_is_only_taken_from_trusted_proxies() {
+    let proxies =
+      TrustedProxies::new(&List::of(&["10.0.0.0/8", "::1"]));
+
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+    // the spoofed first entry is never reached
+    let forwarded_for = ["6.6.6.6", "1.2.3.4", "10.0.0.1"];
+    assert_eq!(
+      proxies.resolve(ip("10.0.0.2"), &forwarded_for),
+      ip("1.2.3.4"),
+    );
+    assert_eq!(
+      proxies.resolve(ip("1.2.3.4"), &["6.6.6.6"]),
+      ip("1.2.3.4"),
+    );
+    assert_eq!(proxies.resolve(ip("::1"), &["2001:db8::1"]),
+      ip("2001:db8::1"));
+    assert!("10.0.0.0/33".parse::<Network>().is_err());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      "x-forwarded-for".parse().unwrap(),
+      HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.1"),
+    );
+    assert_eq!(proxies.client_ip(None, &headers), None);
+  }
+
+  #[test]
+  fn test_client_ip_falls_back_to_the_peer() {
+    let ip = |s: &str| ClientIp(s.parse().unwrap());
+
+    let req = TestRequest::default()
+      .peer_addr("1.2.3.4:80".parse().unwrap())
+      .to_http_request();
+    assert_eq!(ClientIp::of(&req), Some(ip("1.2.3.4")));
+
+    req.extensions_mut().insert(ip("5.6.7.8"));
+    assert_eq!(ClientIp::of(&req).unwrap().key(), "ip:5.6.7.8");
+
+    let req = TestRequest::default().to_http_request();
+    assert_eq!(ClientIp::of(&req), None);
+  }
+}
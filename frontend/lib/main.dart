@@ -973,7 +973,7 @@ class ElementsController extends YataController {
     try {
       var token = authController.accessToken.toCompactSerialization();
       var response = await client.get(
-        "http://localhost:9999/${authController.user}",
+        "http://localhost:9999/api/v1/${authController.user}",
         headers: {
           "Authorization": "Bearer $token",
         }
@@ -1036,7 +1036,7 @@ class ElementsController extends YataController {
   }
 
   addTODO(String content) async {
-    var url = "http://localhost:9999/${authController.user}/add_todo";
+    var url = "http://localhost:9999/api/v1/${authController.user}/add_todo";
     var token = authController.accessToken.toCompactSerialization();
     // TODO: error mangement
     try {
@@ -1084,7 +1084,7 @@ class ElementsController extends YataController {
   }
 
   _putStatus(String elementId, ElementStatus status) async {
-    var url = "http://localhost:9999/${authController.user}/$elementId/status";
+    var url = "http://localhost:9999/api/v1/${authController.user}/$elementId/status";
     var token = authController.accessToken.toCompactSerialization();
 
     try {
@@ -1115,7 +1115,7 @@ class ElementsController extends YataController {
 
   deleteCompletely(int index) async {
     var elementId = _deleted[index].id;
-    var url = "http://localhost:9999/${authController.user}/$elementId";
+    var url = "http://localhost:9999/api/v1/${authController.user}/$elementId";
     var token = authController.accessToken.toCompactSerialization();
 
     try {
@@ -1137,7 +1137,7 @@ class ElementsController extends YataController {
   }
 
   deleteAllCompletely() async {
-    var url = "http://localhost:9999/${authController.user}/empty_bin";
+    var url = "http://localhost:9999/api/v1/${authController.user}/empty_bin";
     var token = authController.accessToken.toCompactSerialization();
 
     try {
@@ -0,0 +1,419 @@
+use actix_web::http::{HeaderName, Method};
+
+use actix_cors::Cors;
+
+use serde_derive::{Serialize, Deserialize};
+
+use toml::value::{Table, Value};
+
+use yata_common::cors::{self, CorsConfig};
+use yata_common::logging::LogFormat;
+use yata_common::rate_limit::{Network, Rule};
+
+pub use yata_common::list::List;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+static ENV_PREFIX: &str = "KEYCLOAK_PROXY_";
+
+static REDACTED: &str = "********";
+
+// origin of the frontend when served by flutter's development server
+static FRONTEND_ORIGIN: &str = "http://localhost:8000";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+  pub port: u16,
+  pub client_id: String,
+  pub realm: String,
+  pub admin_cli_secret: String,
+  // base url of keycloak including the path prefix, e.g.
+  // http://keycloak:8080/auth
+  pub keycloak_url: String,
+  pub yata_api_url: String,
+  // shared secret yata_api requires when users are deleted, its
+  // service_token
+  pub yata_api_service_token: String,
+  pub upstream_timeout_secs: u64,
+  // in-flight requests get this long to finish on SIGTERM
+  pub shutdown_timeout_secs: u64,
+  pub log_level: String,
+  pub log_format: LogFormat,
+  pub cors_allowed_origins: List,
+  pub cors_allowed_methods: List,
+  pub cors_allowed_headers: List,
+  pub cors_max_age_secs: usize,
+  // addresses or networks of reverse proxies whose X-Forwarded-For
+  // header is trusted
+  pub trusted_proxies: List,
+  pub rate_limits: List,
+  // bearer token scrapes of /metrics need, nobody can scrape without
+  pub metrics_token: Option<String>,
+}
+
+impl Config {
+  // reads the optional toml file given by --config or
+  // KEYCLOAK_PROXY_CONFIG, environment variables take precedence over
+  // its values
+  pub fn load(args: &[String]) -> Result<Config, ConfigError> {
+    let path = args.iter()
+      .position(|a| a == "--config")
+      .and_then(|i| args.get(i + 1).cloned())
+      .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+
+    let file = match path {
+      Some(path) => read_file(&path)?,
+      None => Table::new(),
+    };
+
+    Config::from_sources(file, |key| env::var(key).ok())
+  }
+
+  pub fn from_sources<F>(file: Table, env: F)
+    -> Result<Config, ConfigError>
+    where F: Fn(&str) -> Option<String>
+  {
+    let mut s = Source::new(file, env);
+
+    let keycloak_url = match s.optional::<String>("keycloak_url") {
+      Some(url) => url,
+      None => format!(
+        "{}://{}:{}{}",
+        s.get("keycloak_scheme", Some(String::from("http"))),
+        s.get::<String>("keycloak_server", None),
+        s.get("keycloak_port", Some(8080u16)),
+        s.get("keycloak_path_prefix", Some(String::from("/auth"))),
+      ),
+    };
+
+    let yata_api_url = match s.optional::<String>("yata_api_url") {
+      Some(url) => url,
+      None => format!(
+        "{}://{}:{}",
+        s.get("yata_api_scheme", Some(String::from("http"))),
+        s.get::<String>("yata_api_server", None),
+        s.get("yata_api_port", Some(9999u16)),
+      ),
+    };
+
+    let config = Config {
+      port: s.get("port", Some(9998)),
+      client_id: s.get("client_id", None),
+      realm: s.get("realm", None),
+      admin_cli_secret: s.get("admin_cli_secret", None),
+      keycloak_url: keycloak_url.trim_end_matches("/").to_string(),
+      yata_api_url: yata_api_url.trim_end_matches("/").to_string(),
+      yata_api_service_token: s.get("yata_api_service_token", None),
+      upstream_timeout_secs: s.get("upstream_timeout_secs", Some(10)),
+      shutdown_timeout_secs: s.get("shutdown_timeout_secs", Some(30)),
+      log_level: s.get("log_level", Some(String::from("info"))),
+      log_format: s.get("log_format", Some(LogFormat::Text)),
+      cors_allowed_origins: s.get(
+        "cors_allowed_origins", Some(List::of(&[FRONTEND_ORIGIN])),
+      ),
+      cors_allowed_methods: s.get(
+        "cors_allowed_methods",
+        Some(List::of(&["GET", "POST", "PUT", "DELETE"])),
+      ),
+      cors_allowed_headers: s.get(
+        "cors_allowed_headers",
+        Some(List::of(
+          &["Authorization", "Content-Type", "X-Request-Id"]
+        )),
+      ),
+      cors_max_age_secs: s.get("cors_max_age_secs", Some(3600)),
+      trusted_proxies: s.get("trusted_proxies", Some(List::default())),
+      rate_limits: s.get("rate_limits", Some(List::of(&[
+        "POST /token 10/60",
+        "POST /register 5/3600",
+        "* * 300/60",
+      ]))),
+      metrics_token: s.optional("metrics_token"),
+    };
+
+    s.check(
+      "cors_allowed_methods",
+      config.cors_allowed_methods.iter()
+        .all(|m| Method::from_bytes(m.as_bytes()).is_ok()),
+    );
+    s.check(
+      "cors_allowed_headers",
+      config.cors_allowed_headers.iter().all(|h| {
+        h == "*" || HeaderName::from_bytes(h.as_bytes()).is_ok()
+      }),
+    );
+
+    s.check(
+      "trusted_proxies",
+      config.trusted_proxies.iter()
+        .all(|n| n.parse::<Network>().is_ok()),
+    );
+    s.check(
+      "rate_limits",
+      config.rate_limits.iter().all(|r| r.parse::<Rule>().is_ok()),
+    );
+
+    s.finish()?;
+    Ok(config)
+  }
+
+  pub fn upstream_timeout(&self) -> Duration {
+    Duration::from_secs(self.upstream_timeout_secs)
+  }
+
+  pub fn realm_url(&self) -> String {
+    format!("{}/realms/{}", self.keycloak_url, self.realm)
+  }
+
+  fn realm_endpoint(&self, path: &str) -> String {
+    format!("{}/protocol/openid-connect/{}", self.realm_url(), path)
+  }
+
+  pub fn certs_endpoint(&self) -> String {
+    self.realm_endpoint("certs")
+  }
+
+  pub fn token_endpoint(&self) -> String {
+    self.realm_endpoint("token")
+  }
+
+  pub fn logout_endpoint(&self) -> String {
+    self.realm_endpoint("logout")
+  }
+
+  pub fn revoke_endpoint(&self) -> String {
+    self.realm_endpoint("revoke")
+  }
+
+  pub fn userinfo_endpoint(&self) -> String {
+    self.realm_endpoint("userinfo")
+  }
+
+  pub fn users_endpoint(&self) -> String {
+    format!("{}/admin/realms/{}/users", self.keycloak_url, self.realm)
+  }
+
+  // the routes of yata_api acting on the elements of user
+  pub fn yata_api_user_endpoint(&self, user: &str) -> String {
+    format!("{}/api/v1/{}", self.yata_api_url, user)
+  }
+
+  pub fn admin_token_endpoint(&self) -> String {
+    format!(
+      "{}/realms/master/protocol/openid-connect/token",
+      self.keycloak_url,
+    )
+  }
+
+  fn cors_config(&self) -> CorsConfig<'_> {
+    CorsConfig {
+      allowed_origins: &self.cors_allowed_origins,
+      allowed_methods: &self.cors_allowed_methods,
+      allowed_headers: &self.cors_allowed_headers,
+      max_age_secs: self.cors_max_age_secs,
+    }
+  }
+
+  pub fn cors(&self) -> Cors {
+    cors::cors(&self.cors_config(), &[])
+  }
+
+  // the configuration as toml, without secrets
+  pub fn print(&self) -> String {
+    let mut redacted = self.clone();
+    redacted.admin_cli_secret = String::from(REDACTED);
+    redacted.yata_api_service_token = String::from(REDACTED);
+    redacted.metrics_token = self.metrics_token.as_ref()
+      .map(|_| String::from(REDACTED));
+    toml::to_string(&redacted).unwrap()
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigError {
+  file: Option<String>,
+  missing: Vec<String>,
+  invalid: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "invalid configuration")?;
+
+    if let Some(file) = &self.file {
+      writeln!(f, "  config file: {}", file)?;
+    }
+
+    for key in &self.missing {
+      writeln!(f, "  missing: {}", key)?;
+    }
+
+    for key in &self.invalid {
+      writeln!(f, "  invalid: {}", key)?;
+    }
+
+    Ok(())
+  }
+}
+
+fn read_file(path: &str) -> Result<Table, ConfigError> {
+  let error = |e: String| ConfigError {
+    file: Some(format!("{}: {}", path, e)), ..Default::default()
+  };
+
+  let content = fs::read_to_string(path)
+    .map_err(|e| error(e.to_string()))?;
+  toml::from_str(&content).map_err(|e| error(e.to_string()))
+}
+
+// looks up keys first in the environment (upper case, prefixed with
+// KEYCLOAK_PROXY_), then in the config file. Missing and invalid keys
+// are collected, so they can all be reported at once
+struct Source<F> {
+  file: Table,
+  env: F,
+  error: ConfigError,
+}
+
+impl<F: Fn(&str) -> Option<String>> Source<F> {
+  fn new(file: Table, env: F) -> Source<F> {
+    Source{file, env, error: ConfigError::default()}
+  }
+
+  fn env_key(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+  }
+
+  fn raw(&self, key: &str) -> Option<String> {
+    (self.env)(&Self::env_key(key)).or_else(|| {
+      match self.file.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(values) => Some(values.iter()
+          .map(|v| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+          })
+          .collect::<Vec<String>>()
+          .join(",")),
+        v => Some(v.to_string()),
+      }
+    })
+  }
+
+  fn optional<T: FromStr>(&mut self, key: &str) -> Option<T> {
+    let raw = self.raw(key)?;
+
+    match raw.parse() {
+      Ok(v) => Some(v),
+      Err(_) => {
+        self.error.invalid.push(format!(
+          "{} (or {} in the config file): {:?}",
+          Self::env_key(key), key, raw,
+        ));
+        None
+      }
+    }
+  }
+
+  fn get<T: FromStr + Default>(&mut self, key: &str, default: Option<T>)
+    -> T
+  {
+    if self.raw(key).is_none() {
+      match default {
+        Some(default) => return default,
+        None => self.error.missing.push(format!(
+          "{} (or {} in the config file)", Self::env_key(key), key,
+        )),
+      }
+    }
+
+    self.optional(key).unwrap_or_default()
+  }
+
+  // for values that parse, but are still not valid
+  fn check(&mut self, key: &str, valid: bool) {
+    if !valid {
+      self.error.invalid.push(format!(
+        "{} (or {} in the config file): {:?}",
+        Self::env_key(key), key, self.raw(key).unwrap_or_default(),
+      ));
+    }
+  }
+
+  fn finish(self) -> Result<(), ConfigError> {
+    if self.error.missing.is_empty() && self.error.invalid.is_empty() {
+      Ok(())
+    } else {
+      Err(self.error)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::HashMap;
+
+  fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars.iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect();
+
+    move |key| vars.get(key).cloned()
+  }
+
+  #[test]
+  fn test_config_from_env_and_file() {
+    let file: Table = toml::from_str(r#"
+      realm = "yata"
+      keycloak_server = "keycloak"
+      yata_api_url = "https://api.example.com/"
+      upstream_timeout_secs = 5
+      cors_allowed_origins = ["https://yata.example.com", "https://b"]
+    "#).unwrap();
+
+    let config = Config::from_sources(file, env(&[
+      ("KEYCLOAK_PROXY_CLIENT_ID", "yata_frontend"),
+      ("KEYCLOAK_PROXY_ADMIN_CLI_SECRET", "some secret"),
+      ("KEYCLOAK_PROXY_YATA_API_SERVICE_TOKEN", "some token"),
+      ("KEYCLOAK_PROXY_METRICS_TOKEN", "metrics token"),
+      ("KEYCLOAK_PROXY_REALM", "other"),
+    ])).unwrap();
+
+    assert_eq!(config.realm, "other");
+    assert_eq!(config.keycloak_url, "http://keycloak:8080/auth");
+    assert_eq!(config.yata_api_url, "https://api.example.com");
+    assert_eq!(config.upstream_timeout_secs, 5);
+    assert_eq!(
+      config.cors_allowed_origins,
+      List::of(&["https://yata.example.com", "https://b"]),
+    );
+    assert!(config.cors_allowed_methods.contains("DELETE"));
+    assert_eq!(
+      config.users_endpoint(),
+      "http://keycloak:8080/auth/admin/realms/other/users",
+    );
+    assert_eq!(
+      config.yata_api_user_endpoint("some_user"),
+      "https://api.example.com/api/v1/some_user",
+    );
+    assert!(!config.print().contains("some secret"));
+    assert!(!config.print().contains("some token"));
+    assert!(!config.print().contains("metrics token"));
+  }
+
+  #[test]
+  fn test_all_missing_keys_are_reported() {
+    let e = Config::from_sources(Table::new(), env(&[
+      ("KEYCLOAK_PROXY_PORT", "not a port"),
+    ])).unwrap_err();
+
+    assert_eq!(e.missing.len(), 6);
+    assert_eq!(e.invalid.len(), 1);
+  }
+}
@@ -0,0 +1,160 @@
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::client::{ConnectError, JsonPayloadError, PayloadError,
+  SendRequestError};
+use actix_web::http::StatusCode;
+
+use serde_derive::Serialize;
+
+use tracing::{info, warn};
+
+use yata_common::rate_limit::Quota;
+
+use std::convert::From;
+use std::fmt;
+
+use crate::validation::ValidationErrors;
+
+#[derive(Debug)]
+pub enum ProxyError {
+  UpstreamUnreachable(SendRequestError),
+  UpstreamTimeout,
+  UpstreamPayload(PayloadError),
+  UpstreamJson(JsonPayloadError),
+  UpstreamRejected(StatusCode),
+  AdminTokenUnavailable,
+  TooManyRequests(Quota),
+  UnknownClient,
+  InvalidInput(ValidationErrors),
+  UserExists,
+  Unauthorized,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse<'a> {
+  error: &'static str,
+  message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  fields: Option<&'a ValidationErrors>,
+}
+
+impl ProxyError {
+  fn error(&self) -> &'static str {
+    match self {
+      ProxyError::UpstreamUnreachable(_) => "upstream_unreachable",
+      ProxyError::UpstreamTimeout => "upstream_timeout",
+      ProxyError::UpstreamPayload(_) => "upstream_payload",
+      ProxyError::UpstreamJson(_) => "upstream_payload",
+      ProxyError::UpstreamRejected(_) => "upstream_rejected",
+      ProxyError::AdminTokenUnavailable => "admin_token_unavailable",
+      ProxyError::TooManyRequests(_) => "too_many_requests",
+      ProxyError::UnknownClient => "unknown_client",
+      ProxyError::InvalidInput(_) => "invalid_input",
+      ProxyError::UserExists => "user_exists",
+      ProxyError::Unauthorized => "unauthorized",
+    }
+  }
+}
+
+impl fmt::Display for ProxyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProxyError::UpstreamUnreachable(e) =>
+        write!(f, "could not reach keycloak: {}", e),
+      ProxyError::UpstreamTimeout =>
+        write!(f, "keycloak did not answer in time"),
+      ProxyError::UpstreamPayload(e) =>
+        write!(f, "could not read keycloak's response: {}", e),
+      ProxyError::UpstreamJson(e) =>
+        write!(f, "could not read keycloak's response: {}", e),
+      ProxyError::UpstreamRejected(status) =>
+        write!(f, "keycloak rejected the request: {}", status),
+      ProxyError::AdminTokenUnavailable =>
+        write!(f, "no admin token for keycloak available"),
+      ProxyError::TooManyRequests(quota) =>
+        write!(f, "too many requests, retry in {}s",
+          quota.reset_secs()),
+      ProxyError::UnknownClient =>
+        write!(f, "the address of the client is unknown"),
+      ProxyError::InvalidInput(_) =>
+        write!(f, "some fields are invalid"),
+      ProxyError::UserExists =>
+        write!(f, "username or email taken"),
+      ProxyError::Unauthorized =>
+        write!(f, "the bearer token is invalid or expired"),
+    }
+  }
+}
+
+impl ResponseError for ProxyError {
+  fn status_code(&self) -> StatusCode {
+    match self {
+      ProxyError::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+      ProxyError::AdminTokenUnavailable =>
+        StatusCode::SERVICE_UNAVAILABLE,
+      ProxyError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+      ProxyError::UnknownClient => StatusCode::BAD_REQUEST,
+      ProxyError::InvalidInput(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      ProxyError::UserExists => StatusCode::CONFLICT,
+      ProxyError::Unauthorized => StatusCode::UNAUTHORIZED,
+      _ => StatusCode::BAD_GATEWAY,
+    }
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    if self.status_code().is_server_error() {
+      warn!(error = %self, "could not proxy the request");
+    } else {
+      info!(error = %self, "rejected the request");
+    }
+
+    let mut response = HttpResponse::build(self.status_code());
+
+    if let ProxyError::TooManyRequests(quota) = self {
+      response.header("Retry-After", quota.reset_secs());
+
+      for (name, value) in quota.headers() {
+        response.header(name, value);
+      }
+    }
+
+    let fields = match self {
+      ProxyError::InvalidInput(fields) => Some(fields),
+      _ => None,
+    };
+
+    response.json(ErrorResponse {
+      error: self.error(),
+      message: self.to_string(),
+      fields,
+    })
+  }
+}
+
+impl From<SendRequestError> for ProxyError {
+  fn from(e: SendRequestError) -> Self {
+    match e {
+      SendRequestError::Timeout |
+      SendRequestError::Connect(ConnectError::Timeout) =>
+        ProxyError::UpstreamTimeout,
+      e => ProxyError::UpstreamUnreachable(e),
+    }
+  }
+}
+
+impl From<ValidationErrors> for ProxyError {
+  fn from(e: ValidationErrors) -> Self {
+    ProxyError::InvalidInput(e)
+  }
+}
+
+impl From<PayloadError> for ProxyError {
+  fn from(e: PayloadError) -> Self {
+    ProxyError::UpstreamPayload(e)
+  }
+}
+
+impl From<JsonPayloadError> for ProxyError {
+  fn from(e: JsonPayloadError) -> Self {
+    ProxyError::UpstreamJson(e)
+  }
+}
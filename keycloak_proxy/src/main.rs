@@ -1,24 +1,51 @@
-use actix_web::{get, post, web, dev, App, HttpResponse, HttpServer,
-  Responder};
+use actix_web::{get, post, put, delete, web, dev, App,
+  Error as ActixError, FromRequest, HttpRequest, HttpResponse,
+  HttpServer};
+use actix_web::error::ErrorInternalServerError;
+use actix_service::Service;
 use actix_web::client::{Client, ClientResponse};
-use actix_cors::Cors;
+use actix_web::http::{HeaderMap, StatusCode};
+use actix_web::http::header::{self, HeaderName};
 use actix_rt::time;
 use actix_web_httpauth::headers::authorization::Bearer;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, oneshot, watch};
+
+use tracing::{info, warn};
+
+use futures::FutureExt;
+use futures::future::{self, Either};
 
 use serde_derive::{Serialize, Deserialize};
 //use serde_json as json;
 use serde_qs as qs;
 
-#[macro_use]
-extern crate lazy_static;
+use yata_common::health::{ComponentHealth, Health, Status};
+use yata_common::logging::{self, RequestId, REQUEST_ID_HEADER,
+  trace_request};
+use yata_common::metrics::Metrics;
+use yata_common::rate_limit::{ClientIp, RateLimiter};
+
+mod config;
+mod errors;
+mod metrics;
+mod rate_limit;
+mod validation;
+
+use config::Config;
+use errors::ProxyError;
+use metrics::KeycloakMetrics;
+use rate_limit::RateLimits;
+use validation::{Validator, ValidationErrors};
 
+use std::collections::BTreeMap;
 use std::env;
+use std::process;
 use std::convert::From;
 use std::default::Default;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 trait ToFlattenedUrlString {
   fn to_flattened_url_string(&self) -> String;
@@ -26,48 +53,102 @@ trait ToFlattenedUrlString {
 
 struct AdminToken {
   token_response: Option<TokenResponse>,
+  obtained: Option<Instant>,
   endpoint: String,
   token_request: TokenRequest,
+  client_secret: String,
+  failures: u32,
+  last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AdminTokenHealth {
+  valid: bool,
+  expires_in: Option<u64>,
+  consecutive_failures: u32,
+  last_error: Option<String>,
 }
 
 impl AdminToken {
-  fn new(endpoint: String, token_request: TokenRequest) -> AdminToken
-  {
+  fn new(config: &Config) -> AdminToken {
+    let token_request = TokenRequest::new(
+      TokenRequestData::Admin(AdminTokenRequestData {
+        client_secret: config.admin_cli_secret.clone(),
+      }),
+      ADMIN_CLI_CLIENT_ID,
+    );
+
     AdminToken {
       token_response: None,
-      endpoint: endpoint,
-      token_request: token_request,
+      obtained: None,
+      endpoint: config.admin_token_endpoint(),
+      token_request,
+      client_secret: config.admin_cli_secret.clone(),
+      failures: 0,
+      last_error: None,
     }
   }
 
-  async fn get_token(&mut self, client: &Client) {
-    self.token_response = Some(client.post(&self.endpoint)
-      .header("Content-Type", "application/x-www-form-urlencoded")
-      .send_body(self.token_request.to_flattened_url_string())
-      .await
-      .unwrap()
-      .json()
-      .await
-      .unwrap());
+  // the form body for the next token request. Uses the refresh grant
+  // as long as the refresh token is valid, otherwise the client
+  // credentials
+  fn next_token_request(&self) -> (String, Option<String>) {
+    let credentials = self.token_request.to_flattened_url_string();
+
+    let refresh = self.remaining(|r| r.refresh_expires_in)
+      .filter(|remaining| *remaining > REFRESH_TOKEN_MARGIN)
+      .and_then(|_| self.token_response.as_ref()?.refresh_token.clone())
+      .map(|refresh_token| AdminRefreshTokenRequest::new(
+        refresh_token, &self.client_secret,
+      ).to_flattened_url_string());
+
+    (credentials, refresh)
+  }
+
+  fn set_token(&mut self, token_response: TokenResponse) {
+    self.token_response = Some(token_response);
+    self.obtained = Some(Instant::now());
+    self.failures = 0;
+    self.last_error = None;
+  }
+
+  fn record_failure(&mut self, e: &ProxyError) {
+    self.failures += 1;
+    self.last_error = Some(e.to_string());
   }
 
-  fn expires_in(&self) -> Option<i64> {
+  // time left until the duration of the token response selected by
+  // expires_in runs out, counting from when the token was obtained
+  fn remaining<F>(&self, expires_in: F) -> Option<Duration>
+    where F: Fn(&TokenResponse) -> Option<i64>
+  {
     let token_response = self.token_response.as_ref()?;
-    Some(token_response.expires_in)
+    let expires_in = Duration::from_secs(
+      expires_in(token_response)?.max(0) as u64
+    );
+
+    expires_in.checked_sub(self.obtained?.elapsed())
+  }
+
+  fn expires_in(&self) -> Option<Duration> {
+    self.remaining(|r| Some(r.expires_in))
   }
 
   fn access_token(&self) -> Option<String> {
+    self.expires_in()?;
     let token_response = self.token_response.as_ref()?;
     Some(token_response.access_token.clone())
   }
-}
 
-impl Default for AdminToken {
-  fn default() -> Self {
-    let token_request = TokenRequest::from(
-      TokenRequestData::Admin(AdminTokenRequestData::default())
-    );
-    AdminToken::new(ADMIN_TOKEN_ENDPOINT.clone(), token_request)
+  fn health(&self) -> AdminTokenHealth {
+    let expires_in = self.expires_in();
+
+    AdminTokenHealth {
+      valid: expires_in.is_some(),
+      expires_in: expires_in.map(|d| d.as_secs()),
+      consecutive_failures: self.failures,
+      last_error: self.last_error.clone(),
+    }
   }
 }
 
@@ -87,10 +168,10 @@ impl ToFlattenedUrlString for TokenRequest {
   }
 }
 
-impl From<TokenRequestData> for TokenRequest {
-  fn from(data: TokenRequestData) -> Self {
-    let meta = TokenRequestMeta::from(&data);
-    TokenRequest{data: data, meta: meta}
+impl TokenRequest {
+  fn new(data: TokenRequestData, client_id: &str) -> Self {
+    let meta = TokenRequestMeta::new(&data, client_id);
+    TokenRequest{data, meta}
   }
 }
 
@@ -120,18 +201,20 @@ struct TokenRequestMeta {
   client_id: String,
 }
 
-impl From<&TokenRequestData> for TokenRequestMeta {
-  fn from(token_request_data: &TokenRequestData) -> Self {
-    match token_request_data {
-      &TokenRequestData::RefreshToken(_) => TokenRequestMeta {
+impl TokenRequestMeta {
+  // the admin grants always use the admin-cli client instead of
+  // client_id
+  fn new(token_request_data: &TokenRequestData, client_id: &str) -> Self {
+    match *token_request_data {
+      TokenRequestData::RefreshToken(_) => TokenRequestMeta {
         grant_type: String::from("refresh_token"),
-        client_id: CLIENT_ID.clone(),
+        client_id: String::from(client_id),
       },
-      &TokenRequestData::Password(_) => TokenRequestMeta {
+      TokenRequestData::Password(_) => TokenRequestMeta {
         grant_type: String::from("password"),
-        client_id: CLIENT_ID.clone(),
+        client_id: String::from(client_id),
       },
-      &TokenRequestData::Admin(_) => TokenRequestMeta {
+      TokenRequestData::Admin(_) => TokenRequestMeta {
         grant_type: String::from("client_credentials"),
         client_id: String::from(ADMIN_CLI_CLIENT_ID),
       },
@@ -144,6 +227,43 @@ struct RefreshTokenRequestData {
   refresh_token: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct RevocationRequestData {
+  token: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  token_type_hint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ClientMeta {
+  client_id: String,
+}
+
+// form body for the endpoints of keycloak that only need to know the
+// client on top of the data sent by the frontend
+#[derive(Serialize, Deserialize, Debug)]
+struct ClientRequest<T> {
+  data: T,
+  meta: ClientMeta,
+}
+
+impl<T: serde::Serialize> ToFlattenedUrlString for ClientRequest<T> {
+  fn to_flattened_url_string(&self) -> String {
+    format!(
+      "{}&{}",
+      qs::to_string(&self.data).unwrap(),
+      qs::to_string(&self.meta).unwrap(),
+    )
+  }
+}
+
+impl<T> ClientRequest<T> {
+  fn new(data: T, client_id: &str) -> Self {
+    let meta = ClientMeta{client_id: String::from(client_id)};
+    ClientRequest{data, meta}
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct PasswordTokenRequestData {
   username: String,
@@ -155,18 +275,52 @@ struct AdminTokenRequestData {
   client_secret: String,
 }
 
-impl Default for AdminTokenRequestData {
-  fn default() -> Self {
-    AdminTokenRequestData{client_secret: ADMIN_CLI_SECRET.clone()}
+#[derive(Serialize, Debug)]
+struct AdminRefreshTokenRequestData {
+  refresh_token: String,
+  client_secret: String,
+}
+
+// the refresh grant of the admin token. Not part of TokenRequestData,
+// so clients of /token can not use the admin-cli client
+#[derive(Serialize, Debug)]
+struct AdminRefreshTokenRequest {
+  data: AdminRefreshTokenRequestData,
+  meta: TokenRequestMeta,
+}
+
+impl AdminRefreshTokenRequest {
+  fn new(refresh_token: String, client_secret: &str) -> Self {
+    AdminRefreshTokenRequest {
+      data: AdminRefreshTokenRequestData {
+        refresh_token,
+        client_secret: String::from(client_secret),
+      },
+      meta: TokenRequestMeta {
+        grant_type: String::from("refresh_token"),
+        client_id: String::from(ADMIN_CLI_CLIENT_ID),
+      },
+    }
+  }
+}
+
+impl ToFlattenedUrlString for AdminRefreshTokenRequest {
+  fn to_flattened_url_string(&self) -> String {
+    format!(
+      "{}&{}",
+      qs::to_string(&self.data).unwrap(),
+      qs::to_string(&self.meta).unwrap(),
+    )
   }
 }
 
+// keycloak does not hand out refresh tokens for every grant
 #[derive(Serialize, Deserialize, Debug)]
 struct TokenResponse {
   access_token: String,
-  refresh_token: String,
+  refresh_token: Option<String>,
   expires_in: i64,
-  refresh_expires_in: i64,
+  refresh_expires_in: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -213,147 +367,1347 @@ struct ProxyRegisterRequest {
   password: String,
 }
 
+// headers only meaningful for a single connection, which must not be
+// forwarded by a proxy (RFC 7230, section 6.1). Content-Length and
+// Content-Encoding are dropped as well, because the body is
+// decompressed and its length set anew
+static HOP_BY_HOP_HEADERS: [&str; 10] = [
+  "connection",
+  "keep-alive",
+  "proxy-authenticate",
+  "proxy-authorization",
+  "te",
+  "trailer",
+  "transfer-encoding",
+  "upgrade",
+  "content-length",
+  "content-encoding",
+];
+
+fn is_hop_by_hop(name: &HeaderName, headers: &HeaderMap) -> bool {
+  if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+    return true;
+  }
+
+  // the Connection header can name further hop-by-hop headers
+  headers.get_all(header::CONNECTION)
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(","))
+    .any(|v| v.trim().eq_ignore_ascii_case(name.as_str()))
+}
+
+impl ProxyRegisterRequest {
+  fn validate(&self) -> Result<(), ValidationErrors> {
+    let mut validator = Validator::default();
+
+    validator.check("username", validation::username(&self.username));
+    validator.check("email", validation::email(&self.email));
+    validator.check(
+      "password", validation::password(&self.password, &self.username)
+    );
+    validator.check("first_name", validation::name(&self.first_name));
+    validator.check("last_name", validation::name(&self.last_name));
+
+    validator.finish()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AccountActionRequest {
+  // either the username or the email address of the user
+  user: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UserQuery {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  username: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  email: Option<String>,
+  exact: bool,
+}
+
+impl From<&AccountActionRequest> for UserQuery {
+  fn from(request: &AccountActionRequest) -> Self {
+    if request.user.contains("@") {
+      UserQuery{username: None, email: Some(request.user.clone()),
+        exact: true}
+    } else {
+      UserQuery{username: Some(request.user.clone()), email: None,
+        exact: true}
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct UserRepresentation {
+  id: String,
+  username: String,
+  email: Option<String>,
+  firstName: Option<String>,
+  lastName: Option<String>,
+  #[serde(default)]
+  emailVerified: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UserInfo {
+  sub: String,
+  preferred_username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Profile {
+  username: String,
+  first_name: Option<String>,
+  last_name: Option<String>,
+  email: Option<String>,
+  email_verified: bool,
+}
+
+impl From<UserRepresentation> for Profile {
+  fn from(user: UserRepresentation) -> Profile {
+    Profile {
+      username: user.username,
+      first_name: user.firstName,
+      last_name: user.lastName,
+      email: user.email,
+      email_verified: user.emailVerified,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ProfileUpdate {
+  first_name: Option<String>,
+  last_name: Option<String>,
+  email: Option<String>,
+}
+
+impl ProfileUpdate {
+  fn validate(&self) -> Result<(), ValidationErrors> {
+    let mut validator = Validator::default();
+
+    if let Some(first_name) = &self.first_name {
+      validator.check("first_name", validation::name(first_name));
+    }
+
+    if let Some(last_name) = &self.last_name {
+      validator.check("last_name", validation::name(last_name));
+    }
+
+    if let Some(email) = &self.email {
+      validator.check("email", validation::email(email));
+    }
+
+    validator.finish()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct UserUpdateRequest {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  firstName: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  lastName: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  email: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  emailVerified: Option<bool>,
+}
+
+impl From<ProfileUpdate> for UserUpdateRequest {
+  fn from(update: ProfileUpdate) -> UserUpdateRequest {
+    // a changed email address has to be verified again
+    let email_verified = update.email.as_ref().map(|_| false);
+
+    UserUpdateRequest {
+      firstName: update.first_name,
+      lastName: update.last_name,
+      email: update.email,
+      emailVerified: email_verified,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum RequiredAction {
+  VerifyEmail,
+  UpdatePassword,
+}
+
 async fn into_response(
   mut client_response: ClientResponse<dev::Decompress<dev::Payload>>
-) -> HttpResponse {
+) -> Result<HttpResponse, ProxyError> {
   let mut response = HttpResponse::build(client_response.status());
 
-  client_response.headers().iter().for_each(|(k, v)| {
-    response.set_header(k, v.clone());
-  });
+  let headers = client_response.headers();
 
-  response.body(client_response.body().await.unwrap())
+  headers.iter()
+    .filter(|(k, _)| !is_hop_by_hop(k, headers))
+    .for_each(|(k, v)| {
+      response.set_header(k, v.clone());
+    });
+
+  Ok(response.body(client_response.body().await?))
+}
+
+fn upstream_client(config: &Config) -> Client {
+  Client::builder()
+    .timeout(config.upstream_timeout())
+    .finish()
 }
 
 #[post("/token")]
 async fn token(
   body: web::Json<TokenRequestData>,
-  client: web::Data<Client>) -> impl Responder
-{
-  let token_request = TokenRequest::from(body.into_inner());
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  let token_request =
+    TokenRequest::new(body.into_inner(), &config.client_id);
 
-  into_response(client.post(&*TOKEN_ENDPOINT)
+  into_response(metrics.upstream("token", client
+    .post(&config.token_endpoint())
     .header("Content-Type", "application/x-www-form-urlencoded")
+    .header(REQUEST_ID_HEADER, request_id.as_str())
     .send_body(token_request.to_flattened_url_string())
-    .await
-    .unwrap()).await
+  ).await?).await
 }
 
-#[get("/certs")]
-async fn certs(client: web::Data<Client>) -> impl Responder {
-  let response = client.get(&*CERTS_ENDPOINT)
+// ends the session of the refresh token at keycloak
+#[post("/logout")]
+async fn logout(
+  body: web::Json<RefreshTokenRequestData>,
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  let logout_request =
+    ClientRequest::new(body.into_inner(), &config.client_id);
+
+  into_response(metrics.upstream("logout", client
+    .post(&config.logout_endpoint())
+    .header("Content-Type", "application/x-www-form-urlencoded")
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send_body(logout_request.to_flattened_url_string())
+  ).await?).await
+}
+
+// token revocation as defined in RFC 7009
+#[post("/revoke")]
+async fn revoke(
+  body: web::Json<RevocationRequestData>,
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  let revocation_request =
+    ClientRequest::new(body.into_inner(), &config.client_id);
+
+  into_response(metrics.upstream("revoke", client
+    .post(&config.revoke_endpoint())
+    .header("Content-Type", "application/x-www-form-urlencoded")
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send_body(revocation_request.to_flattened_url_string())
+  ).await?).await
+}
+
+// looks up the user and lets keycloak send them an email asking them
+// to perform the actions. To not disclose which users exist, the
+// response is the same whether or not the user was found
+async fn send_actions_email(
+  req: HttpRequest,
+  request_id: RequestId,
+  context: ActionsEmailContext,
+  request: AccountActionRequest,
+  actions: Vec<RequiredAction>,
+) -> Result<HttpResponse, ProxyError> {
+  let ActionsEmailContext {
+    client, config, admin_token, limits, metrics,
+  } = context;
+
+  let ip = ClientIp::of(&req).ok_or(ProxyError::UnknownClient)?.key();
+
+  limits.per_client.try_acquire(&ip)
+    .map_err(ProxyError::TooManyRequests)?;
+
+  // keyed on the client as well, so nobody can use up the emails of
+  // someone else
+  let user = request.user.to_lowercase();
+
+  limits.per_user.try_acquire(&format!("{} user:{}", ip, user))
+    .map_err(ProxyError::TooManyRequests)?;
+
+  let access_token = admin_bearer(&admin_token).await?;
+
+  let query = qs::to_string(&UserQuery::from(&request)).unwrap();
+
+  let mut response = metrics.upstream("users", client
+    .get(&format!("{}?{}", config.users_endpoint(), query))
+    .header("Authorization", access_token.clone())
+    .header(REQUEST_ID_HEADER, request_id.as_str())
     .send()
-    .await;
+  ).await?;
 
-  match response {
-    Ok(response) => into_response(response).await,
-    Err(e) => {
-      eprintln!("{:?}", e);
-      HttpResponse::InternalServerError().finish()
+  if !response.status().is_success() {
+    return Err(ProxyError::UpstreamRejected(response.status()));
+  }
+
+  let users: Vec<UserRepresentation> = response.json().await?;
+
+  for user in users {
+    let response = metrics.upstream("execute_actions_email", client
+      .put(&format!("{}/{}/execute-actions-email",
+        config.users_endpoint(), user.id))
+      .header("Authorization", access_token.clone())
+      .header(REQUEST_ID_HEADER, request_id.as_str())
+      .send_json(&actions)
+    ).await?;
+
+    if !response.status().is_success() {
+      warn!(
+        user = %user.username,
+        status = response.status().as_u16(),
+        "keycloak could not send actions email",
+      );
     }
   }
+
+  Ok(HttpResponse::Accepted().finish())
+}
+
+async fn admin_bearer(admin_token: &RwLock<AdminToken>)
+  -> Result<Bearer, ProxyError>
+{
+  let access_token = admin_token.read().await.access_token()
+    .ok_or(ProxyError::AdminTokenUnavailable)?;
+
+  Ok(Bearer::new(access_token))
+}
+
+// asks keycloak who the bearer token belongs to, which also verifies
+// the token
+async fn authenticated_user(
+  client: &Client,
+  config: &Config,
+  request_id: &RequestId,
+  metrics: &KeycloakMetrics,
+  bearer: &BearerAuth,
+) -> Result<UserInfo, ProxyError> {
+  let mut response = metrics.upstream("userinfo", client
+    .get(&config.userinfo_endpoint())
+    .header("Authorization", Bearer::new(bearer.token().to_string()))
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send()
+  ).await?;
+
+  if !response.status().is_success() {
+    return Err(ProxyError::Unauthorized);
+  }
+
+  response.json().await.map_err(|_| ProxyError::Unauthorized)
+}
+
+#[get("/account")]
+async fn get_account(
+  bearer: BearerAuth,
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  let user_info = authenticated_user(
+    &client, &config, &request_id, &metrics, &bearer,
+  ).await?;
+  let access_token = admin_bearer(&admin_token).await?;
+
+  let mut response = metrics.upstream("user", client
+    .get(&format!("{}/{}", config.users_endpoint(), user_info.sub))
+    .header("Authorization", access_token)
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send()
+  ).await?;
+
+  if !response.status().is_success() {
+    return into_response(response).await;
+  }
+
+  let user: UserRepresentation = response.json().await?;
+
+  Ok(HttpResponse::Ok().json(Profile::from(user)))
+}
+
+#[put("/account")]
+async fn update_account(
+  bearer: BearerAuth,
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  metrics: web::Data<KeycloakMetrics>,
+  update: web::Json<ProfileUpdate>,
+) -> Result<HttpResponse, ProxyError> {
+  let update = update.into_inner();
+  update.validate()?;
+
+  let user_info = authenticated_user(
+    &client, &config, &request_id, &metrics, &bearer,
+  ).await?;
+  let access_token = admin_bearer(&admin_token).await?;
+
+  let response = metrics.upstream("user", client
+    .put(&format!("{}/{}", config.users_endpoint(), user_info.sub))
+    .header("Authorization", access_token)
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send_json(&UserUpdateRequest::from(update))
+  ).await?;
+
+  if response.status() == StatusCode::CONFLICT {
+    return Err(ProxyError::UserExists);
+  }
+
+  into_response(response).await
+}
+
+// yata_api only deletes users for requests with this header
+static SERVICE_TOKEN_HEADER: &str = "X-Service-Token";
+
+// purges the elements of the user in yata_api before removing the
+// user from keycloak, so no data of deleted accounts is left behind
+#[delete("/account")]
+async fn delete_account(
+  bearer: BearerAuth,
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  let user_info = authenticated_user(
+    &client, &config, &request_id, &metrics, &bearer,
+  ).await?;
+  let access_token = admin_bearer(&admin_token).await?;
+
+  let response = client
+    .delete(&config.yata_api_user_endpoint(
+      &user_info.preferred_username
+    ))
+    .header("Authorization", Bearer::new(bearer.token().to_string()))
+    .header(
+      SERVICE_TOKEN_HEADER, config.yata_api_service_token.as_str(),
+    )
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send()
+    .await?;
+
+  if !response.status().is_success() {
+    warn!(
+      user = %user_info.preferred_username,
+      status = response.status().as_u16(),
+      "yata_api could not purge the elements of the user",
+    );
+    return into_response(response).await;
+  }
+
+  into_response(metrics.upstream("user", client
+    .delete(&format!("{}/{}", config.users_endpoint(), user_info.sub))
+    .header("Authorization", access_token)
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send()
+  ).await?).await
+}
+
+#[post("/reset_password")]
+async fn reset_password(
+  req: HttpRequest,
+  request_id: RequestId,
+  context: ActionsEmailContext,
+  body: web::Json<AccountActionRequest>,
+) -> Result<HttpResponse, ProxyError> {
+  send_actions_email(
+    req, request_id, context, body.into_inner(),
+    vec![RequiredAction::UpdatePassword],
+  ).await
+}
+
+#[post("/verify_email")]
+async fn verify_email(
+  req: HttpRequest,
+  request_id: RequestId,
+  context: ActionsEmailContext,
+  body: web::Json<AccountActionRequest>,
+) -> Result<HttpResponse, ProxyError> {
+  send_actions_email(
+    req, request_id, context, body.into_inner(),
+    vec![RequiredAction::VerifyEmail],
+  ).await
+}
+
+// the process is up and answering requests
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+  HttpResponse::Ok().json(Health::new(BTreeMap::new()))
+}
+
+#[get("/readyz")]
+async fn readyz(
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  metrics: web::Data<KeycloakMetrics>,
+) -> HttpResponse {
+  let mut components = BTreeMap::new();
+
+  let keycloak = metrics
+    .upstream("realm", client.get(&config.realm_url()).send())
+    .await;
+
+  components.insert("keycloak", match keycloak {
+    Ok(response) if response.status().is_success() =>
+      ComponentHealth::ok(),
+    Ok(response) => ComponentHealth::unavailable(
+      format!("unexpected status {}", response.status())
+    ),
+    Err(e) => ComponentHealth::unavailable(
+      ProxyError::from(e).to_string()
+    ),
+  });
+
+  let token_health = admin_token.read().await.health();
+
+  components.insert("admin_token", if token_health.valid {
+    ComponentHealth::ok()
+  } else {
+    ComponentHealth::unavailable(token_health.last_error
+      .unwrap_or_else(|| String::from("no admin token obtained yet")))
+  });
+
+  let health = Health::new(components);
+
+  match health.status {
+    Status::Ok => HttpResponse::Ok().json(health),
+    Status::Unavailable => HttpResponse::ServiceUnavailable().json(health),
+  }
+}
+
+#[get("/health/admin_token")]
+async fn admin_token_health(
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+) -> HttpResponse {
+  let health = admin_token.read().await.health();
+
+  if health.valid {
+    HttpResponse::Ok().json(health)
+  } else {
+    HttpResponse::ServiceUnavailable().json(health)
+  }
+}
+
+#[get("/certs")]
+async fn certs(
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
+  metrics: web::Data<KeycloakMetrics>,
+) -> Result<HttpResponse, ProxyError> {
+  into_response(metrics.upstream("certs", client
+    .get(&config.certs_endpoint())
+    .header(REQUEST_ID_HEADER, request_id.as_str())
+    .send()
+  ).await?).await
+}
+
+// scrapes need the metrics_token as bearer token
+#[get("/metrics")]
+async fn get_metrics(req: HttpRequest, metrics: web::Data<Metrics>)
+  -> HttpResponse
+{
+  if !metrics.authorized(&req) {
+    return HttpResponse::Unauthorized().finish();
+  }
+
+  HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(metrics.render())
 }
 
 #[post("/register")]
 async fn register(
   client: web::Data<Client>,
+  config: web::Data<Config>,
+  request_id: RequestId,
   admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  metrics: web::Data<KeycloakMetrics>,
   registration_data: web::Json<ProxyRegisterRequest>,
-) -> impl Responder {
-  let registration =
-    RegisterRequest::from(registration_data.into_inner());
+) -> Result<HttpResponse, ProxyError> {
+  let registration_data = registration_data.into_inner();
+  registration_data.validate()?;
 
-  let access_token = admin_token.read().await.access_token().unwrap();
-  let access_token = Bearer::new(access_token);
+  let registration = RegisterRequest::from(registration_data);
 
-  into_response(client.post(&*REGISTER_ENDPOINT)
+  let access_token = admin_bearer(&admin_token).await?;
+
+  let response = metrics.upstream("users", client
+    .post(&config.users_endpoint())
     .header("Content-Type", "application/json")
     .header("Authorization", access_token)
+    .header(REQUEST_ID_HEADER, request_id.as_str())
     .send_json(&registration)
-    .await
-    .unwrap()).await
-}
-
-lazy_static!{
-  static ref CLIENT_ID: String = env::var("KEYCLOAK_PROXY_CLIENT_ID")
-    .unwrap();
-  static ref ADMIN_CLI_SECRET: String =
-    env::var("KEYCLOAK_PROXY_ADMIN_CLI_SECRET").unwrap();
-  static ref CERTS_ENDPOINT: String = format!(
-    "http://{}:8080/auth/realms/{}/protocol/openid-connect/certs",
-    env::var("KEYCLOAK_PROXY_KEYCLOAK_SERVER").unwrap(),
-    env::var("KEYCLOAK_PROXY_REALM").unwrap(),
-  );
-  static ref TOKEN_ENDPOINT: String = format!(
-    "http://{}:8080/auth/realms/{}/protocol/openid-connect/token",
-    env::var("KEYCLOAK_PROXY_KEYCLOAK_SERVER").unwrap(),
-    env::var("KEYCLOAK_PROXY_REALM").unwrap(),
-  );
-  static ref REGISTER_ENDPOINT: String = format!(
-    "http://{}:8080/auth/admin/realms/{}/users",
-    env::var("KEYCLOAK_PROXY_KEYCLOAK_SERVER").unwrap(),
-    env::var("KEYCLOAK_PROXY_REALM").unwrap(),
-  );
-  static ref ADMIN_TOKEN_ENDPOINT: String = format!(
-    "http://{}:8080/auth/realms/master/protocol/openid-connect/token",
-    env::var("KEYCLOAK_PROXY_KEYCLOAK_SERVER").unwrap(),
-  );
-}
-
-static ADMIN_CLI_CLIENT_ID: &'static str = "admin-cli";
+  ).await?;
+
+  // keycloak answers with 409 if either the username or the email
+  // address is already in use
+  if response.status() == StatusCode::CONFLICT {
+    return Err(ProxyError::UserExists);
+  }
+
+  into_response(response).await
+}
+
+static ADMIN_CLI_CLIENT_ID: &str = "admin-cli";
+
+// how many account action emails a client ip can trigger per hour,
+// and how many of them may go to the same user
+static ACTIONS_EMAIL_LIMIT: u32 = 5;
+static ACTIONS_EMAIL_USER_LIMIT: u32 = 2;
+static ACTIONS_EMAIL_PERIOD: Duration = Duration::from_secs(3600);
+
+struct ActionsEmailLimits {
+  per_client: RateLimiter,
+  per_user: RateLimiter,
+}
+
+impl ActionsEmailLimits {
+  fn new() -> ActionsEmailLimits {
+    ActionsEmailLimits {
+      per_client:
+        RateLimiter::new(ACTIONS_EMAIL_LIMIT, ACTIONS_EMAIL_PERIOD),
+      per_user: RateLimiter::new(
+        ACTIONS_EMAIL_USER_LIMIT, ACTIONS_EMAIL_PERIOD,
+      ),
+    }
+  }
+}
+
+// the app data sending actions emails needs, extracted as a whole so
+// the handlers do not take each part as an argument
+struct ActionsEmailContext {
+  client: web::Data<Client>,
+  config: web::Data<Config>,
+  admin_token: web::Data<Arc<RwLock<AdminToken>>>,
+  limits: web::Data<Arc<ActionsEmailLimits>>,
+  metrics: web::Data<KeycloakMetrics>,
+}
+
+impl ActionsEmailContext {
+  fn extract(req: &HttpRequest) -> Result<Self, ActixError> {
+    Ok(ActionsEmailContext {
+      client: app_data(req)?,
+      config: app_data(req)?,
+      admin_token: app_data(req)?,
+      limits: app_data(req)?,
+      metrics: app_data(req)?,
+    })
+  }
+}
+
+impl FromRequest for ActionsEmailContext {
+  type Error = ActixError;
+  type Future = future::Ready<Result<Self, Self::Error>>;
+  type Config = ();
+
+  fn from_request(req: &HttpRequest, _: &mut dev::Payload)
+    -> Self::Future
+  {
+    future::ready(ActionsEmailContext::extract(req))
+  }
+}
+
+fn app_data<T: 'static>(req: &HttpRequest)
+  -> Result<web::Data<T>, ActixError>
+{
+  req.app_data::<web::Data<T>>().cloned()
+    .ok_or_else(|| ErrorInternalServerError("app data missing"))
+}
+
+// the refresh grant is only tried while the refresh token is valid
+// for at least this long
+static REFRESH_TOKEN_MARGIN: Duration = Duration::from_secs(10);
+
+// the admin token is refreshed after 90% of its lifetime, but never
+// sooner than this, so short-lived tokens can not make the refresher
+// spin
+static REFRESH_MIN_DELAY: Duration = Duration::from_secs(5);
+
+static REFRESH_BACKOFF_BASE: Duration = Duration::from_secs(1);
+static REFRESH_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+async fn request_token(
+  client: &Client,
+  metrics: &KeycloakMetrics,
+  endpoint: &str,
+  body: String,
+) -> Result<TokenResponse, ProxyError> {
+  let mut response = metrics.upstream("admin_token", client
+    .post(endpoint)
+    .header("Content-Type", "application/x-www-form-urlencoded")
+    .send_body(body)
+  ).await?;
 
+  if !response.status().is_success() {
+    return Err(ProxyError::UpstreamRejected(response.status()));
+  }
+
+  Ok(response.json().await?)
+}
+
+// the lock is not held while waiting for keycloak, so requests needing
+// the current admin token are not blocked by a slow refresh
+async fn refresh_admin_token(
+  admin_token: &RwLock<AdminToken>,
+  client: &Client,
+  metrics: &KeycloakMetrics,
+) -> Result<Duration, ProxyError> {
+  let (endpoint, (credentials, refresh)) = {
+    let admin_token = admin_token.read().await;
+    (admin_token.endpoint.clone(), admin_token.next_token_request())
+  };
+
+  let refreshed = match refresh {
+    Some(refresh) =>
+      request_token(client, metrics, &endpoint, refresh).await,
+    None => Err(ProxyError::AdminTokenUnavailable),
+  };
+
+  let response = match refreshed {
+    Ok(response) => Ok(response),
+    Err(_) =>
+      request_token(client, metrics, &endpoint, credentials).await,
+  };
+
+  let mut admin_token = admin_token.write().await;
+
+  match response {
+    Ok(response) => {
+      admin_token.set_token(response);
+      Ok(admin_token.expires_in().unwrap_or_default())
+    },
+    Err(e) => {
+      admin_token.record_failure(&e);
+      Err(e)
+    }
+  }
+}
+
+fn refresh_delay(expires_in: Duration) -> Duration {
+  expires_in.mul_f64(0.9).max(REFRESH_MIN_DELAY)
+}
+
+// exponential backoff with jitter over the upper half of the delay, so
+// several proxies failing at once do not retry in lockstep
+fn refresh_backoff(failures: u32) -> Duration {
+  let exponent = failures.saturating_sub(1).min(16) as i32;
+
+  let delay = (REFRESH_BACKOFF_BASE.as_secs_f64() * 2f64.powi(exponent))
+    .min(REFRESH_BACKOFF_MAX.as_secs_f64());
+
+  let jitter = rand::random::<f64>() * delay / 2.0;
+
+  Duration::from_secs_f64(delay / 2.0 + jitter)
+}
+
+// the task stops once shutdown changes, after finishing a refresh in
+// progress. The returned receiver resolves once it stopped
 fn spawn_task_for_periodically_refreshing_admin_token(
-  admin_token: Arc<RwLock<AdminToken>>
-) {
+  admin_token: Arc<RwLock<AdminToken>>,
+  client: Client,
+  metrics: KeycloakMetrics,
+  mut shutdown: watch::Receiver<bool>,
+) -> oneshot::Receiver<()> {
+  let (stopped, stopped_rx) = oneshot::channel();
+
   actix_rt::spawn(async move {
-    let client = Client::default();
     loop {
-      admin_token.write().await.get_token(&client).await;
-      println!("successfully refreshed admin token");
+      let refreshed =
+        refresh_admin_token(&admin_token, &client, &metrics).await;
+
+      metrics.admin_token_refreshed(&refreshed);
+
+      let delay = match refreshed {
+        Ok(expires_in) => {
+          info!("successfully refreshed admin token");
+          refresh_delay(expires_in)
+        },
+        Err(e) => {
+          // counted by refresh_admin_token, which reports them in the
+          // health of the admin token as well
+          let failures = admin_token.read().await.failures;
+          let delay = refresh_backoff(failures);
+          warn!(
+            failures,
+            retry_in_secs = delay.as_secs_f64(),
+            error = %e,
+            "could not refresh admin token",
+          );
+          delay
+        }
+      };
 
-      time::delay_for({
-        let expires_in = admin_token.read().await.expires_in()
-          .unwrap() as f64;
+      let wait = Box::pin(time::delay_for(delay));
+      let stop = Box::pin(shutdown.changed());
 
-        Duration::from_secs_f64(expires_in * 0.98)
-      }).await;
+      if let Either::Right(_) = future::select(wait, stop).await {
+        break;
+      }
     }
+
+    info!("stopped refreshing admin token");
+    let _ = stopped.send(());
   });
+
+  stopped_rx
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-  let port = env::var("KEYCLOAK_PROXY_PORT").unwrap();
-  let addr = format!("0.0.0.0:{}", port);
+  let args: Vec<String> = env::args().collect();
+
+  let config = match Config::load(&args) {
+    Ok(config) => config,
+    Err(e) => {
+      eprint!("{}", e);
+      process::exit(1);
+    }
+  };
+
+  if args.iter().any(|a| a == "--print-config") {
+    print!("{}", config.print());
+    return Ok(());
+  }
+
+  if let Err(e) = logging::init(&config.log_level, config.log_format) {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+
+  let addr = format!("0.0.0.0:{}", config.port);
 
   let admin_token: Arc<RwLock<AdminToken>> =
-    Arc::new(RwLock::new(AdminToken::default()));
+    Arc::new(RwLock::new(AdminToken::new(&config)));
 
-  spawn_task_for_periodically_refreshing_admin_token(
-    admin_token.clone()
-  );
+  let metrics = Metrics::new()
+    .require_token(config.metrics_token.as_deref());
+  let keycloak_metrics = KeycloakMetrics::new(&metrics);
 
-  println!("STARTING KEYCLOAK_PROXY SERVER");
+  let (shutdown, shutdown_rx) = watch::channel(false);
+
+  let refresher_stopped =
+    spawn_task_for_periodically_refreshing_admin_token(
+      admin_token.clone(),
+      upstream_client(&config),
+      keycloak_metrics.clone(),
+      shutdown_rx,
+    );
+
+  let shutdown_timeout = config.shutdown_timeout_secs;
+
+  let actions_email_limits = Arc::new(ActionsEmailLimits::new());
+
+  let rate_limits = Arc::new(RateLimits::new(&config));
+
+  info!("starting keycloak_proxy server");
 
   HttpServer::new(move || {
+    let request_metrics = metrics.clone();
+    let rate_limits = rate_limits.clone();
+
     App::new()
-      .data(Client::default())
+      .data(upstream_client(&config))
+      .data(config.clone())
       .data(admin_token.clone())
-      .wrap(Cors::permissive()) // TODO: only yata_frontend
-      /*
-      .wrap_fn(|req, srv| {
-        println!("{:?}", req);
-        srv.call(req).map(|res| {
-          println!("{:?}", res);
+      .data(actions_email_limits.clone())
+      .data(metrics.clone())
+      .data(keycloak_metrics.clone())
+      .wrap(config.cors())
+      .wrap_fn(move |req, srv| {
+        let timer = request_metrics.start(&req);
+        srv.call(req).map(move |res| {
+          timer.finish(&res);
           res
         })
       })
-      */
-      .service(certs)
-      .service(token)
-      .service(register)
+      .wrap_fn(trace_request)
+      // registered before the limited scope, so probes and scrapes are
+      // never rejected
+      .service(healthz)
+      .service(readyz)
+      .service(get_metrics)
+      .service(web::scope("")
+        .wrap_fn(move |req, srv| rate_limits.limit(req, srv))
+        .service(certs)
+        .service(admin_token_health)
+        .service(token)
+        .service(logout)
+        .service(revoke)
+        .service(register)
+        .service(reset_password)
+        .service(verify_email)
+        .service(get_account)
+        .service(update_account)
+        .service(delete_account))
   })
+  // actix stops accepting connections on SIGTERM and waits for the
+  // in-flight requests to finish
+  .shutdown_timeout(shutdown_timeout)
   .bind(&addr)?
   .run()
-  .await
+  .await?;
+
+  let _ = shutdown.send(true);
+  let _ = refresher_stopped.await;
+
+  info!("keycloak_proxy server stopped");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::{test, HttpMessage};
+  use actix_web::http::{HeaderMap, Method};
+
+  use toml::value::Table;
+
+  use std::collections::HashMap;
+  use std::sync::Mutex;
+
+  // stands in for keycloak and yata_api. Answers "<method> <path>"
+  // with the configured response, everything else with 404
+  #[derive(Clone, Default)]
+  struct Upstream {
+    responses: Arc<Mutex<HashMap<String, (u16, String)>>>,
+    // the headers of the last request of every route
+    received: Arc<Mutex<HashMap<String, HeaderMap>>>,
+  }
+
+  impl Upstream {
+    fn respond(&self, route: &str, status: u16, body: &str) {
+      self.responses.lock().unwrap()
+        .insert(String::from(route), (status, String::from(body)));
+    }
+
+    fn header(&self, route: &str, name: &str) -> Option<String> {
+      let received = self.received.lock().unwrap();
+      let value = received.get(route)?.get(name)?;
+
+      Some(String::from(value.to_str().ok()?))
+    }
+
+    fn handle(&self, req: &HttpRequest) -> HttpResponse {
+      let route = format!("{} {}", req.method(), req.path());
+
+      self.received.lock().unwrap()
+        .insert(route.clone(), req.headers().clone());
+
+      match self.responses.lock().unwrap().get(&route) {
+        Some((status, body)) =>
+          HttpResponse::build(StatusCode::from_u16(*status).unwrap())
+            .content_type("application/json")
+            .body(body.clone()),
+        None => HttpResponse::NotFound().finish(),
+      }
+    }
+
+    fn start(&self) -> test::TestServer {
+      let upstream = self.clone();
+
+      test::start(move || {
+        let upstream = upstream.clone();
+
+        App::new().default_service(web::route().to(
+          move |req: HttpRequest| future::ready(upstream.handle(&req))
+        ))
+      })
+    }
+  }
+
+  fn config(server: &test::TestServer) -> Config {
+    let url = format!("http://{}", server.addr());
+
+    Config::from_sources(Table::new(), |key| match key {
+      "KEYCLOAK_PROXY_CLIENT_ID" => Some(String::from("yata_frontend")),
+      "KEYCLOAK_PROXY_REALM" => Some(String::from("yata")),
+      "KEYCLOAK_PROXY_ADMIN_CLI_SECRET" => Some(String::from("secret")),
+      "KEYCLOAK_PROXY_YATA_API_SERVICE_TOKEN" =>
+        Some(String::from("service token")),
+      "KEYCLOAK_PROXY_KEYCLOAK_URL" => Some(url.clone()),
+      "KEYCLOAK_PROXY_YATA_API_URL" => Some(url.clone()),
+      _ => None,
+    }).unwrap()
+  }
+
+  fn admin_token(config: &Config) -> Arc<RwLock<AdminToken>> {
+    let mut admin_token = AdminToken::new(config);
+
+    admin_token.set_token(TokenResponse {
+      access_token: String::from("admin token"),
+      refresh_token: None,
+      expires_in: 300,
+      refresh_expires_in: None,
+    });
+
+    Arc::new(RwLock::new(admin_token))
+  }
+
+  async fn call(config: &Config, req: test::TestRequest)
+    -> (StatusCode, serde_json::Value)
+  {
+    let mut app = test::init_service(App::new()
+      .data(upstream_client(config))
+      .data(config.clone())
+      .data(admin_token(config))
+      .data(Arc::new(ActionsEmailLimits::new()))
+      .data(KeycloakMetrics::new(&Metrics::new()))
+      .service(reset_password)
+      .service(get_account)
+      .service(update_account)
+      .service(delete_account)).await;
+
+    let req = req.peer_addr("1.2.3.4:80".parse().unwrap());
+    let res = test::call_service(&mut app, req.to_request()).await;
+    let status = res.status();
+    let body = test::read_body(res).await;
+
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+  }
+
+  #[actix_rt::test]
+  async fn test_get_account_returns_profile_of_bearer() {
+    let upstream = Upstream::default();
+    upstream.respond(
+      "GET /realms/yata/protocol/openid-connect/userinfo", 200,
+      r#"{"sub": "1234", "preferred_username": "alice"}"#,
+    );
+    upstream.respond(
+      "GET /admin/realms/yata/users/1234", 200,
+      r#"{"id": "1234", "username": "alice",
+        "email": "alice@example.com", "emailVerified": true}"#,
+    );
+    let server = upstream.start();
+
+    let req = test::TestRequest::get()
+      .uri("/account")
+      .header("Authorization", "Bearer user token");
+    let (status, profile) = call(&config(&server), req).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(profile["username"], "alice");
+    assert_eq!(profile["email"], "alice@example.com");
+    assert_eq!(profile["email_verified"], true);
+  }
+
+  async fn preflight(origin: &str, method: &str)
+    -> (StatusCode, Option<String>)
+  {
+    let config = Config::from_sources(Table::new(), |key| match key {
+      "KEYCLOAK_PROXY_CLIENT_ID" => Some(String::from("yata_frontend")),
+      "KEYCLOAK_PROXY_REALM" => Some(String::from("yata")),
+      "KEYCLOAK_PROXY_ADMIN_CLI_SECRET" => Some(String::from("secret")),
+      "KEYCLOAK_PROXY_YATA_API_SERVICE_TOKEN" =>
+        Some(String::from("service token")),
+      "KEYCLOAK_PROXY_KEYCLOAK_URL" => Some(String::from("http://k")),
+      "KEYCLOAK_PROXY_YATA_API_URL" => Some(String::from("http://a")),
+      "KEYCLOAK_PROXY_CORS_ALLOWED_ORIGINS" =>
+        Some(String::from("https://yata.example.com")),
+      _ => None,
+    }).unwrap();
+
+    let mut app = test::init_service(App::new()
+      .wrap(config.cors())
+      .route("/token", web::post().to(|| {
+        future::ready(HttpResponse::Ok().finish())
+      }))).await;
+
+    let req = test::TestRequest::with_header("Origin", origin)
+      .method(Method::OPTIONS)
+      .uri("/token")
+      .header("Access-Control-Request-Method", method)
+      .header("Access-Control-Request-Headers", "content-type")
+      .to_request();
+
+    match app.call(req).await {
+      Ok(res) => (
+        res.status(),
+        res.headers().get("access-control-allow-origin")
+          .map(|v| v.to_str().unwrap().to_string()),
+      ),
+      Err(e) => (e.as_response_error().status_code(), None),
+    }
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_allowed_origin() {
+    let (status, origin) =
+      preflight("https://yata.example.com", "POST").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(origin.as_deref(), Some("https://yata.example.com"));
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_unknown_origin_is_rejected() {
+    let (status, origin) =
+      preflight("https://evil.example", "POST").await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(origin, None);
+  }
+
+  #[actix_rt::test]
+  async fn test_cors_preflight_of_disallowed_method_is_rejected() {
+    let (status, _) =
+      preflight("https://yata.example.com", "PATCH").await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  #[actix_rt::test]
+  async fn test_metrics_need_the_metrics_token() {
+    let metrics = Metrics::new().require_token(Some("metrics token"));
+
+    let mut app = test::init_service(App::new()
+      .data(metrics)
+      .service(get_metrics)).await;
+
+    let scrape = |authorization: Option<&str>| {
+      let req = test::TestRequest::get().uri("/metrics");
+
+      match authorization {
+        Some(authorization) =>
+          req.header("Authorization", authorization),
+        None => req,
+      }.to_request()
+    };
+
+    let res = test::call_service(
+      &mut app, scrape(Some("Bearer metrics token")),
+    ).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(
+      &mut app, scrape(Some("Bearer other")),
+    ).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = test::call_service(&mut app, scrape(None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+  }
+
+  fn signed_in(upstream: &Upstream) {
+    upstream.respond(
+      "GET /realms/yata/protocol/openid-connect/userinfo", 200,
+      r#"{"sub": "1234", "preferred_username": "alice"}"#,
+    );
+  }
+
+  #[actix_rt::test]
+  async fn test_get_account_needs_a_valid_bearer() {
+    let upstream = Upstream::default();
+    upstream.respond(
+      "GET /realms/yata/protocol/openid-connect/userinfo", 401, "",
+    );
+    let server = upstream.start();
+
+    let req = test::TestRequest::get()
+      .uri("/account")
+      .header("Authorization", "Bearer expired token");
+    let (status, error) = call(&config(&server), req).await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "unauthorized");
+  }
+
+  #[actix_rt::test]
+  async fn test_update_account_changes_the_user_of_bearer() {
+    let upstream = Upstream::default();
+    signed_in(&upstream);
+    upstream.respond("PUT /admin/realms/yata/users/1234", 204, "");
+    let server = upstream.start();
+
+    let req = test::TestRequest::put()
+      .uri("/account")
+      .header("Authorization", "Bearer user token")
+      .set_json(&serde_json::json!({"first_name": "Alice"}));
+    let (status, _) = call(&config(&server), req).await;
+
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let route = "PUT /admin/realms/yata/users/1234";
+    assert_eq!(
+      upstream.header(route, "authorization"),
+      Some(String::from("Bearer admin token")),
+    );
+  }
+
+  #[actix_rt::test]
+  async fn test_update_account_rejects_invalid_and_taken_emails() {
+    let upstream = Upstream::default();
+    signed_in(&upstream);
+    upstream.respond("PUT /admin/realms/yata/users/1234", 409, "");
+    let server = upstream.start();
+    let config = config(&server);
+
+    let update = |email: &str| test::TestRequest::put()
+      .uri("/account")
+      .header("Authorization", "Bearer user token")
+      .set_json(&serde_json::json!({"email": email}));
+
+    let (status, error) = call(&config, update("not an email")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(error["fields"]["email"].is_array());
+
+    let (status, error) = call(&config, update("bob@example.com"))
+      .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["error"], "user_exists");
+  }
+
+  #[actix_rt::test]
+  async fn test_delete_account_purges_elements_before_the_user() {
+    let upstream = Upstream::default();
+    signed_in(&upstream);
+    upstream.respond("DELETE /api/v1/alice", 200, "");
+    upstream.respond("DELETE /admin/realms/yata/users/1234", 204, "");
+    let server = upstream.start();
+
+    let req = test::TestRequest::delete()
+      .uri("/account")
+      .header("Authorization", "Bearer user token");
+    let (status, _) = call(&config(&server), req).await;
+
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(
+      upstream.header("DELETE /api/v1/alice", "x-service-token"),
+      Some(String::from("service token")),
+    );
+    assert_eq!(
+      upstream.header("DELETE /api/v1/alice", "authorization"),
+      Some(String::from("Bearer user token")),
+    );
+  }
+
+  #[actix_rt::test]
+  async fn test_delete_account_keeps_the_user_if_purging_fails() {
+    let upstream = Upstream::default();
+    signed_in(&upstream);
+    upstream.respond("DELETE /api/v1/alice", 403, "");
+    upstream.respond("DELETE /admin/realms/yata/users/1234", 204, "");
+    let server = upstream.start();
+
+    let req = test::TestRequest::delete()
+      .uri("/account")
+      .header("Authorization", "Bearer user token");
+    let (status, _) = call(&config(&server), req).await;
+
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(
+      upstream.header("DELETE /admin/realms/yata/users/1234", "host"),
+      None,
+    );
+  }
+
+  fn reset(user: &str) -> test::TestRequest {
+    test::TestRequest::post()
+      .uri("/reset_password")
+      .set_json(&serde_json::json!({"user": user}))
+  }
+
+  #[actix_rt::test]
+  async fn test_reset_password_fails_if_users_can_not_be_looked_up() {
+    let upstream = Upstream::default();
+    upstream.respond("GET /admin/realms/yata/users", 500, "");
+    let server = upstream.start();
+
+    let (status, error) = call(&config(&server), reset("alice")).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(error["error"], "upstream_rejected");
+  }
+
+  #[actix_rt::test]
+  async fn test_reset_password_of_others_is_not_limited() {
+    let upstream = Upstream::default();
+    upstream.respond("GET /admin/realms/yata/users", 200, "[]");
+    let server = upstream.start();
+    let config = config(&server);
+
+    let limits = Arc::new(ActionsEmailLimits::new());
+
+    let mut app = test::init_service(App::new()
+      .data(upstream_client(&config))
+      .data(config.clone())
+      .data(admin_token(&config))
+      .data(limits)
+      .data(KeycloakMetrics::new(&Metrics::new()))
+      .wrap_fn(|req, srv| {
+        let ip = req.headers().get("x-test-client")
+          .and_then(|ip| ip.to_str().ok()?.parse().ok());
+
+        if let Some(ip) = ip {
+          req.extensions_mut().insert(ClientIp(ip));
+        }
+        srv.call(req)
+      })
+      .service(reset_password)).await;
+
+    let from = |ip: &str| reset("alice")
+      .header("x-test-client", ip)
+      .to_request();
+
+    for _ in 0..ACTIONS_EMAIL_USER_LIMIT {
+      let res = test::call_service(&mut app, from("6.6.6.6")).await;
+      assert_eq!(res.status(), StatusCode::ACCEPTED);
+    }
+
+    let res = test::call_service(&mut app, from("6.6.6.6")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // the emails of alice are not used up for other clients
+    let res = test::call_service(&mut app, from("1.2.3.4")).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+  }
+
+  #[test]
+  fn test_admin_token_refresh_is_not_accepted_by_token() {
+    let body = r#"{"AdminRefreshToken": {
+      "refresh_token": "stolen", "client_secret": "guessed"}}"#;
+
+    assert!(serde_json::from_str::<TokenRequestData>(body).is_err());
+
+    let refresh = AdminRefreshTokenRequest::new(
+      String::from("refresh"), "secret",
+    ).to_flattened_url_string();
+
+    assert!(refresh.contains("grant_type=refresh_token"));
+    assert!(refresh.contains("client_id=admin-cli"));
+  }
+
+  #[test]
+  fn test_admin_token_refresh_delay_is_bounded() {
+    assert_eq!(
+      refresh_delay(Duration::from_secs(0)), REFRESH_MIN_DELAY,
+    );
+    assert_eq!(
+      refresh_delay(Duration::from_secs(300)), Duration::from_secs(270),
+    );
+  }
 }
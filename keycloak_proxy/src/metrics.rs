@@ -0,0 +1,81 @@
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+
+use yata_common::metrics::Metrics;
+
+use std::future::Future;
+use std::time::Instant;
+
+// the admin token and the requests sent to keycloak, rendered with
+// the metrics of the requests
+#[derive(Clone)]
+pub struct KeycloakMetrics {
+  admin_token_refreshes: IntCounterVec,
+  upstream_latency: HistogramVec,
+}
+
+impl KeycloakMetrics {
+  pub fn new(metrics: &Metrics) -> KeycloakMetrics {
+    let admin_token_refreshes = IntCounterVec::new(
+      Opts::new(
+        "admin_token_refreshes_total", "Number of admin token refreshes"
+      ),
+      &["result"],
+    ).unwrap();
+
+    let upstream_latency = HistogramVec::new(
+      HistogramOpts::new(
+        "keycloak_request_duration_seconds",
+        "Latency of requests sent to keycloak",
+      ),
+      &["endpoint"],
+    ).unwrap();
+
+    metrics.register(admin_token_refreshes.clone());
+    metrics.register(upstream_latency.clone());
+
+    KeycloakMetrics{admin_token_refreshes, upstream_latency}
+  }
+
+  pub fn admin_token_refreshed<T, E>(&self, res: &Result<T, E>) {
+    let result = if res.is_ok() { "success" } else { "failure" };
+    self.admin_token_refreshes.with_label_values(&[result]).inc();
+  }
+
+  // awaits the request sent to keycloak, recording how long it took
+  // under the given endpoint label
+  pub async fn upstream<F: Future>(&self, endpoint: &str, send: F)
+    -> F::Output
+  {
+    let start = Instant::now();
+    let res = send.await;
+
+    self.upstream_latency
+      .with_label_values(&[endpoint])
+      .observe(start.elapsed().as_secs_f64());
+
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_admin_token_refreshes_are_rendered() {
+    let metrics = Metrics::new();
+    let keycloak_metrics = KeycloakMetrics::new(&metrics);
+    keycloak_metrics.admin_token_refreshed::<(), ()>(&Ok(()));
+    keycloak_metrics.admin_token_refreshed::<(), ()>(&Err(()));
+    keycloak_metrics.admin_token_refreshed::<(), ()>(&Err(()));
+
+    let rendered = metrics.render();
+
+    assert!(rendered.contains(
+      "admin_token_refreshes_total{result=\"success\"} 1"
+    ));
+    assert!(rendered.contains(
+      "admin_token_refreshes_total{result=\"failure\"} 2"
+    ));
+  }
+}
@@ -0,0 +1,69 @@
+use actix_web::{Error as ActixError, HttpMessage};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+
+use actix_service::Service;
+
+use futures::future::{Either, Future, FutureExt, ready};
+
+use yata_common::rate_limit::{ClientIp, Limits, TrustedProxies};
+
+use crate::config::Config;
+use crate::errors::ProxyError;
+
+// every request is limited per client address, the proxy does not
+// know about users
+pub struct RateLimits {
+  limits: Limits,
+  trusted_proxies: TrustedProxies,
+}
+
+impl RateLimits {
+  pub fn new(config: &Config) -> RateLimits {
+    RateLimits {
+      limits: Limits::new(&config.rate_limits),
+      trusted_proxies: TrustedProxies::new(&config.trusted_proxies),
+    }
+  }
+
+  pub fn limit<S, B>(&self, req: ServiceRequest, srv: &mut S)
+    -> impl Future<Output = Result<ServiceResponse<B>, ActixError>>
+    where S: Service<
+      Request = ServiceRequest,
+      Response = ServiceResponse<B>,
+      Error = ActixError,
+    >
+  {
+    // only requests not coming in over TCP have no address
+    let client_ip = match self.trusted_proxies
+      .client_ip(req.peer_addr(), req.headers())
+    {
+      Some(ip) => ClientIp(ip),
+      None => return Either::Left(ready(
+        Err(ActixError::from(ProxyError::UnknownClient))
+      )),
+    };
+
+    req.extensions_mut().insert(client_ip);
+
+    let route = req.match_pattern().unwrap_or_default();
+
+    let quota = match self.limits.acquire(
+      req.method().as_str(), &route, &client_ip.key(),
+    ) {
+      Some(Ok(quota)) => Some(quota),
+      Some(Err(quota)) => return Either::Left(ready(
+        Err(ActixError::from(ProxyError::TooManyRequests(quota)))
+      )),
+      None => None,
+    };
+
+    Either::Right(srv.call(req).map(move |res| {
+      res.map(|mut res| {
+        if let Some(quota) = &quota {
+          quota.insert_headers(res.headers_mut());
+        }
+        res
+      })
+    }))
+  }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+// messages per invalid field, sent to the frontend as is
+pub type ValidationErrors = BTreeMap<&'static str, Vec<String>>;
+
+static USERNAME_MIN_LEN: usize = 3;
+static USERNAME_MAX_LEN: usize = 32;
+static EMAIL_MAX_LEN: usize = 254;
+static PASSWORD_MIN_LEN: usize = 8;
+static PASSWORD_MAX_LEN: usize = 128;
+static NAME_MAX_LEN: usize = 64;
+
+// usernames are path segments of yata_api's routes, so they must not
+// collide with its other top-level paths
+static RESERVED_USERNAMES: [&str; 8] = [
+  "api",
+  "dav",
+  "docs",
+  "healthz",
+  "me",
+  "metrics",
+  "openapi.json",
+  "readyz",
+];
+
+#[derive(Default, Debug)]
+pub struct Validator {
+  errors: ValidationErrors,
+}
+
+impl Validator {
+  pub fn check(&mut self, field: &'static str, messages: Vec<String>) {
+    if !messages.is_empty() {
+      self.errors.entry(field).or_default().extend(messages);
+    }
+  }
+
+  pub fn finish(self) -> Result<(), ValidationErrors> {
+    if self.errors.is_empty() {
+      Ok(())
+    } else {
+      Err(self.errors)
+    }
+  }
+}
+
+pub fn username(username: &str) -> Vec<String> {
+  let mut res = Vec::new();
+  let len = username.chars().count();
+
+  if len < USERNAME_MIN_LEN || len > USERNAME_MAX_LEN {
+    res.push(format!(
+      "must be between {} and {} characters long",
+      USERNAME_MIN_LEN, USERNAME_MAX_LEN,
+    ));
+  }
+
+  let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit()
+    || c == '.' || c == '_' || c == '-';
+
+  if !username.chars().all(allowed) {
+    res.push(String::from(
+      "may only contain lowercase letters, digits, '.', '_' and '-'"
+    ));
+  }
+
+  match username.chars().next() {
+    Some(c) if c.is_ascii_alphanumeric() => (),
+    _ => res.push(String::from("must start with a letter or digit")),
+  }
+
+  if RESERVED_USERNAMES.contains(&username) {
+    res.push(String::from("is reserved"));
+  }
+
+  res
+}
+
+pub fn email(email: &str) -> Vec<String> {
+  let mut res = Vec::new();
+
+  if email.len() > EMAIL_MAX_LEN {
+    res.push(format!("must be at most {} characters long",
+      EMAIL_MAX_LEN));
+  }
+
+  let mut parts = email.split("@");
+
+  let valid = match (parts.next(), parts.next(), parts.next()) {
+    (Some(local), Some(domain), None) => {
+      !local.is_empty()
+        && !domain.starts_with(".")
+        && !domain.ends_with(".")
+        && domain.contains(".")
+        && !domain.contains("..")
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+    },
+    _ => false,
+  };
+
+  if !valid {
+    res.push(String::from("is not a valid email address"));
+  }
+
+  res
+}
+
+pub fn password(password: &str, username: &str) -> Vec<String> {
+  let mut res = Vec::new();
+  let len = password.chars().count();
+
+  if len < PASSWORD_MIN_LEN || len > PASSWORD_MAX_LEN {
+    res.push(format!(
+      "must be between {} and {} characters long",
+      PASSWORD_MIN_LEN, PASSWORD_MAX_LEN,
+    ));
+  }
+
+  if !password.chars().any(|c| c.is_alphabetic())
+    || !password.chars().any(|c| c.is_numeric())
+  {
+    res.push(String::from("must contain a letter and a digit"));
+  }
+
+  if password.to_lowercase().contains(&username.to_lowercase())
+    && !username.is_empty()
+  {
+    res.push(String::from("must not contain the username"));
+  }
+
+  res
+}
+
+pub fn name(name: &str) -> Vec<String> {
+  let mut res = Vec::new();
+
+  if name.trim().is_empty() {
+    res.push(String::from("must not be empty"));
+  }
+
+  if name.chars().count() > NAME_MAX_LEN {
+    res.push(format!("must be at most {} characters long",
+      NAME_MAX_LEN));
+  }
+
+  if name.chars().any(|c| c.is_control()) {
+    res.push(String::from("must not contain control characters"));
+  }
+
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_username() {
+    assert!(username("some_user-1.0").is_empty());
+    assert_eq!(username("ab").len(), 1);
+    assert_eq!(username("Some User").len(), 1);
+    assert_eq!(username("_user").len(), 1);
+    assert_eq!(username("healthz"), vec![String::from("is reserved")]);
+    assert!(username("healthz2").is_empty());
+  }
+
+  #[test]
+  fn test_email() {
+    assert!(email("some.user@example.com").is_empty());
+    assert!(!email("some.user@example").is_empty());
+    assert!(!email("some user@example.com").is_empty());
+    assert!(!email("@example.com").is_empty());
+    assert!(!email("a@b@example.com").is_empty());
+  }
+
+  #[test]
+  fn test_password() {
+    assert!(password("correct horse 9", "some_user").is_empty());
+    assert_eq!(password("short1", "some_user").len(), 1);
+    assert_eq!(password("onlyletters", "some_user").len(), 1);
+    assert_eq!(password("some_user123", "some_user").len(), 1);
+  }
+
+  #[test]
+  fn test_validator_collects_errors_per_field() {
+    let mut validator = Validator::default();
+    validator.check("username", username("ab"));
+    validator.check("first_name", name("Some"));
+
+    let errors = validator.finish().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors.contains_key("username"));
+  }
+}